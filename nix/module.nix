@@ -26,7 +26,13 @@ in {
     pskFile = lib.mkOption {
       type = lib.types.nullOr lib.types.path;
       default = null;
-      description = "File containing the pre-shared key. If null, uses default PSK.";
+      description = "File containing the pre-shared key. Required unless insecureNoPsk is set.";
+    };
+
+    insecureNoPsk = lib.mkOption {
+      type = lib.types.bool;
+      default = false;
+      description = "Run with the publicly known default key when no pskFile is given.";
     };
 
     pollMs = lib.mkOption {
@@ -48,6 +54,13 @@ in {
   };
 
   config = lib.mkIf cfg.enable {
+    assertions = [
+      {
+        assertion = cfg.pskFile != null || cfg.insecureNoPsk;
+        message = "services.cursedboard.pskFile must be set, or insecureNoPsk enabled to run without a key.";
+      }
+    ];
+
     networking.firewall = lib.mkIf cfg.openFirewall {
       allowedTCPPorts = [cfg.port];
       allowedUDPPorts = [5353];
@@ -62,8 +75,8 @@ in {
         ExecStart = let
           pskArg =
             if cfg.pskFile != null
-            then "--psk $(cat ${cfg.pskFile})"
-            else "";
+            then "--psk-file ${lib.escapeShellArg cfg.pskFile}"
+            else lib.optionalString cfg.insecureNoPsk "--insecure-no-psk";
         in ''
           ${cfg.package}/bin/cursedboard \
             --name ${lib.escapeShellArg cfg.name} \
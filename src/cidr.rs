@@ -0,0 +1,148 @@
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("invalid CIDR '{0}', expected e.g. 192.168.1.0/24 or fd00::/8")]
+pub struct InvalidCidr(String);
+
+/// An IPv4 or IPv6 address range such as `192.168.1.0/24`. A bare address
+/// is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // Dual-stack sockets report IPv4 peers as IPv4-mapped IPv6.
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether `addr` is allowed by `allowed`; an empty list allows everything.
+pub fn allows(allowed: &[Cidr], addr: IpAddr) -> bool {
+    allowed.is_empty() || allowed.iter().any(|cidr| cidr.contains(addr))
+}
+
+fn prefix_matches(net: &[u8], addr: &[u8], prefix: u8) -> bool {
+    let full = usize::from(prefix / 8);
+    let rest = prefix % 8;
+    if net[..full] != addr[..full] {
+        return false;
+    }
+    if rest == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest);
+    net[full] & mask == addr[full] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidCidr(s.to_string());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ipv4_ranges() {
+        let lan: Cidr = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains(ip("192.168.1.42")));
+        assert!(!lan.contains(ip("192.168.2.1")));
+
+        let odd: Cidr = "10.0.0.0/13".parse().unwrap();
+        assert!(odd.contains(ip("10.7.255.255")));
+        assert!(!odd.contains(ip("10.8.0.0")));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("203.0.113.9")));
+        assert!(!any.contains(ip("::1")));
+    }
+
+    #[test]
+    fn test_ipv6_ranges() {
+        let ula: Cidr = "fd00::/8".parse().unwrap();
+        assert!(ula.contains(ip("fd12:3456::1")));
+        assert!(!ula.contains(ip("fe80::1")));
+
+        let host: Cidr = "::1".parse().unwrap();
+        assert_eq!(host.to_string(), "::1/128");
+        assert!(host.contains(ip("::1")));
+        assert!(!host.contains(ip("::2")));
+    }
+
+    #[test]
+    fn test_mapped_ipv4_matches_ipv4_range() {
+        let lan: Cidr = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains(ip("::ffff:192.168.1.7")));
+    }
+
+    #[test]
+    fn test_invalid_cidrs() {
+        for s in [
+            "",
+            "192.168.1.0/33",
+            "fd00::/129",
+            "192.168.1/24",
+            "lan/8",
+            "10.0.0.0/x",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{s} should not parse");
+        }
+    }
+
+    #[test]
+    fn test_empty_allowlist_allows_all() {
+        assert!(allows(&[], ip("203.0.113.9")));
+        let allowed = ["10.0.0.0/8".parse().unwrap()];
+        assert!(allows(&allowed, ip("10.1.2.3")));
+        assert!(!allows(&allowed, ip("203.0.113.9")));
+    }
+}
@@ -0,0 +1,849 @@
+use crate::inbound;
+use crate::last_synced::LastSynced;
+use crate::protocol::Selection;
+use crate::recent::{RecentContent, RECENT_CAPACITY};
+use arboard::Clipboard;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Mutex};
+use tracing::{debug, error, info, warn};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub trait ClipboardProvider: Sized {
+    type Error: std::fmt::Display;
+
+    fn new() -> Result<Self, Self::Error>;
+    fn get_text(&mut self) -> Result<String, Self::Error>;
+    fn set_text(&mut self, text: &str) -> Result<(), Self::Error>;
+
+    /// Reads the PRIMARY selection. Providers without one report it empty.
+    fn get_primary(&mut self) -> Result<String, Self::Error> {
+        Ok(String::new())
+    }
+
+    fn set_primary(&mut self, _text: &str) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn get(&mut self, selection: Selection) -> Result<String, Self::Error> {
+        match selection {
+            Selection::Clipboard => self.get_text(),
+            Selection::Primary => self.get_primary(),
+        }
+    }
+
+    fn set(&mut self, selection: Selection, text: &str) -> Result<(), Self::Error> {
+        match selection {
+            Selection::Clipboard => self.set_text(text),
+            Selection::Primary => self.set_primary(text),
+        }
+    }
+}
+
+impl ClipboardProvider for Clipboard {
+    type Error = arboard::Error;
+
+    fn new() -> Result<Self, Self::Error> {
+        Clipboard::new()
+    }
+
+    fn get_text(&mut self) -> Result<String, Self::Error> {
+        Clipboard::get_text(self)
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<(), Self::Error> {
+        Clipboard::set_text(self, text)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn get_primary(&mut self) -> Result<String, Self::Error> {
+        use arboard::{GetExtLinux, LinuxClipboardKind};
+        self.get().clipboard(LinuxClipboardKind::Primary).text()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_primary(&mut self, text: &str) -> Result<(), Self::Error> {
+        use arboard::{LinuxClipboardKind, SetExtLinux};
+        self.set().clipboard(LinuxClipboardKind::Primary).text(text)
+    }
+}
+
+/// Clipboard system the platform provider talks to. On Linux this depends on
+/// the session, so it is logged at startup and reported in the status
+/// output to make bug reports easier to act on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Wayland,
+    X11,
+    MacOs,
+    Windows,
+    Unknown,
+}
+
+impl Backend {
+    pub fn detect() -> Self {
+        if cfg!(target_os = "macos") {
+            Self::MacOs
+        } else if cfg!(windows) {
+            Self::Windows
+        } else {
+            Self::detect_from(|name| std::env::var_os(name))
+        }
+    }
+
+    /// Detects the session from `WAYLAND_DISPLAY` and `DISPLAY`, preferring
+    /// Wayland when both are set as under XWayland.
+    fn detect_from(var: impl Fn(&str) -> Option<OsString>) -> Self {
+        let set = |name| var(name).is_some_and(|value| !value.is_empty());
+        if set("WAYLAND_DISPLAY") {
+            Self::Wayland
+        } else if set("DISPLAY") {
+            Self::X11
+        } else {
+            Self::Unknown
+        }
+    }
+
+    /// Whether the backend has a PRIMARY selection separate from the
+    /// clipboard.
+    pub fn has_primary(self) -> bool {
+        matches!(self, Self::Wayland | Self::X11)
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Wayland => "wayland",
+            Self::X11 => "x11",
+            Self::MacOs => "macos",
+            Self::Windows => "windows",
+            Self::Unknown => "unknown",
+        })
+    }
+}
+
+/// Clipboard handle that tolerates the provider being unavailable (e.g. no
+/// display yet) by retrying initialization with backoff. Content received
+/// while unavailable is kept and applied once the provider comes up.
+pub struct LazyClipboard<P = Clipboard> {
+    provider: Option<P>,
+    pending: HashMap<Selection, String>,
+    backoff: Duration,
+    initial_backoff: Duration,
+    next_attempt: Instant,
+}
+
+impl<P: ClipboardProvider> LazyClipboard<P> {
+    pub fn new() -> Self {
+        Self::with_backoff(INITIAL_BACKOFF)
+    }
+
+    pub fn with_backoff(initial_backoff: Duration) -> Self {
+        Self {
+            provider: None,
+            pending: HashMap::new(),
+            backoff: initial_backoff,
+            initial_backoff,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn from_provider(provider: P) -> Self {
+        let mut clipboard = Self::new();
+        clipboard.provider = Some(provider);
+        clipboard
+    }
+
+    fn provider(&mut self) -> Option<&mut P> {
+        if self.provider.is_none() && Instant::now() >= self.next_attempt {
+            match P::new() {
+                Ok(mut provider) => {
+                    info!("clipboard available");
+                    for (selection, content) in self.pending.drain() {
+                        if let Err(e) = provider.set(selection, &content) {
+                            warn!(error = %e, ?selection, "failed to apply pending clipboard");
+                        }
+                    }
+                    self.provider = Some(provider);
+                    self.backoff = self.initial_backoff;
+                }
+                Err(e) => {
+                    warn!(error = %e, retry_in = ?self.backoff, "clipboard unavailable");
+                    self.next_attempt = Instant::now() + self.backoff;
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        self.provider.as_mut()
+    }
+
+    pub fn get_text(&mut self, selection: Selection) -> Option<String> {
+        self.provider()?.get(selection).ok()
+    }
+
+    pub fn set_text(&mut self, selection: Selection, content: &str) -> Result<(), P::Error> {
+        match self.provider() {
+            Some(provider) => provider.set(selection, content),
+            None => {
+                self.pending.insert(selection, content.to_string());
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Local clipboard together with the last content synced through it, kept
+/// behind one lock so a value applied from a peer can never be mistaken for a
+/// local change and broadcast back.
+pub struct SyncedClipboard<P = Clipboard> {
+    clipboard: LazyClipboard<P>,
+    last: HashMap<Selection, String>,
+    /// Content recently broadcast or applied. Catches values that were
+    /// replaced before a poll could see them, which `last` alone would report
+    /// as local changes.
+    recent: RecentContent,
+    /// Hashes of `last` persisted across restarts; consulted for a selection
+    /// until `last` has an entry for it.
+    persisted: Option<LastSynced>,
+}
+
+impl<P: ClipboardProvider> SyncedClipboard<P> {
+    pub fn new() -> Self {
+        Self::with_clipboard(LazyClipboard::new())
+    }
+
+    pub fn with_clipboard(clipboard: LazyClipboard<P>) -> Self {
+        Self {
+            clipboard,
+            last: HashMap::new(),
+            recent: RecentContent::default(),
+            persisted: None,
+        }
+    }
+
+    /// Sets how long content broadcast or applied from a peer is not
+    /// broadcast again when it shows up as a local change.
+    pub fn with_echo_window(mut self, window: Duration) -> Self {
+        self.recent = RecentContent::new(window, RECENT_CAPACITY);
+        self
+    }
+
+    /// Persists what was last synced, so content still on the clipboard
+    /// after a restart is not broadcast again.
+    pub fn with_last_synced(mut self, last_synced: LastSynced) -> Self {
+        self.persisted = Some(last_synced);
+        self
+    }
+
+    /// Returns the selection's content if it changed locally since the last
+    /// sync.
+    pub fn poll_change(&mut self, selection: Selection) -> Option<String> {
+        let content = self.clipboard.get_text(selection).unwrap_or_default();
+        if content.is_empty() {
+            return None;
+        }
+        let unchanged = match self.last.get(&selection) {
+            Some(last) => *last == content,
+            None => self
+                .persisted
+                .as_ref()
+                .is_some_and(|persisted| persisted.matches(selection, &content)),
+        };
+        if unchanged {
+            self.last.entry(selection).or_insert(content);
+            return None;
+        }
+        self.remember(selection, &content);
+        if self.recent.contains((selection, &content)) {
+            debug!(
+                ?selection,
+                "clipboard content synced recently, not broadcasting again"
+            );
+            return None;
+        }
+        self.recent.record((selection, &content));
+        Some(content)
+    }
+
+    pub fn apply_remote(&mut self, selection: Selection, content: &str) -> Result<(), P::Error> {
+        self.remember(selection, content);
+        self.recent.record((selection, content));
+        self.clipboard.set_text(selection, content)
+    }
+
+    /// Returns the selection's content whether or not it changed, recording
+    /// it as synced so the next poll doesn't report it again.
+    pub fn current(&mut self, selection: Selection) -> Option<String> {
+        let content = self.clipboard.get_text(selection).unwrap_or_default();
+        if content.is_empty() {
+            return None;
+        }
+        self.remember(selection, &content);
+        self.recent.record((selection, &content));
+        Some(content)
+    }
+
+    fn remember(&mut self, selection: Selection, content: &str) {
+        self.last.insert(selection, content.to_string());
+        if let Some(persisted) = &mut self.persisted {
+            persisted.record(selection, content);
+        }
+    }
+}
+
+/// Applies content received from peers once no newer update has arrived for
+/// `delay`, so a burst of inbound updates only writes the final value of
+/// each selection. Writes are also spaced at least `min_interval` apart, for
+/// desktops that react to every clipboard change. While `pinned_until` lies
+/// in the future, updates are held back the same way and applied once the
+/// pin expires or is cleared.
+pub async fn apply_coalesced<P: ClipboardProvider>(
+    clipboard: Arc<Mutex<SyncedClipboard<P>>>,
+    mut rx: inbound::Receiver,
+    delay: Duration,
+    min_interval: Duration,
+    mut pinned_until: watch::Receiver<Option<Instant>>,
+) {
+    let mut next_write = Instant::now();
+    while let Some((selection, content)) = rx.recv().await {
+        let mut pending = HashMap::from([(selection, content)]);
+        let mut closed = false;
+        loop {
+            match tokio::time::timeout(delay, rx.recv()).await {
+                Ok(Some((selection, newer))) => {
+                    if pending.insert(selection, newer).is_some() {
+                        debug!(?selection, "superseded by newer remote clipboard");
+                    }
+                }
+                Ok(None) => {
+                    closed = true;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        if !closed {
+            closed = hold_until(&mut rx, next_write, &mut pending).await;
+        }
+        if !closed {
+            closed = hold_while_pinned(&mut rx, &mut pinned_until, &mut pending).await;
+        }
+        let mut clipboard = clipboard.lock().await;
+        for (selection, content) in pending {
+            if let Err(e) = clipboard.apply_remote(selection, &content) {
+                error!(error = %e, ?selection, "failed to set clipboard");
+            }
+        }
+        next_write = Instant::now() + min_interval;
+        if closed {
+            return;
+        }
+    }
+}
+
+/// Waits until `until`, replacing `pending` updates with newer ones
+/// meanwhile. Returns whether the update channel closed.
+async fn hold_until(
+    rx: &mut inbound::Receiver,
+    until: Instant,
+    pending: &mut HashMap<Selection, String>,
+) -> bool {
+    let sleep = tokio::time::sleep_until(until.into());
+    tokio::pin!(sleep);
+    loop {
+        tokio::select! {
+            update = rx.recv() => {
+                let Some((selection, newer)) = update else { return true };
+                if pending.insert(selection, newer).is_some() {
+                    debug!(?selection, "superseded by newer remote clipboard");
+                }
+            }
+            _ = &mut sleep => return false,
+        }
+    }
+}
+
+/// Waits for the pin to expire or be cleared, replacing `pending` updates with
+/// newer ones meanwhile. Returns whether the update channel closed.
+async fn hold_while_pinned(
+    rx: &mut inbound::Receiver,
+    pinned_until: &mut watch::Receiver<Option<Instant>>,
+    pending: &mut HashMap<Selection, String>,
+) -> bool {
+    loop {
+        let until = match *pinned_until.borrow_and_update() {
+            Some(until) if until > Instant::now() => until,
+            _ => return false,
+        };
+        debug!("clipboard pinned, holding remote update");
+        tokio::select! {
+            update = rx.recv() => {
+                let Some((selection, newer)) = update else { return true };
+                pending.insert(selection, newer);
+            }
+            _ = tokio::time::sleep_until(until.into()) => {}
+            changed = pinned_until.changed() => {
+                if changed.is_err() {
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+/// Clipboard backed by a shared string, for driving sync logic in tests
+/// without a display. Clones share the same contents.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct InMemoryClipboardProvider {
+    text: std::sync::Arc<std::sync::Mutex<String>>,
+    primary: std::sync::Arc<std::sync::Mutex<String>>,
+}
+
+#[cfg(test)]
+impl ClipboardProvider for InMemoryClipboardProvider {
+    type Error = std::convert::Infallible;
+
+    fn new() -> Result<Self, Self::Error> {
+        Ok(Self::default())
+    }
+
+    fn get_text(&mut self) -> Result<String, Self::Error> {
+        Ok(self.text.lock().unwrap().clone())
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<(), Self::Error> {
+        *self.text.lock().unwrap() = text.to_string();
+        Ok(())
+    }
+
+    fn get_primary(&mut self) -> Result<String, Self::Error> {
+        Ok(self.primary.lock().unwrap().clone())
+    }
+
+    fn set_primary(&mut self, text: &str) -> Result<(), Self::Error> {
+        *self.primary.lock().unwrap() = text.to_string();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+
+    struct FlakyProvider {
+        text: String,
+    }
+
+    impl ClipboardProvider for FlakyProvider {
+        type Error = &'static str;
+
+        fn new() -> Result<Self, Self::Error> {
+            if ATTEMPTS.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err("no display");
+            }
+            Ok(Self {
+                text: String::new(),
+            })
+        }
+
+        fn get_text(&mut self) -> Result<String, Self::Error> {
+            Ok(self.text.clone())
+        }
+
+        fn set_text(&mut self, text: &str) -> Result<(), Self::Error> {
+            self.text = text.to_string();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_backend_detected_from_session() {
+        fn detect(vars: &[(&str, &str)]) -> Backend {
+            Backend::detect_from(|name| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.into())
+            })
+        }
+
+        assert_eq!(
+            detect(&[("WAYLAND_DISPLAY", "wayland-0")]),
+            Backend::Wayland
+        );
+        assert_eq!(detect(&[("DISPLAY", ":0")]), Backend::X11);
+        assert_eq!(
+            detect(&[("WAYLAND_DISPLAY", "wayland-0"), ("DISPLAY", ":0")]),
+            Backend::Wayland
+        );
+        assert_eq!(
+            detect(&[("WAYLAND_DISPLAY", ""), ("DISPLAY", ":0")]),
+            Backend::X11
+        );
+        assert_eq!(detect(&[]), Backend::Unknown);
+        assert!(!Backend::Unknown.has_primary());
+    }
+
+    #[test]
+    fn test_in_memory_provider() {
+        let mut local = InMemoryClipboardProvider::default();
+        let mut cb = SyncedClipboard::with_clipboard(LazyClipboard::from_provider(local.clone()));
+        assert_eq!(cb.poll_change(Selection::Clipboard), None);
+
+        local.set_text("copied").unwrap();
+        assert_eq!(
+            cb.poll_change(Selection::Clipboard).as_deref(),
+            Some("copied")
+        );
+        assert_eq!(cb.poll_change(Selection::Clipboard), None);
+
+        cb.apply_remote(Selection::Clipboard, "from peer").unwrap();
+        assert_eq!(local.get_text().unwrap(), "from peer");
+    }
+
+    #[test]
+    fn test_restart_with_unchanged_clipboard_does_not_broadcast() {
+        let dir = std::env::temp_dir().join(format!("cursedboard-test-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("last_synced.toml");
+        let mut local = InMemoryClipboardProvider::default();
+        let start = |local: &InMemoryClipboardProvider| {
+            SyncedClipboard::with_clipboard(LazyClipboard::from_provider(local.clone()))
+                .with_last_synced(LastSynced::load_from(path.clone()))
+        };
+
+        local.set_text("copied").unwrap();
+        let mut cb = start(&local);
+        assert_eq!(
+            cb.poll_change(Selection::Clipboard).as_deref(),
+            Some("copied")
+        );
+        drop(cb);
+
+        let mut cb = start(&local);
+        assert_eq!(cb.poll_change(Selection::Clipboard), None);
+
+        local.set_text("copied again").unwrap();
+        assert_eq!(
+            cb.poll_change(Selection::Clipboard).as_deref(),
+            Some("copied again")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remote_set_is_not_rebroadcast() {
+        let mut local = InMemoryClipboardProvider::default();
+        let mut cb = SyncedClipboard::with_clipboard(LazyClipboard::from_provider(local.clone()));
+        assert_eq!(cb.poll_change(Selection::Clipboard), None);
+
+        cb.apply_remote(Selection::Clipboard, "from peer").unwrap();
+        assert_eq!(cb.poll_change(Selection::Clipboard), None);
+
+        local.set_text("local copy").unwrap();
+        assert_eq!(
+            cb.poll_change(Selection::Clipboard).as_deref(),
+            Some("local copy")
+        );
+        assert_eq!(cb.poll_change(Selection::Clipboard), None);
+    }
+
+    #[test]
+    fn test_applied_content_recopied_after_echo_window_is_broadcast() {
+        let mut local = InMemoryClipboardProvider::default();
+        let mut cb = SyncedClipboard::with_clipboard(LazyClipboard::from_provider(local.clone()))
+            .with_echo_window(Duration::from_millis(50));
+
+        cb.apply_remote(Selection::Clipboard, "from peer").unwrap();
+        local.set_text("other").unwrap();
+        assert_eq!(
+            cb.poll_change(Selection::Clipboard).as_deref(),
+            Some("other")
+        );
+        local.set_text("from peer").unwrap();
+        assert_eq!(cb.poll_change(Selection::Clipboard), None);
+
+        std::thread::sleep(Duration::from_millis(60));
+        local.set_text("other again").unwrap();
+        assert_eq!(
+            cb.poll_change(Selection::Clipboard).as_deref(),
+            Some("other again")
+        );
+        local.set_text("from peer").unwrap();
+        assert_eq!(
+            cb.poll_change(Selection::Clipboard).as_deref(),
+            Some("from peer")
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct RecordingProvider {
+        writes: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl ClipboardProvider for RecordingProvider {
+        type Error = std::convert::Infallible;
+
+        fn new() -> Result<Self, Self::Error> {
+            Ok(Self::default())
+        }
+
+        fn get_text(&mut self) -> Result<String, Self::Error> {
+            Ok(self
+                .writes
+                .lock()
+                .unwrap()
+                .last()
+                .cloned()
+                .unwrap_or_default())
+        }
+
+        fn set_text(&mut self, text: &str) -> Result<(), Self::Error> {
+            self.writes.lock().unwrap().push(text.to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_burst_applies_only_last_value() {
+        let provider = RecordingProvider::default();
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(provider.clone()),
+        )));
+        let (tx, rx) = inbound::channel(Arc::default());
+        let (_pin_tx, pin_rx) = watch::channel(None);
+        let task = tokio::spawn(apply_coalesced(
+            clipboard,
+            rx,
+            Duration::from_millis(100),
+            Duration::ZERO,
+            pin_rx,
+        ));
+
+        for content in ["one", "two", "three"] {
+            tx.send((Selection::Clipboard, content.to_string()));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(*provider.writes.lock().unwrap(), ["three"]);
+
+        drop(tx);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_writes_spaced_by_min_interval() {
+        let provider = RecordingProvider::default();
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(provider.clone()),
+        )));
+        let (tx, rx) = inbound::channel(Arc::default());
+        let (_pin_tx, pin_rx) = watch::channel(None);
+        let task = tokio::spawn(apply_coalesced(
+            clipboard,
+            rx,
+            Duration::ZERO,
+            Duration::from_millis(100),
+            pin_rx,
+        ));
+
+        for i in 1..=10 {
+            tx.send((Selection::Clipboard, format!("update {i}")));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let writes = provider.writes.lock().unwrap().clone();
+        assert!(writes.len() < 5, "{writes:?}");
+        assert_eq!(writes.first().map(String::as_str), Some("update 1"));
+        assert_eq!(writes.last().map(String::as_str), Some("update 10"));
+        drop(tx);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pinned_clipboard_applies_after_release() {
+        let provider = RecordingProvider::default();
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(provider.clone()),
+        )));
+        let (tx, rx) = inbound::channel(Arc::default());
+        let (pin_tx, pin_rx) = watch::channel(Some(Instant::now() + Duration::from_secs(3600)));
+        let task = tokio::spawn(apply_coalesced(
+            clipboard,
+            rx,
+            Duration::ZERO,
+            Duration::ZERO,
+            pin_rx,
+        ));
+
+        for content in ["one", "two"] {
+            tx.send((Selection::Clipboard, content.to_string()));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(provider.writes.lock().unwrap().is_empty());
+
+        pin_tx.send(None).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*provider.writes.lock().unwrap(), ["two"]);
+
+        drop(tx);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pin_expires() {
+        let provider = RecordingProvider::default();
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(provider.clone()),
+        )));
+        let (tx, rx) = inbound::channel(Arc::default());
+        let (_pin_tx, pin_rx) = watch::channel(Some(Instant::now() + Duration::from_millis(100)));
+        let task = tokio::spawn(apply_coalesced(
+            clipboard,
+            rx,
+            Duration::ZERO,
+            Duration::ZERO,
+            pin_rx,
+        ));
+
+        tx.send((Selection::Clipboard, "held".to_string()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(provider.writes.lock().unwrap().is_empty());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(*provider.writes.lock().unwrap(), ["held"]);
+
+        drop(tx);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_panic_holding_lock_does_not_block_clipboard() {
+        let mut local = InMemoryClipboardProvider::default();
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(local.clone()),
+        )));
+
+        let panicked = tokio::spawn({
+            let clipboard = clipboard.clone();
+            async move {
+                let _guard = clipboard.lock().await;
+                panic!("clipboard hiccup");
+            }
+        })
+        .await;
+        assert!(panicked.unwrap_err().is_panic());
+
+        let mut clipboard = clipboard.lock().await;
+        clipboard
+            .apply_remote(Selection::Clipboard, "still works")
+            .unwrap();
+        assert_eq!(local.get_text().unwrap(), "still works");
+    }
+
+    #[test]
+    fn test_selections_sync_independently() {
+        let mut local = InMemoryClipboardProvider::default();
+        let mut cb = SyncedClipboard::with_clipboard(LazyClipboard::from_provider(local.clone()));
+
+        cb.apply_remote(Selection::Primary, "selected").unwrap();
+        assert_eq!(local.get_primary().unwrap(), "selected");
+        assert_eq!(local.get_text().unwrap(), "");
+        assert_eq!(cb.poll_change(Selection::Primary), None);
+
+        local.set_text("copied").unwrap();
+        assert_eq!(cb.poll_change(Selection::Primary), None);
+        assert_eq!(
+            cb.poll_change(Selection::Clipboard).as_deref(),
+            Some("copied")
+        );
+        assert_eq!(local.get_primary().unwrap(), "selected");
+    }
+
+    /// Shows each written value only on the read after the write, like a
+    /// display server that hands over ownership asynchronously.
+    #[derive(Clone, Default)]
+    struct LaggingProvider {
+        shown: std::sync::Arc<std::sync::Mutex<String>>,
+        queued: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<String>>>,
+    }
+
+    impl ClipboardProvider for LaggingProvider {
+        type Error = &'static str;
+
+        fn new() -> Result<Self, Self::Error> {
+            Ok(Self::default())
+        }
+
+        fn get_text(&mut self) -> Result<String, Self::Error> {
+            let mut shown = self.shown.lock().unwrap();
+            let text = shown.clone();
+            if let Some(next) = self.queued.lock().unwrap().pop_front() {
+                *shown = next;
+            }
+            Ok(text)
+        }
+
+        fn set_text(&mut self, text: &str) -> Result<(), Self::Error> {
+            self.queued.lock().unwrap().push_back(text.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_mesh_paste_messages_bounded() {
+        let providers: Vec<LaggingProvider> = (0..4).map(|_| Default::default()).collect();
+        let mut nodes: Vec<_> = providers
+            .iter()
+            .map(|p| SyncedClipboard::with_clipboard(LazyClipboard::from_provider(p.clone())))
+            .collect();
+
+        // Two nodes paste in the same poll interval.
+        *providers[0].shown.lock().unwrap() = "from a".into();
+        *providers[2].shown.lock().unwrap() = "from c".into();
+
+        let mut messages = 0;
+        for _ in 0..20 {
+            let mut inboxes = vec![Vec::new(); nodes.len()];
+            for (i, node) in nodes.iter_mut().enumerate() {
+                if let Some(content) = node.poll_change(Selection::Clipboard) {
+                    for (j, inbox) in inboxes.iter_mut().enumerate() {
+                        if j != i {
+                            inbox.push(content.clone());
+                            messages += 1;
+                        }
+                    }
+                }
+            }
+            for (node, inbox) in nodes.iter_mut().zip(inboxes) {
+                for content in inbox {
+                    node.apply_remote(Selection::Clipboard, &content).unwrap();
+                }
+            }
+        }
+        assert!(messages <= 2 * 3, "{messages} messages for two pastes");
+    }
+
+    #[test]
+    fn test_init_failure_retries_and_applies_pending() {
+        let mut cb = LazyClipboard::<FlakyProvider>::with_backoff(Duration::ZERO);
+        assert!(cb.set_text(Selection::Clipboard, "from peer").is_ok());
+        assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            cb.get_text(Selection::Clipboard).as_deref(),
+            Some("from peer")
+        );
+        assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 2);
+    }
+}
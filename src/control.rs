@@ -0,0 +1,301 @@
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CommandError {
+    #[error("unknown command: {0}")]
+    Unknown(String),
+    #[error("usage: {0}")]
+    Usage(&'static str),
+}
+
+/// Commands accepted on the control socket, one per line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Mute(String),
+    Unmute(String),
+    Pin(Duration),
+    Unpin,
+    /// Broadcasts the rest of the line to peers without touching the local
+    /// clipboard.
+    Send(String),
+    SendTo {
+        peer: String,
+        text: String,
+    },
+    /// Broadcasts the local clipboard as it is, even if it was already sent.
+    SyncNow,
+    /// Sets the label of a trusted peer, or clears it with `None`.
+    Label {
+        peer: String,
+        label: Option<String>,
+    },
+    ConfigSources,
+}
+
+impl FromStr for Command {
+    type Err = CommandError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let rest: Vec<&str> = words.collect();
+        match name {
+            "mute" if rest.len() == 1 => Ok(Self::Mute(rest[0].to_string())),
+            "mute" => Err(CommandError::Usage("mute <peer id or name>")),
+            "unmute" if rest.len() == 1 => Ok(Self::Unmute(rest[0].to_string())),
+            "unmute" => Err(CommandError::Usage("unmute <peer id or name>")),
+            "pin" => match rest.as_slice() {
+                [secs] => secs
+                    .parse()
+                    .map(|secs| Self::Pin(Duration::from_secs(secs)))
+                    .map_err(|_| CommandError::Usage("pin <seconds>")),
+                _ => Err(CommandError::Usage("pin <seconds>")),
+            },
+            "unpin" if rest.is_empty() => Ok(Self::Unpin),
+            "send" if !rest.is_empty() => {
+                let text = line.trim_start()["send".len()..].trim_start();
+                Ok(Self::Send(text.trim_end_matches(['\r', '\n']).to_string()))
+            }
+            "send" => Err(CommandError::Usage("send <text>")),
+            "send-to" if rest.len() >= 2 => {
+                let after_name = line.trim_start()["send-to".len()..].trim_start();
+                let text = after_name[rest[0].len()..].trim_start();
+                Ok(Self::SendTo {
+                    peer: rest[0].to_string(),
+                    text: text.trim_end_matches(['\r', '\n']).to_string(),
+                })
+            }
+            "send-to" => Err(CommandError::Usage("send-to <peer id or name> <text>")),
+            "sync-now" if rest.is_empty() => Ok(Self::SyncNow),
+            "sync-now" => Err(CommandError::Usage("sync-now")),
+            "label" if rest.len() >= 2 => {
+                let after_name = line.trim_start()["label".len()..].trim_start();
+                let label = after_name[rest[0].len()..].trim();
+                Ok(Self::Label {
+                    peer: rest[0].to_string(),
+                    label: Some(label.to_string()),
+                })
+            }
+            "label" => Err(CommandError::Usage("label <peer id or name> <label>")),
+            "unlabel" if rest.len() == 1 => Ok(Self::Label {
+                peer: rest[0].to_string(),
+                label: None,
+            }),
+            "unlabel" => Err(CommandError::Usage("unlabel <peer id or name>")),
+            "config" if rest == ["sources"] => Ok(Self::ConfigSources),
+            "config" => Err(CommandError::Usage("config sources")),
+            _ => Err(CommandError::Unknown(name.to_string())),
+        }
+    }
+}
+
+pub struct Request {
+    pub command: Command,
+    pub reply: oneshot::Sender<String>,
+}
+
+pub fn socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .or_else(dirs::config_dir)
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("cursedboard")
+        .join("control.sock")
+}
+
+/// Binds the control socket, readable and writable by the owner only. A
+/// socket left behind by a daemon that exited is replaced, but one another
+/// daemon still answers on is not taken over.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    if let Some(parent) = path.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+    }
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("another daemon is listening on {}", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Accepts control connections and forwards each parsed command to
+/// `requests`, writing the handler's reply back to the client.
+pub async fn serve(listener: UnixListener, requests: mpsc::Sender<Request>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!(path = ?addr.as_pathname(), "listening for control commands");
+    }
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let requests = requests.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, requests).await {
+                debug!(error = %e, "control client failed");
+            }
+        });
+    }
+}
+
+async fn handle_client(stream: UnixStream, requests: mpsc::Sender<Request>) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(read).read_line(&mut line).await?;
+
+    let response = match line.parse::<Command>() {
+        Ok(command) => {
+            let (reply, response) = oneshot::channel();
+            if requests.send(Request { command, reply }).await.is_err() {
+                return Ok(());
+            }
+            response.await.unwrap_or_default()
+        }
+        Err(e) => format!("error: {e}"),
+    };
+    write.write_all(response.as_bytes()).await?;
+    write.write_all(b"\n").await?;
+    write.shutdown().await
+}
+
+/// Sends one command line to a running daemon and returns its reply.
+pub async fn send(path: &Path, line: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path).await?;
+    stream.write_all(line.as_bytes()).await?;
+    stream.write_all(b"\n").await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!("mute laptop".parse(), Ok(Command::Mute("laptop".into())));
+        assert_eq!(
+            "unmute laptop\n".parse(),
+            Ok(Command::Unmute("laptop".into()))
+        );
+        assert_eq!("pin 30".parse(), Ok(Command::Pin(Duration::from_secs(30))));
+        assert_eq!(
+            "pin soon".parse::<Command>(),
+            Err(CommandError::Usage("pin <seconds>"))
+        );
+        assert_eq!("unpin".parse(), Ok(Command::Unpin));
+        assert_eq!(
+            "send  two  spaces\n".parse(),
+            Ok(Command::Send("two  spaces".into()))
+        );
+        assert_eq!(
+            "send".parse::<Command>(),
+            Err(CommandError::Usage("send <text>"))
+        );
+        assert_eq!(
+            "send-to phone call me\n".parse(),
+            Ok(Command::SendTo {
+                peer: "phone".into(),
+                text: "call me".into()
+            })
+        );
+        assert_eq!(
+            "send-to phone".parse::<Command>(),
+            Err(CommandError::Usage("send-to <peer id or name> <text>"))
+        );
+        assert_eq!("sync-now\n".parse(), Ok(Command::SyncNow));
+        assert_eq!(
+            "label nas  backup box\n".parse(),
+            Ok(Command::Label {
+                peer: "nas".into(),
+                label: Some("backup box".into())
+            })
+        );
+        assert_eq!(
+            "unlabel nas".parse(),
+            Ok(Command::Label {
+                peer: "nas".into(),
+                label: None
+            })
+        );
+        assert_eq!(
+            "label nas".parse::<Command>(),
+            Err(CommandError::Usage("label <peer id or name> <label>"))
+        );
+        assert_eq!("config sources".parse(), Ok(Command::ConfigSources));
+        assert_eq!(
+            "config".parse::<Command>(),
+            Err(CommandError::Usage("config sources"))
+        );
+        assert_eq!(
+            "mute".parse::<Command>(),
+            Err(CommandError::Usage("mute <peer id or name>"))
+        );
+        assert_eq!(
+            "explode".parse::<Command>(),
+            Err(CommandError::Unknown("explode".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let path = std::env::temp_dir()
+            .join(format!("cursedboard-test-{}", uuid::Uuid::new_v4()))
+            .join("control.sock");
+        let listener = bind(&path).unwrap();
+        let (tx, mut rx) = mpsc::channel(1);
+        tokio::spawn(serve(listener, tx));
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                let _ = request.reply.send(format!("ok {:?}", request.command));
+            }
+        });
+
+        let response = send(&path, "mute laptop").await.unwrap();
+        assert_eq!(response, "ok Mute(\"laptop\")\n");
+        let response = send(&path, "bogus").await.unwrap();
+        assert_eq!(response, "error: unknown command: bogus\n");
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_refuses_live_socket_and_replaces_stale_one() {
+        let path = std::env::temp_dir()
+            .join(format!("cursedboard-test-{}", uuid::Uuid::new_v4()))
+            .join("control.sock");
+        let listener = bind(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let dir_mode = std::fs::metadata(path.parent().unwrap())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(dir_mode & 0o777, 0o700);
+
+        let err = bind(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        // The socket file outlives a daemon that exited without cleaning up.
+        drop(listener);
+        assert!(path.exists());
+        bind(&path).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
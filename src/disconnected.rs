@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisconnectedPeer {
+    pub name: String,
+    pub label: Option<String>,
+    /// Whether we had dialed the peer.
+    pub outbound: bool,
+    pub last_seen: Instant,
+}
+
+/// Peers that disconnected within the last `ttl`, kept so status can show
+/// when they were last seen. Without a ttl nothing is kept.
+#[derive(Debug, Default)]
+pub struct RecentlyDisconnected {
+    peers: HashMap<Uuid, DisconnectedPeer>,
+    ttl: Option<Duration>,
+}
+
+impl RecentlyDisconnected {
+    pub fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            peers: HashMap::new(),
+            ttl,
+        }
+    }
+
+    pub fn insert(&mut self, id: Uuid, peer: DisconnectedPeer) {
+        if self.ttl.is_none() {
+            return;
+        }
+        self.evict_at(peer.last_seen);
+        self.peers.insert(id, peer);
+    }
+
+    /// Forgets a peer that connected again.
+    pub fn remove(&mut self, id: &Uuid) {
+        self.peers.remove(id);
+    }
+
+    pub fn recent(&mut self) -> impl Iterator<Item = (&Uuid, &DisconnectedPeer)> {
+        self.recent_at(Instant::now())
+    }
+
+    fn recent_at(&mut self, now: Instant) -> impl Iterator<Item = (&Uuid, &DisconnectedPeer)> {
+        self.evict_at(now);
+        self.peers.iter()
+    }
+
+    fn evict_at(&mut self, now: Instant) {
+        let Some(ttl) = self.ttl else { return };
+        self.peers
+            .retain(|_, peer| now.saturating_duration_since(peer.last_seen) < ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(name: &str, last_seen: Instant) -> DisconnectedPeer {
+        DisconnectedPeer {
+            name: name.into(),
+            label: Some("work".into()),
+            outbound: false,
+            last_seen,
+        }
+    }
+
+    fn ids(recent: &mut RecentlyDisconnected, now: Instant) -> Vec<Uuid> {
+        recent.recent_at(now).map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn test_disconnected_peer_kept_until_ttl() {
+        let mut recent = RecentlyDisconnected::new(Some(Duration::from_secs(60)));
+        let start = Instant::now();
+        let id = Uuid::new_v4();
+        recent.insert(id, peer("laptop", start));
+
+        assert_eq!(ids(&mut recent, start + Duration::from_secs(59)), [id]);
+        assert!(ids(&mut recent, start + Duration::from_secs(60)).is_empty());
+        assert!(ids(&mut recent, start).is_empty());
+    }
+
+    #[test]
+    fn test_reconnected_or_untracked_peers_not_listed() {
+        let start = Instant::now();
+        let id = Uuid::new_v4();
+        let mut recent = RecentlyDisconnected::new(Some(Duration::from_secs(60)));
+        recent.insert(id, peer("laptop", start));
+        recent.remove(&id);
+        assert!(ids(&mut recent, start).is_empty());
+
+        let mut untracked = RecentlyDisconnected::new(None);
+        untracked.insert(id, peer("laptop", start));
+        assert!(ids(&mut untracked, start).is_empty());
+    }
+}
@@ -1,12 +1,23 @@
+use clap::ValueEnum;
+use futures::Stream;
 use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::Serialize;
 use std::collections::HashSet;
 use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::mpsc;
 use tracing::{debug, info, warn};
 use uuid::Uuid;
 
 const SERVICE_TYPE: &str = "_cursedboard._tcp.local.";
+/// Times a stopped browse is restarted in a row, without it producing any
+/// event in between, before discovery is given up.
+const BROWSE_RESTARTS: u32 = 5;
+/// Delay before the first restart, doubling on each further attempt.
+const BROWSE_RESTART_DELAY: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Error)]
 pub enum DiscoveryError {
@@ -18,109 +29,222 @@ pub enum DiscoveryError {
 pub struct Peer {
     pub id: Uuid,
     pub name: String,
+    pub group: Option<String>,
     pub addr: SocketAddr,
+    /// Unix time (seconds, rounded down to the minute) of the peer's last
+    /// clipboard activity, if it chooses to advertise it.
+    pub last_active: Option<u64>,
 }
 
+#[derive(Debug, Clone)]
+pub enum DiscoveryEvent {
+    Resolved(Peer),
+    Removed(Uuid),
+}
+
+/// Events from an active browse. Ends when the browse stops, e.g. after
+/// [`Discovery::shutdown`].
+pub struct DiscoveryStream {
+    rx: mpsc::Receiver<DiscoveryEvent>,
+}
+
+impl DiscoveryStream {
+    #[cfg(test)]
+    pub fn from_receiver(rx: mpsc::Receiver<DiscoveryEvent>) -> Self {
+        Self { rx }
+    }
+}
+
+impl Stream for DiscoveryStream {
+    type Item = DiscoveryEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// How to treat discovered peers advertising a different group than ours.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupPolicy {
+    /// Ignore them without logging.
+    #[default]
+    Strict,
+    /// Ignore them, but log that they were seen.
+    Log,
+    /// Connect anyway.
+    Allow,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum GroupDecision {
+    Connect,
+    Ignore,
+    IgnoreLogged,
+}
+
+impl GroupPolicy {
+    pub fn decide(self, ours: Option<&str>, theirs: Option<&str>) -> GroupDecision {
+        if ours == theirs {
+            return GroupDecision::Connect;
+        }
+        match self {
+            Self::Strict => GroupDecision::Ignore,
+            Self::Log => GroupDecision::IgnoreLogged,
+            Self::Allow => GroupDecision::Connect,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Discovery {
     daemon: ServiceDaemon,
     instance_id: Uuid,
     name: String,
+    group: Option<String>,
+    /// mDNS subtype isolating this deployment from others on the same LAN.
+    subtype: Option<String>,
     port: u16,
 }
 
 impl Discovery {
-    pub fn new(instance_id: Uuid, name: String, port: u16) -> Result<Self, DiscoveryError> {
+    pub fn new(
+        instance_id: Uuid,
+        name: String,
+        group: Option<String>,
+        subtype: Option<String>,
+        port: u16,
+    ) -> Result<Self, DiscoveryError> {
         let daemon = ServiceDaemon::new()?;
         Ok(Self {
             daemon,
             instance_id,
             name,
+            group,
+            subtype,
             port,
         })
     }
 
     pub fn register(&self) -> Result<(), DiscoveryError> {
+        self.register_service(None)?;
+        info!(name = %self.name, port = %self.port, "registered mDNS service");
+        Ok(())
+    }
+
+    /// Re-announces the service with a coarse last-activity timestamp, so
+    /// scanners can tell live instances apart without connecting.
+    pub fn advertise_last_active(&self, timestamp: u64) -> Result<(), DiscoveryError> {
+        self.register_service(Some(timestamp - timestamp % 60))
+    }
+
+    fn register_service(&self, last_active: Option<u64>) -> Result<(), DiscoveryError> {
         let host = hostname::get()
             .map(|h| h.to_string_lossy().to_string())
             .unwrap_or_else(|_| "unknown".to_string());
 
-        let local_ips: Vec<IpAddr> = local_ip_address::list_afinet_netifas()
-            .unwrap_or_default()
-            .into_iter()
-            .filter_map(|(_, ip)| {
-                if ip.is_loopback() {
-                    return None;
-                }
-                match ip {
-                    IpAddr::V4(_) => Some(ip),
-                    IpAddr::V6(v6) if !v6.is_loopback() && !is_link_local_v6(&v6) => Some(ip),
-                    _ => None,
-                }
-            })
-            .collect();
-
-        info!(?local_ips, "discovered local IPs");
+        let local_ips = local_ips();
+        debug!(?local_ips, "discovered local IPs");
 
-        let service_name = format!("{}_{}", self.name, self.instance_id);
+        let service_name = self.service_name();
+        let id = self.instance_id.to_string();
+        let last_active = last_active.map(|ts| ts.to_string());
+        let mut properties = vec![("id", id.as_str())];
+        if let Some(group) = &self.group {
+            properties.push(("group", group.as_str()));
+        }
+        if let Some(subtype) = &self.subtype {
+            properties.push(("subtype", subtype.as_str()));
+        }
+        if let Some(last_active) = &last_active {
+            properties.push(("last_active", last_active.as_str()));
+        }
         let service = ServiceInfo::new(
-            SERVICE_TYPE,
+            &service_type(self.subtype.as_deref()),
             &service_name,
             &format!("{}.local.", host),
             local_ips.as_slice(),
             self.port,
-            [("id", self.instance_id.to_string().as_str())].as_slice(),
+            properties.as_slice(),
         )?;
 
         self.daemon.register(service)?;
-        info!(name = %self.name, port = %self.port, "registered mDNS service");
         Ok(())
     }
 
-    pub fn browse(&self, tx: mpsc::Sender<Peer>) -> Result<(), DiscoveryError> {
-        let receiver = self.daemon.browse(SERVICE_TYPE)?;
+    fn service_name(&self) -> String {
+        format!("{}_{}", self.name, self.instance_id)
+    }
+
+    /// Withdraws the service registered by [`Discovery::register`].
+    pub fn unregister(&self) -> Result<(), DiscoveryError> {
+        let fullname = format!("{}.{SERVICE_TYPE}", self.service_name());
+        self.daemon.unregister(&fullname)?;
+        info!(name = %self.name, "unregistered mDNS service");
+        Ok(())
+    }
+
+    /// Registers the service and starts a watched browse. If the browse
+    /// can't start, the service is unregistered again, so peers don't keep
+    /// dialing a daemon that isn't doing discovery.
+    pub fn start(&self) -> Result<DiscoveryStream, DiscoveryError> {
+        register_then_browse(
+            || self.register(),
+            || self.browse_watched(),
+            || self.unregister(),
+        )
+    }
+
+    pub fn browse(&self) -> Result<DiscoveryStream, DiscoveryError> {
+        let receiver = self.daemon.browse(&service_type(self.subtype.as_deref()))?;
         let own_id = self.instance_id;
+        let subtype = self.subtype.clone();
+        let local_ips = local_ips();
+        let (tx, rx) = mpsc::channel(32);
 
         tokio::spawn(async move {
             let mut seen: HashSet<Uuid> = HashSet::new();
-            
+
             loop {
                 match receiver.recv() {
                     Ok(event) => match event {
                         ServiceEvent::ServiceResolved(info) => {
-                            let id = match info.get_properties().get("id") {
-                                Some(prop) => match prop.val_str().parse::<Uuid>() {
-                                    Ok(id) => id,
-                                    Err(_) => continue,
-                                },
-                                None => continue,
+                            let Some(peer) = parse_service_info(&info, subtype.as_deref()) else {
+                                continue;
                             };
 
-                            if id == own_id || seen.contains(&id) {
+                            if seen.contains(&peer.id) {
                                 continue;
                             }
 
-                            let addr = match info.get_addresses().iter().next() {
-                                Some(ip) => SocketAddr::new(ip.to_ip_addr(), info.get_port()),
-                                None => continue,
-                            };
-
-                            let name = info
-                                .get_fullname()
-                                .split('_')
-                                .next()
-                                .unwrap_or("unknown")
-                                .to_string();
+                            if peer.id == own_id {
+                                if is_duplicate_instance(peer.addr, &local_ips) {
+                                    warn!(
+                                        id = %peer.id,
+                                        addr = %peer.addr,
+                                        "another machine is using this instance id, so neither will connect to it; \
+                                         run `cursedboard regenerate-id` on one of them"
+                                    );
+                                    seen.insert(peer.id);
+                                }
+                                continue;
+                            }
 
-                            seen.insert(id);
-                            let peer = Peer { id, name, addr };
+                            seen.insert(peer.id);
                             debug!(?peer, "discovered peer");
 
-                            if tx.send(peer).await.is_err() {
+                            if tx.send(DiscoveryEvent::Resolved(peer)).await.is_err() {
                                 break;
                             }
                         }
                         ServiceEvent::ServiceRemoved(_, fullname) => {
                             debug!(fullname, "peer removed");
+                            let Some(id) = id_from_fullname(&fullname) else {
+                                continue;
+                            };
+                            if id != own_id && tx.send(DiscoveryEvent::Removed(id)).await.is_err() {
+                                break;
+                            }
                         }
                         _ => {}
                     },
@@ -132,7 +256,21 @@ impl Discovery {
             }
         });
 
-        Ok(())
+        Ok(DiscoveryStream { rx })
+    }
+
+    /// Like [`Discovery::browse`], but restarts the browse when it stops on
+    /// an mDNS error instead of ending the stream, so a long-running daemon
+    /// keeps discovering after a transient hiccup.
+    pub fn browse_watched(&self) -> Result<DiscoveryStream, DiscoveryError> {
+        let browse = self.browse()?;
+        let discovery = self.clone();
+        Ok(watch_browse(
+            browse,
+            move || discovery.browse(),
+            BROWSE_RESTARTS,
+            BROWSE_RESTART_DELAY,
+        ))
     }
 
     pub fn shutdown(self) -> Result<(), DiscoveryError> {
@@ -141,6 +279,382 @@ impl Discovery {
     }
 }
 
+/// Runs `browse` once `register` succeeded, undoing the registration with
+/// `unregister` if the browse fails.
+fn register_then_browse<T>(
+    register: impl FnOnce() -> Result<(), DiscoveryError>,
+    browse: impl FnOnce() -> Result<T, DiscoveryError>,
+    unregister: impl FnOnce() -> Result<(), DiscoveryError>,
+) -> Result<T, DiscoveryError> {
+    register()?;
+    browse().inspect_err(|_| {
+        if let Err(e) = unregister() {
+            warn!(error = %e, "failed to unregister mDNS service");
+        }
+    })
+}
+
+/// Forwards events from `browse`, calling `restart` for a new browse whenever
+/// the current one ends. Gives up after `max_restarts` restarts in a row
+/// that produced no events, and stops once the returned stream is dropped.
+fn watch_browse(
+    mut browse: DiscoveryStream,
+    mut restart: impl FnMut() -> Result<DiscoveryStream, DiscoveryError> + Send + 'static,
+    max_restarts: u32,
+    delay: Duration,
+) -> DiscoveryStream {
+    let (tx, rx) = mpsc::channel(32);
+    tokio::spawn(async move {
+        let mut restarts = 0;
+        loop {
+            while let Some(event) = browse.rx.recv().await {
+                restarts = 0;
+                if tx.send(event).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                if restarts == max_restarts {
+                    warn!(
+                        restarts,
+                        "mDNS browse keeps stopping, giving up on discovery"
+                    );
+                    return;
+                }
+                let wait = delay * 2u32.pow(restarts);
+                restarts += 1;
+                warn!(attempt = restarts, ?wait, "mDNS browse stopped, restarting");
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = tx.closed() => return,
+                }
+                match restart() {
+                    Ok(restarted) => {
+                        browse = restarted;
+                        break;
+                    }
+                    Err(e) => warn!(error = %e, "failed to restart mDNS browse"),
+                }
+            }
+        }
+    });
+    DiscoveryStream { rx }
+}
+
+/// Type to register and browse. Browsing a subtype only finds instances
+/// registered with it; instances without one browse the plain type and would
+/// still see subtyped ones, so the advertised subtype is checked as well.
+fn service_type(subtype: Option<&str>) -> String {
+    match subtype {
+        Some(subtype) => format!("_{subtype}._sub.{SERVICE_TYPE}"),
+        None => SERVICE_TYPE.to_string(),
+    }
+}
+
+/// Validates a `--service-subtype` value as a DNS label.
+pub fn parse_subtype(value: &str) -> Result<String, String> {
+    let valid = !value.is_empty()
+        && value.len() <= 63
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid {
+        return Err("expected 1-63 letters, digits or '-'".to_string());
+    }
+    Ok(value.to_string())
+}
+
+/// Parses an advertisement into a peer, skipping instances from another
+/// deployment (a different subtype).
+fn parse_service_info(info: &ServiceInfo, subtype: Option<&str>) -> Option<Peer> {
+    let properties = info.get_properties();
+    let theirs = properties.get("subtype").map(|prop| prop.val_str());
+    if theirs != subtype {
+        return None;
+    }
+    let id = properties.get("id")?.val_str().parse().ok()?;
+    let ip = info.get_addresses().iter().next()?;
+    let name = info
+        .get_fullname()
+        .split('_')
+        .next()
+        .unwrap_or("unknown")
+        .to_string();
+    Some(Peer {
+        id,
+        name,
+        group: properties
+            .get("group")
+            .map(|prop| prop.val_str().to_string()),
+        addr: SocketAddr::new(ip.to_ip_addr(), info.get_port()),
+        last_active: properties
+            .get("last_active")
+            .and_then(|prop| prop.val_str().parse().ok()),
+    })
+}
+
+fn local_ips() -> Vec<IpAddr> {
+    local_ip_address::list_afinet_netifas()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(_, ip)| {
+            if ip.is_loopback() {
+                return None;
+            }
+            match ip {
+                IpAddr::V4(_) => Some(ip),
+                IpAddr::V6(v6) if !v6.is_loopback() && !is_link_local_v6(&v6) => Some(ip),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Whether an advertisement carrying our own instance id came from somewhere
+/// other than this process, i.e. the id was copied to another machine.
+fn is_duplicate_instance(addr: SocketAddr, local_ips: &[IpAddr]) -> bool {
+    !addr.ip().is_loopback() && !local_ips.contains(&addr.ip())
+}
+
+/// Extracts the instance id from a `{name}_{id}.<service type>` full name.
+fn id_from_fullname(fullname: &str) -> Option<Uuid> {
+    let instance = fullname.strip_suffix(SERVICE_TYPE)?.strip_suffix('.')?;
+    instance.rsplit_once('_')?.1.parse().ok()
+}
+
 fn is_link_local_v6(addr: &std::net::Ipv6Addr) -> bool {
     (addr.segments()[0] & 0xffc0) == 0xfe80
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    fn peer(name: &str) -> Peer {
+        Peer {
+            id: Uuid::new_v4(),
+            name: name.into(),
+            group: None,
+            addr: "10.0.0.2:42069".parse().unwrap(),
+            last_active: None,
+        }
+    }
+
+    #[test]
+    fn test_last_active_round_trips() {
+        let id = Uuid::new_v4();
+        let id_str = id.to_string();
+        let ip: IpAddr = "10.0.0.2".parse().unwrap();
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &format!("laptop_{id}"),
+            "laptop.local.",
+            ip,
+            42069,
+            &[("id", id_str.as_str()), ("last_active", "1700000040")],
+        )
+        .unwrap();
+        let peer = parse_service_info(&info, None).unwrap();
+        assert_eq!(peer.id, id);
+        assert_eq!(peer.name, "laptop");
+        assert_eq!(peer.addr, "10.0.0.2:42069".parse().unwrap());
+        assert_eq!(peer.last_active, Some(1_700_000_040));
+
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &format!("laptop_{id}"),
+            "laptop.local.",
+            ip,
+            42069,
+            &[("id", id_str.as_str())],
+        )
+        .unwrap();
+        assert_eq!(parse_service_info(&info, None).unwrap().last_active, None);
+    }
+
+    #[test]
+    fn test_other_subtype_not_discovered() {
+        let id = Uuid::new_v4().to_string();
+        let ip: IpAddr = "10.0.0.2".parse().unwrap();
+        let info = |subtype: Option<&str>| {
+            let mut properties = vec![("id", id.as_str())];
+            if let Some(subtype) = subtype {
+                properties.push(("subtype", subtype));
+            }
+            ServiceInfo::new(
+                &service_type(subtype),
+                "laptop",
+                "laptop.local.",
+                ip,
+                42069,
+                properties.as_slice(),
+            )
+            .unwrap()
+        };
+
+        assert!(parse_service_info(&info(Some("team-a")), Some("team-a")).is_some());
+        assert!(parse_service_info(&info(Some("team-b")), Some("team-a")).is_none());
+        assert!(parse_service_info(&info(Some("team-b")), None).is_none());
+        assert!(parse_service_info(&info(None), Some("team-a")).is_none());
+        assert_eq!(
+            service_type(Some("team-a")),
+            "_team-a._sub._cursedboard._tcp.local."
+        );
+        assert!(parse_subtype("team a").is_err());
+        assert!(parse_subtype("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_take_until() {
+        let (tx, rx) = mpsc::channel(8);
+        let stream = DiscoveryStream { rx };
+        tx.send(DiscoveryEvent::Resolved(peer("laptop")))
+            .await
+            .unwrap();
+        tx.send(DiscoveryEvent::Resolved(peer("desktop")))
+            .await
+            .unwrap();
+
+        // The sender stays open, so only the deadline ends the stream.
+        let names: Vec<String> = stream
+            .take_until(tokio::time::sleep(Duration::from_millis(50)))
+            .filter_map(|event| async move {
+                match event {
+                    DiscoveryEvent::Resolved(peer) => Some(peer.name),
+                    DiscoveryEvent::Removed(_) => None,
+                }
+            })
+            .collect()
+            .await;
+        assert_eq!(names, ["laptop", "desktop"]);
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn test_terminated_browse_restarted() {
+        let (tx, rx) = mpsc::channel(8);
+        drop(tx);
+        let senders = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let restart = {
+            let senders = senders.clone();
+            move || {
+                let (tx, rx) = mpsc::channel(8);
+                tx.try_send(DiscoveryEvent::Resolved(peer("laptop")))
+                    .unwrap();
+                senders.lock().unwrap().push(tx);
+                Ok(DiscoveryStream { rx })
+            }
+        };
+        let mut stream = watch_browse(DiscoveryStream { rx }, restart, 3, Duration::from_millis(1));
+
+        let event = tokio::time::timeout(Duration::from_secs(1), stream.next())
+            .await
+            .unwrap();
+        assert!(matches!(event, Some(DiscoveryEvent::Resolved(peer)) if peer.name == "laptop"));
+        assert_eq!(senders.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_browse_restarts_capped() {
+        let (_, rx) = mpsc::channel(1);
+        let restarts = Arc::new(AtomicU32::new(0));
+        let restart = {
+            let restarts = restarts.clone();
+            move || {
+                restarts.fetch_add(1, Ordering::SeqCst);
+                let (_, rx) = mpsc::channel(1);
+                Ok(DiscoveryStream { rx })
+            }
+        };
+        let mut stream = watch_browse(DiscoveryStream { rx }, restart, 3, Duration::from_millis(1));
+
+        let event = tokio::time::timeout(Duration::from_secs(1), stream.next())
+            .await
+            .unwrap();
+        assert!(event.is_none());
+        assert_eq!(restarts.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_failed_browse_unregisters() {
+        let unregistered = AtomicU32::new(0);
+        let unregister = || {
+            unregistered.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        };
+        let failed = register_then_browse(
+            || Ok(()),
+            || Err::<(), _>(mdns_sd::Error::Msg("browse failed".into()).into()),
+            unregister,
+        );
+        assert!(failed.is_err());
+        assert_eq!(unregistered.load(Ordering::SeqCst), 1);
+
+        assert!(register_then_browse(|| Ok(()), || Ok(()), unregister).is_ok());
+        let not_registered = register_then_browse(
+            || Err(mdns_sd::Error::Msg("register failed".into()).into()),
+            || Ok(()),
+            unregister,
+        );
+        assert!(not_registered.is_err());
+        assert_eq!(unregistered.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_same_id_from_other_address_is_duplicate() {
+        let local_ips: Vec<IpAddr> = vec!["10.0.0.2".parse().unwrap()];
+        assert!(!is_duplicate_instance(
+            "10.0.0.2:42069".parse().unwrap(),
+            &local_ips
+        ));
+        assert!(!is_duplicate_instance(
+            "127.0.0.1:42069".parse().unwrap(),
+            &local_ips
+        ));
+        assert!(is_duplicate_instance(
+            "10.0.0.3:42069".parse().unwrap(),
+            &local_ips
+        ));
+    }
+
+    #[test]
+    fn test_id_from_fullname() {
+        let id = Uuid::new_v4();
+        let fullname = format!("my_laptop_{}.{}", id, SERVICE_TYPE);
+        assert_eq!(id_from_fullname(&fullname), Some(id));
+        assert_eq!(id_from_fullname("laptop._other._tcp.local."), None);
+    }
+
+    #[test]
+    fn test_same_group_connects_under_every_policy() {
+        for policy in [GroupPolicy::Strict, GroupPolicy::Log, GroupPolicy::Allow] {
+            assert_eq!(
+                policy.decide(Some("home"), Some("home")),
+                GroupDecision::Connect
+            );
+            assert_eq!(policy.decide(None, None), GroupDecision::Connect);
+        }
+    }
+
+    #[test]
+    fn test_group_mismatch_policies() {
+        let (ours, theirs) = (Some("home"), Some("work"));
+        assert_eq!(
+            GroupPolicy::Strict.decide(ours, theirs),
+            GroupDecision::Ignore
+        );
+        assert_eq!(
+            GroupPolicy::Log.decide(ours, theirs),
+            GroupDecision::IgnoreLogged
+        );
+        assert_eq!(
+            GroupPolicy::Allow.decide(ours, theirs),
+            GroupDecision::Connect
+        );
+        assert_eq!(
+            GroupPolicy::Strict.decide(ours, None),
+            GroupDecision::Ignore
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::process::Stdio;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::warn;
+
+#[derive(Debug, Error)]
+pub enum FilterError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("exited with {0}")]
+    Failed(std::process::ExitStatus),
+    #[error("output is not valid utf-8")]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterDirection {
+    Outbound,
+    Inbound,
+    Both,
+}
+
+impl FilterDirection {
+    pub fn outbound(self) -> bool {
+        matches!(self, Self::Outbound | Self::Both)
+    }
+
+    pub fn inbound(self) -> bool {
+        matches!(self, Self::Inbound | Self::Both)
+    }
+}
+
+/// Shell command that clipboard content is piped through, using its stdout as
+/// the transformed content.
+#[derive(Debug, Clone)]
+pub struct FilterCommand {
+    command: String,
+    timeout: Duration,
+}
+
+impl FilterCommand {
+    pub fn new(command: String, timeout: Duration) -> Self {
+        Self { command, timeout }
+    }
+
+    /// Returns the filtered content, or the original if the command fails.
+    pub async fn apply(&self, content: &str) -> String {
+        match self.run(content).await {
+            Ok(filtered) => filtered,
+            Err(e) => {
+                warn!(command = %self.command, error = %e, "filter command failed, using original content");
+                content.to_string()
+            }
+        }
+    }
+
+    async fn run(&self, content: &str) -> Result<String, FilterError> {
+        let mut child = shell(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let input = content.to_string();
+        let writer = tokio::spawn(async move {
+            let _ = stdin.write_all(input.as_bytes()).await;
+        });
+
+        let output = tokio::time::timeout(self.timeout, child.wait_with_output())
+            .await
+            .map_err(|_| FilterError::Timeout(self.timeout))??;
+        let _ = writer.await;
+
+        if !output.status.success() {
+            return Err(FilterError::Failed(output.status));
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn filter(command: &str) -> FilterCommand {
+        FilterCommand::new(command.to_string(), Duration::from_secs(5))
+    }
+
+    #[tokio::test]
+    async fn test_filter_transforms_content() {
+        assert_eq!(filter("tr a-z A-Z").apply("hello").await, "HELLO");
+    }
+
+    #[tokio::test]
+    async fn test_failing_filter_falls_back() {
+        assert_eq!(filter("exit 3").apply("hello").await, "hello");
+    }
+
+    #[tokio::test]
+    async fn test_slow_filter_times_out() {
+        let filter = FilterCommand::new("sleep 5".to_string(), Duration::from_millis(50));
+        assert!(matches!(
+            filter.run("hello").await,
+            Err(FilterError::Timeout(_))
+        ));
+        assert_eq!(filter.apply("hello").await, "hello");
+    }
+}
@@ -0,0 +1,134 @@
+use crate::protocol::Selection;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::Notify;
+use tracing::debug;
+
+/// Clipboard content received from a peer, waiting to be applied locally.
+pub type Update = (Selection, String);
+
+struct Shared {
+    updates: Mutex<HashMap<Selection, String>>,
+    closed: AtomicBool,
+    notify: Notify,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Hands updates to the task applying them to the local clipboard, keeping
+/// only the latest one per selection: older content would be overwritten
+/// right away, and a slow clipboard must not hold up handling peer events.
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+
+pub struct Receiver {
+    shared: Arc<Shared>,
+}
+
+/// Creates a channel counting updates replaced before they were applied in
+/// `dropped`.
+pub fn channel(dropped: Arc<AtomicU64>) -> (Sender, Receiver) {
+    let shared = Arc::new(Shared {
+        updates: Mutex::default(),
+        closed: AtomicBool::new(false),
+        notify: Notify::new(),
+        dropped,
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl Sender {
+    pub fn send(&self, (selection, content): Update) {
+        let mut updates = self
+            .shared
+            .updates
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if updates.insert(selection, content).is_some() {
+            debug!(?selection, "clipboard behind, dropping unapplied update");
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        drop(updates);
+        self.shared.notify.notify_one();
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+        self.shared.notify.notify_one();
+    }
+}
+
+impl Receiver {
+    /// Returns a pending update, or `None` once the sender is gone and none
+    /// are left. Cancel safe.
+    pub async fn recv(&mut self) -> Option<Update> {
+        loop {
+            if let Some(update) = self.take() {
+                return Some(update);
+            }
+            if self.shared.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    fn take(&self) -> Option<Update> {
+        let mut updates = self
+            .shared
+            .updates
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let selection = *updates.keys().next()?;
+        updates.remove_entry(&selection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_flood_drops_intermediates_but_keeps_latest() {
+        let dropped = Arc::new(AtomicU64::new(0));
+        let (tx, mut rx) = channel(dropped.clone());
+        tx.send((Selection::Primary, "primary".to_string()));
+        for i in 1..=100 {
+            tx.send((Selection::Clipboard, format!("update {i}")));
+        }
+        drop(tx);
+
+        let mut received = HashMap::new();
+        while let Some((selection, content)) = rx.recv().await {
+            assert!(received.insert(selection, content).is_none());
+        }
+        assert_eq!(
+            received,
+            HashMap::from([
+                (Selection::Clipboard, "update 100".to_string()),
+                (Selection::Primary, "primary".to_string()),
+            ])
+        );
+        assert_eq!(dropped.load(Ordering::Relaxed), 99);
+    }
+
+    #[tokio::test]
+    async fn test_recv_waits_for_update() {
+        let (tx, mut rx) = channel(Arc::default());
+        let receiving = tokio::spawn(async move { rx.recv().await });
+        tokio::task::yield_now().await;
+        tx.send((Selection::Primary, "late".to_string()));
+        assert_eq!(
+            receiving.await.unwrap(),
+            Some((Selection::Primary, "late".to_string()))
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use crate::protocol::Selection;
+use crate::trust::write_atomic;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use tracing::warn;
+
+/// Hashes of the content last synced through each selection, kept on disk so
+/// a restarted daemon recognises an unchanged clipboard instead of
+/// broadcasting it again. Only hashes are stored, never the content itself.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LastSynced {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    clipboard: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    primary: Option<String>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl LastSynced {
+    /// Loads the hashes saved at `path`. A missing or unreadable file only
+    /// means the first poll after startup may broadcast once more.
+    pub fn load_from(path: PathBuf) -> Self {
+        let mut state: Self = match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content).unwrap_or_else(|e| {
+                warn!(path = %path.display(), error = %e, "ignoring unreadable last synced state");
+                Self::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "failed to read last synced state");
+                Self::default()
+            }
+        };
+        state.path = path;
+        state
+    }
+
+    /// Whether `content` is what was last synced through `selection`.
+    pub fn matches(&self, selection: Selection, content: &str) -> bool {
+        self.slot(selection).as_deref() == Some(content_hash(content).as_str())
+    }
+
+    /// Remembers `content` as last synced through `selection`, saving the
+    /// file if it changed.
+    pub fn record(&mut self, selection: Selection, content: &str) {
+        let hash = content_hash(content);
+        let slot = self.slot_mut(selection);
+        if slot.as_deref() == Some(hash.as_str()) {
+            return;
+        }
+        *slot = Some(hash);
+        if let Err(e) = self.save() {
+            warn!(path = %self.path.display(), error = %e, "failed to save last synced state");
+        }
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let content = toml::to_string_pretty(self).expect("hashes serialize to TOML");
+        write_atomic(&self.path, &content)
+    }
+
+    fn slot(&self, selection: Selection) -> &Option<String> {
+        match selection {
+            Selection::Clipboard => &self.clipboard,
+            Selection::Primary => &self.primary,
+        }
+    }
+
+    fn slot_mut(&mut self, selection: Selection) -> &mut Option<String> {
+        match selection {
+            Selection::Clipboard => &mut self.clipboard,
+            Selection::Primary => &mut self.primary,
+        }
+    }
+}
+
+/// Hex SHA-256 of `content`.
+pub fn content_hash(content: &str) -> String {
+    Sha256::digest(content.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_hashes_persist_without_content() {
+        let dir = std::env::temp_dir().join(format!("cursedboard-test-{}", Uuid::new_v4()));
+        let path = dir.join("last_synced.toml");
+        let mut state = LastSynced::load_from(path.clone());
+        state.record(Selection::Clipboard, "secret text");
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("secret text"));
+
+        let reloaded = LastSynced::load_from(path);
+        assert!(reloaded.matches(Selection::Clipboard, "secret text"));
+        assert!(!reloaded.matches(Selection::Clipboard, "other"));
+        assert!(!reloaded.matches(Selection::Primary, "secret text"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+use regex::Regex;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum LimitExceeded {
+    #[error("{0} lines exceeds the maximum of {1}")]
+    Lines(usize, usize),
+    #[error("{0} characters exceeds the maximum of {1}")]
+    Chars(usize, usize),
+    #[error("{0} characters is below the minimum of {1}")]
+    TooShort(usize, usize),
+    #[error("content matches none of the allowed patterns")]
+    NotAllowed,
+}
+
+/// Size and content rules clipboard content must satisfy to be broadcast.
+#[derive(Debug, Clone, Default)]
+pub struct ContentLimits {
+    pub max_lines: Option<usize>,
+    pub max_chars: Option<usize>,
+    /// Content with fewer characters is skipped; 0 disables the check.
+    pub min_chars: usize,
+    /// Content must match one of these; empty allows anything.
+    pub allow_patterns: Vec<Regex>,
+}
+
+impl ContentLimits {
+    pub fn check(&self, content: &str) -> Result<(), LimitExceeded> {
+        if let Some(max) = self.max_lines {
+            let lines = content.lines().count();
+            if lines > max {
+                return Err(LimitExceeded::Lines(lines, max));
+            }
+        }
+        let chars = content.chars().count();
+        if let Some(max) = self.max_chars {
+            if chars > max {
+                return Err(LimitExceeded::Chars(chars, max));
+            }
+        }
+        if chars < self.min_chars {
+            return Err(LimitExceeded::TooShort(chars, self.min_chars));
+        }
+        if !self.allow_patterns.is_empty()
+            && !self.allow_patterns.iter().any(|re| re.is_match(content))
+        {
+            return Err(LimitExceeded::NotAllowed);
+        }
+        Ok(())
+    }
+}
+
+/// Validates an `--allow-pattern` value as a regex.
+pub fn parse_pattern(value: &str) -> Result<String, String> {
+    Regex::new(value)
+        .map(|_| value.to_string())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_lines_boundary() {
+        let limits = ContentLimits {
+            max_lines: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(limits.check("a\nb\nc"), Ok(()));
+        assert_eq!(limits.check("a\nb\nc\n"), Ok(()));
+        assert_eq!(limits.check("a\nb\nc\nd"), Err(LimitExceeded::Lines(4, 3)));
+    }
+
+    #[test]
+    fn test_max_chars_boundary() {
+        let limits = ContentLimits {
+            max_chars: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(limits.check("héllo"), Ok(()));
+        assert_eq!(limits.check("héllo!"), Err(LimitExceeded::Chars(6, 5)));
+    }
+
+    #[test]
+    fn test_no_limits() {
+        let limits = ContentLimits::default();
+        assert_eq!(limits.check(&"line\n".repeat(10_000)), Ok(()));
+    }
+
+    #[test]
+    fn test_min_chars_boundary() {
+        let limits = ContentLimits {
+            min_chars: 3,
+            ..Default::default()
+        };
+        assert_eq!(limits.check("ab"), Err(LimitExceeded::TooShort(2, 3)));
+        assert_eq!(limits.check("abc"), Ok(()));
+        assert_eq!(limits.check("éé"), Err(LimitExceeded::TooShort(2, 3)));
+        assert_eq!(ContentLimits::default().check("a"), Ok(()));
+    }
+
+    #[test]
+    fn test_allow_patterns() {
+        let limits = ContentLimits {
+            allow_patterns: vec![
+                Regex::new(r"^https?://\S+$").unwrap(),
+                Regex::new(r"^\d{6}$").unwrap(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(limits.check("https://example.com/a?b=c"), Ok(()));
+        assert_eq!(limits.check("123456"), Ok(()));
+        assert_eq!(limits.check("my password"), Err(LimitExceeded::NotAllowed));
+        assert_eq!(ContentLimits::default().check("my password"), Ok(()));
+    }
+
+    #[test]
+    fn test_invalid_pattern_rejected() {
+        assert!(parse_pattern("^https?://").is_ok());
+        assert!(parse_pattern("(unclosed").is_err());
+    }
+}
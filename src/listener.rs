@@ -0,0 +1,175 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tracing::{error, warn};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BIND_BACKOFF: Duration = Duration::from_secs(30);
+
+pub trait Listener {
+    type Stream;
+
+    async fn accept(&mut self) -> io::Result<(Self::Stream, SocketAddr)>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self).await
+    }
+}
+
+/// OS-level TCP keepalive for peer connections, so peers that vanished
+/// without closing (e.g. behind a NAT that dropped the mapping) are detected
+/// by the kernel.
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub retries: u32,
+}
+
+impl Keepalive {
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let params = socket2::TcpKeepalive::new()
+            .with_time(self.idle)
+            .with_interval(self.interval)
+            .with_retries(self.retries);
+        socket2::SockRef::from(stream).set_tcp_keepalive(&params)
+    }
+}
+
+pub fn bind(addr: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+/// Retries `bind` up to `retries` more times when it fails, waiting
+/// `interval` (doubling each time) in between, so a daemon started before the
+/// network is up waits for it instead of exiting.
+pub async fn bind_with_retry<T>(
+    mut bind: impl FnMut() -> io::Result<T>,
+    retries: u32,
+    interval: Duration,
+) -> io::Result<T> {
+    let mut backoff = interval;
+    let mut attempt = 0;
+    loop {
+        match bind() {
+            Ok(bound) => return Ok(bound),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!(error = %e, attempt, retries, retry_in = ?backoff, "bind failed");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BIND_BACKOFF);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Waits for the next connection. Accept errors (e.g. running out of file
+/// descriptors) tend to repeat, so they are retried with backoff instead of
+/// spinning.
+pub async fn accept_next<L: Listener>(listener: &mut L) -> (L::Stream, SocketAddr) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                error!(error = %e, retry_in = ?backoff, "accept failed");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    struct FailingListener {
+        failures: usize,
+        calls: usize,
+    }
+
+    impl Listener for FailingListener {
+        type Stream = ();
+
+        async fn accept(&mut self) -> io::Result<((), SocketAddr)> {
+            self.calls += 1;
+            if self.calls <= self.failures {
+                return Err(io::Error::other("too many open files"));
+            }
+            Ok(((), "127.0.0.1:1".parse().unwrap()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_errors_back_off() {
+        let mut listener = FailingListener {
+            failures: 3,
+            calls: 0,
+        };
+        let start = Instant::now();
+        accept_next(&mut listener).await;
+        assert_eq!(listener.calls, 4);
+        assert!(start.elapsed() >= INITIAL_BACKOFF * 7);
+    }
+
+    #[tokio::test]
+    async fn test_bind_succeeds_after_failures() {
+        let mut calls = 0;
+        let bound = bind_with_retry(
+            || {
+                calls += 1;
+                if calls < 3 {
+                    return Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
+                }
+                Ok(calls)
+            },
+            5,
+            Duration::from_millis(1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(bound, 3);
+    }
+
+    #[tokio::test]
+    async fn test_bind_gives_up_after_retries() {
+        let mut calls = 0;
+        let result: io::Result<()> = bind_with_retry(
+            || {
+                calls += 1;
+                Err(io::Error::from(io::ErrorKind::AddrNotAvailable))
+            },
+            2,
+            Duration::from_millis(1),
+        )
+        .await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AddrNotAvailable);
+        assert_eq!(calls, 3);
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_backlog() {
+        let listener = bind("127.0.0.1:0".parse().unwrap(), 16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let mut listener = listener;
+        let (_, peer) = accept_next(&mut listener).await;
+        assert_eq!(peer, client.local_addr().unwrap());
+    }
+}
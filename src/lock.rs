@@ -0,0 +1,176 @@
+use crate::filter::FilterDirection;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::process::Command;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::info;
+
+/// How often the screen lock state is checked.
+pub const LOCK_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockState {
+    Locked,
+    Unlocked,
+    /// The platform can't tell, e.g. outside a desktop session.
+    Unknown,
+}
+
+pub trait LockStateSource: Send + 'static {
+    fn lock_state(&mut self) -> LockState;
+}
+
+/// Asks the desktop session whether the screen is locked: logind's
+/// `LockedHint` on Linux, the console session in `ioreg` on macOS.
+pub struct SystemLockState;
+
+impl LockStateSource for SystemLockState {
+    #[cfg(target_os = "linux")]
+    fn lock_state(&mut self) -> LockState {
+        let session = std::env::var("XDG_SESSION_ID").unwrap_or_else(|_| "auto".into());
+        let output = Command::new("loginctl")
+            .args(["show-session", &session, "--property=LockedHint", "--value"])
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                match String::from_utf8_lossy(&output.stdout).trim() {
+                    "yes" => LockState::Locked,
+                    "no" => LockState::Unlocked,
+                    _ => LockState::Unknown,
+                }
+            }
+            _ => LockState::Unknown,
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn lock_state(&mut self) -> LockState {
+        match Command::new("ioreg").args(["-n", "Root", "-d1"]).output() {
+            Ok(output) if output.status.success() => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                if stdout.contains("\"CGSSessionScreenIsLocked\"=Yes") {
+                    LockState::Locked
+                } else {
+                    LockState::Unlocked
+                }
+            }
+            _ => LockState::Unknown,
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    fn lock_state(&mut self) -> LockState {
+        LockState::Unknown
+    }
+}
+
+/// Pauses syncing in `direction` while the screen is locked. An unknown
+/// lock state never pauses.
+#[derive(Debug, Clone)]
+pub struct LockPause {
+    direction: FilterDirection,
+    state: watch::Receiver<LockState>,
+}
+
+impl LockPause {
+    /// Checks `source` every `interval` in the background.
+    pub fn watch(
+        mut source: impl LockStateSource,
+        direction: FilterDirection,
+        interval: Duration,
+    ) -> Self {
+        let (tx, state) = watch::channel(LockState::Unknown);
+        tokio::spawn(async move {
+            loop {
+                let Ok((returned, state)) = tokio::task::spawn_blocking(move || {
+                    let state = source.lock_state();
+                    (source, state)
+                })
+                .await
+                else {
+                    return;
+                };
+                source = returned;
+                let changed = tx.send_if_modified(|current| {
+                    let changed = *current != state;
+                    *current = state;
+                    changed
+                });
+                match state {
+                    LockState::Locked if changed => info!("screen locked, pausing sync"),
+                    LockState::Unlocked if changed => info!("screen unlocked, resuming sync"),
+                    _ => {}
+                }
+                if tx.is_closed() {
+                    return;
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+        Self { direction, state }
+    }
+
+    pub fn pauses_outbound(&self) -> bool {
+        self.direction.outbound() && self.locked()
+    }
+
+    pub fn pauses_inbound(&self) -> bool {
+        self.direction.inbound() && self.locked()
+    }
+
+    fn locked(&self) -> bool {
+        *self.state.borrow() == LockState::Locked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct StubLock(Arc<Mutex<LockState>>);
+
+    impl LockStateSource for StubLock {
+        fn lock_state(&mut self) -> LockState {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn test_paused_only_while_locked_in_direction() {
+        let state = Arc::new(Mutex::new(LockState::Unlocked));
+        let pause = LockPause::watch(
+            StubLock(state.clone()),
+            FilterDirection::Outbound,
+            Duration::from_millis(5),
+        );
+        settle().await;
+        assert!(!pause.pauses_outbound());
+
+        *state.lock().unwrap() = LockState::Locked;
+        settle().await;
+        assert!(pause.pauses_outbound());
+        assert!(!pause.pauses_inbound());
+
+        *state.lock().unwrap() = LockState::Unlocked;
+        settle().await;
+        assert!(!pause.pauses_outbound());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_state_never_pauses() {
+        let state = Arc::new(Mutex::new(LockState::Unknown));
+        let pause = LockPause::watch(
+            StubLock(state),
+            FilterDirection::Both,
+            Duration::from_millis(5),
+        );
+        settle().await;
+        assert!(!pause.pauses_outbound());
+        assert!(!pause.pauses_inbound());
+    }
+}
@@ -1,221 +1,3814 @@
+mod cidr;
+mod clipboard;
+#[cfg(unix)]
+mod control;
+mod disconnected;
+#[cfg(feature = "discovery")]
 mod discovery;
+mod filter;
+mod inbound;
+mod last_synced;
+mod limits;
+mod listener;
+mod lock;
+mod mime;
 mod peer;
+mod peers_file;
+mod ping;
+mod preview;
 mod protocol;
+mod recent;
+mod reconnect;
+#[cfg(feature = "discovery")]
+mod scan;
+mod schedule;
+mod status;
 mod trust;
 
-use arboard::Clipboard;
-use clap::Parser;
-use discovery::Discovery;
+use cidr::Cidr;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clipboard::{ClipboardProvider, SyncedClipboard};
+use disconnected::{DisconnectedPeer, RecentlyDisconnected};
+#[cfg(feature = "discovery")]
+use discovery::{
+    Discovery, DiscoveryError, DiscoveryEvent, DiscoveryStream, GroupDecision, GroupPolicy,
+};
+use filter::{FilterCommand, FilterDirection};
+#[cfg(feature = "discovery")]
+use futures::StreamExt;
+use last_synced::{content_hash, LastSynced};
+use limits::ContentLimits;
+use listener::Keepalive;
+use lock::{LockPause, SystemLockState, LOCK_CHECK_INTERVAL};
+use mime::{MimeBackend, MimePassthrough};
 use peer::{PeerConnection, PeerEvent};
-use std::collections::HashMap;
+use ping::PeerHealth;
+use preview::preview;
+use protocol::{Compression, ProtocolError, Selection};
+use reconnect::ReconnectPolicy;
+use regex::Regex;
+use schedule::ActiveHours;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::net::TcpListener;
-use tokio::sync::{mpsc, Mutex};
-use tracing::{error, info, warn};
-use trust::{Instance, TrustStore};
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch, Mutex, Notify};
+use tokio::task::AbortHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn};
+use trust::{Instance, StatePaths, TrustStore, Trusted};
 use uuid::Uuid;
 
-#[derive(Parser)]
+#[cfg(feature = "discovery")]
+const ACTIVITY_REFRESH: Duration = Duration::from_secs(60);
+const OPEN_PSK: &str = "cursedboard";
+const SAVE_ATTEMPTS: u32 = 3;
+const SAVE_RETRY_DELAY: Duration = Duration::from_millis(100);
+#[cfg(feature = "discovery")]
+const DISCOVERED_CONNECT_ATTEMPTS: u32 = 2;
+/// Length of content previews in control command replies.
+const PREVIEW_LEN: usize = 40;
+
+#[derive(Parser, Serialize)]
 #[command(name = "cursedboard", about = "Zero-config clipboard sync")]
 struct Args {
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+
+    #[arg(long)]
+    #[serde(skip)]
+    dump_config: bool,
+
     #[arg(short, long, default_value = "cursedboard")]
     name: String,
 
     #[arg(short, long, default_value = "42069")]
     port: u16,
 
-    #[arg(long, env = "CURSEDBOARD_PSK", default_value = "cursedboard")]
-    psk: String,
+    #[arg(short, long, env = "CURSEDBOARD_GROUP")]
+    group: Option<String>,
 
-    #[arg(long, default_value = "500")]
+    #[arg(long, env = "CURSEDBOARD_GROUP_SECRET", conflicts_with = "group")]
+    #[serde(serialize_with = "redact", skip_serializing_if = "Option::is_none")]
+    group_secret: Option<String>,
+
+    #[cfg(feature = "discovery")]
+    #[arg(long, value_enum, default_value = "strict")]
+    group_policy: GroupPolicy,
+
+    #[arg(long = "group-direction", value_name = "GROUP=DIRECTION", value_parser = parse_group_direction)]
+    group_directions: Vec<(String, Direction)>,
+
+    #[cfg(feature = "discovery")]
+    #[arg(long, value_parser = discovery::parse_subtype)]
+    service_subtype: Option<String>,
+
+    #[arg(long, value_enum, default_value = "suffix")]
+    duplicate_names: DuplicateNames,
+
+    #[arg(long, env = "CURSEDBOARD_PSK")]
+    #[serde(serialize_with = "redact", skip_serializing_if = "Option::is_none")]
+    psk: Option<String>,
+
+    #[arg(long, env = "CURSEDBOARD_PSK_FILE")]
+    psk_file: Option<PathBuf>,
+
+    #[arg(long)]
+    insecure_no_psk: bool,
+
+    #[arg(long, default_value = "16")]
+    min_psk_length: usize,
+
+    #[arg(long)]
+    strict_psk: bool,
+
+    #[arg(long, visible_alias = "check-interval", default_value = "500")]
     poll_ms: u64,
+
+    #[arg(long)]
+    active_hours: Option<ActiveHours>,
+
+    #[arg(long, requires = "active_hours")]
+    active_hours_inbound: bool,
+
+    #[arg(long, num_args = 0..=1, default_missing_value = "both")]
+    pause_when_locked: Option<FilterDirection>,
+
+    #[arg(long)]
+    instance_file: Option<PathBuf>,
+
+    #[arg(long)]
+    peers_file: Option<PathBuf>,
+
+    #[arg(long, default_value = "5")]
+    reconnect_attempts: u32,
+
+    #[arg(
+        long,
+        value_name = "MS,...",
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    reconnect_schedule_ms: Vec<u64>,
+
+    #[arg(long)]
+    max_lines: Option<usize>,
+
+    #[arg(long)]
+    max_chars: Option<usize>,
+
+    #[arg(long, default_value = "0")]
+    min_chars: usize,
+
+    #[arg(long = "allow-pattern", value_name = "REGEX", value_parser = limits::parse_pattern)]
+    allow_patterns: Vec<String>,
+
+    #[arg(long)]
+    filter_command: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "outbound",
+        requires = "filter_command"
+    )]
+    filter_direction: FilterDirection,
+
+    #[arg(long, default_value = "2000", requires = "filter_command")]
+    filter_timeout_ms: u64,
+
+    #[arg(long, default_value = "0")]
+    apply_delay_ms: u64,
+
+    #[arg(long, default_value = "0")]
+    min_apply_interval_ms: u64,
+
+    #[arg(long, default_value = "2000")]
+    echo_window_ms: u64,
+
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_inbound_rate: Option<u32>,
+
+    #[arg(long)]
+    sync_primary: bool,
+
+    #[arg(long, value_enum, default_value = "ignore")]
+    empty_content: EmptyContent,
+
+    #[arg(long = "sync-mime", value_name = "TYPE")]
+    sync_mime: Vec<String>,
+
+    #[arg(long)]
+    offline_queue: bool,
+
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    hash_check_secs: Option<u64>,
+
+    #[arg(long, value_name = "PEER")]
+    send_only: Vec<String>,
+
+    #[arg(long, value_name = "PEER")]
+    receive_only: Vec<String>,
+
+    #[arg(long)]
+    ephemeral: bool,
+
+    #[arg(long, conflicts_with = "instance_file")]
+    no_persist: bool,
+
+    #[arg(long)]
+    no_auto_trust: bool,
+
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_trusted_peers: Option<usize>,
+
+    #[arg(long, requires = "max_trusted_peers")]
+    strict_trust_limit: bool,
+
+    #[arg(long)]
+    no_auth_failure_details: bool,
+
+    #[arg(long, default_value = "1024")]
+    listen_backlog: u32,
+
+    #[arg(long = "allow-cidr", value_name = "CIDR")]
+    allowed_cidrs: Vec<Cidr>,
+
+    #[arg(long)]
+    no_compression: bool,
+
+    #[arg(long, default_value = "60")]
+    tcp_keepalive_secs: u64,
+
+    #[arg(long, default_value = "10")]
+    tcp_keepalive_interval_secs: u64,
+
+    #[arg(long, default_value = "5")]
+    tcp_keepalive_retries: u32,
+
+    #[arg(long, default_value = "30")]
+    ping_interval_secs: u64,
+
+    #[cfg(feature = "discovery")]
+    #[arg(long, default_value = "500")]
+    discovery_grace_ms: u64,
+
+    #[cfg(feature = "discovery")]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    no_peers_hint_secs: Option<u64>,
+
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    keep_disconnected_secs: Option<u64>,
+
+    #[arg(long, default_value = "10")]
+    bind_retries: u32,
+
+    #[arg(long, default_value = "500")]
+    bind_retry_ms: u64,
+
+    #[arg(long, num_args = 0..=1, default_missing_value = "127.0.0.1:42070")]
+    status_addr: Option<SocketAddr>,
+
+    #[cfg(feature = "discovery")]
+    #[arg(long)]
+    advertise_activity: bool,
 }
 
-type ClipboardTx = mpsc::Sender<(String, u64)>;
-type PeerMap = Arc<Mutex<HashMap<Uuid, ClipboardTx>>>;
+fn redact<S: serde::Serializer>(_: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("<redacted>")
+}
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive("cursedboard=info".parse()?),
-        )
-        .init();
+#[derive(Subcommand)]
+enum Command {
+    /// List instances on the network and whether they are trusted, without connecting
+    #[cfg(feature = "discovery")]
+    Scan {
+        #[arg(long, default_value = "5")]
+        seconds: u64,
+    },
+    /// Send a command to the running daemon, e.g. `ctl mute laptop`
+    Ctl {
+        #[arg(required = true, trailing_var_arg = true)]
+        command: Vec<String>,
+    },
+    /// Replace this machine's instance id, e.g. after cloning a VM image
+    RegenerateId,
+    /// Trust a peer by instance id before it ever connects
+    Trust { id: Uuid, name: Option<String> },
+}
 
-    let args = Args::parse();
-    let instance = Instance::load_or_create()?;
-    let trust_store = Arc::new(Mutex::new(TrustStore::load()?));
+type ClipboardTx = mpsc::Sender<(String, u64, Selection)>;
+type MimeApplyTx = mpsc::Sender<(String, Vec<u8>)>;
+type PeerMap = Arc<Mutex<HashMap<Uuid, PeerHandle>>>;
+type OfflineQueue = Arc<Mutex<HashMap<Selection, (String, u64)>>>;
+type LatestContent = Arc<Mutex<HashMap<Selection, (String, u64)>>>;
+type ReconnectingMap = Arc<Mutex<HashMap<Uuid, ReconnectingPeer>>>;
+type DisconnectedMap = Arc<Mutex<RecentlyDisconnected>>;
 
-    info!(id = %instance.id, name = %args.name, port = %args.port, "starting cursedboard");
+struct PeerHandle {
+    tx: ClipboardTx,
+    /// `Data` messages for MIME targets the peer listed in `mime_targets`.
+    data_tx: mpsc::Sender<protocol::Message>,
+    mime_targets: Vec<String>,
+    task: AbortHandle,
+    name: String,
+    /// `name`, disambiguated if another peer was already connected under it.
+    display_name: String,
+    /// Label from the trust store, set with `ctl label`.
+    label: Option<String>,
+    /// Group the peer advertised in its `Hello`.
+    group: Option<String>,
+    /// Address we dialed, for outbound connections that can be re-established.
+    addr: Option<SocketAddr>,
+    health: PeerHealth,
+    /// Whether the peer takes part in clipboard hash checks.
+    hash_check: bool,
+    /// Whether the peer syncs PRIMARY; it is never sent to peers that don't.
+    primary: bool,
+}
+
+/// An outbound peer that dropped and is being dialed again.
+struct ReconnectingPeer {
+    name: String,
+    attempts: u32,
+}
+
+/// State shared by every task that establishes peer connections.
+#[derive(Clone)]
+struct Node {
+    id: Uuid,
+    name: String,
+    /// Group we advertise, over mDNS and in `Hello`.
+    group: Option<String>,
+    psk: String,
+    peers: PeerMap,
+    reconnecting: ReconnectingMap,
+    /// Peers that dropped recently, listed in status until they expire.
+    disconnected: DisconnectedMap,
+    trust: Arc<Mutex<TrustStore>>,
+    /// Whether peers are added to the trust store on their first connection.
+    auto_trust: bool,
+    /// Whether failed authentications log the source address and the
+    /// identity the peer claimed.
+    auth_failure_details: bool,
+    events_tx: mpsc::Sender<PeerEvent>,
+    peer_connected: Arc<Notify>,
+    /// Makes the poller broadcast the current clipboard without waiting for
+    /// a change.
+    sync_now: Arc<Notify>,
+    muted: Arc<Mutex<HashSet<Uuid>>>,
+    directions: Arc<PeerDirections>,
+    duplicate_names: DuplicateNames,
+    inbound_interval: Option<Duration>,
+    keepalive: Option<Keepalive>,
+    /// How often connected peers are pinged to measure latency.
+    ping_interval: Option<Duration>,
+    /// Source ranges inbound connections are accepted from; empty accepts
+    /// any address.
+    allowed_cidrs: Arc<[Cidr]>,
+    /// Frame compression advertised to peers.
+    compression: &'static [Compression],
+    /// MIME targets synced besides text, advertised to peers.
+    mime_targets: Arc<[String]>,
+    /// Whether PRIMARY is synced, advertised to peers.
+    sync_primary: bool,
+    /// Where MIME data received from peers is sent to be applied locally.
+    mime_apply: Option<MimeApplyTx>,
+    /// Inbound updates dropped because the local clipboard fell behind.
+    inbound_dropped: Arc<AtomicU64>,
+    /// Until when inbound updates are held back instead of applied.
+    pinned_until: Arc<watch::Sender<Option<Instant>>>,
+    /// Effective settings and where each came from, for `ctl config sources`.
+    config_sources: Arc<str>,
+    /// Latest local change per selection made while no peer was connected,
+    /// sent to the next peer that connects.
+    offline_queue: Option<OfflineQueue>,
+    /// Content last synced through each selection and its timestamp, kept
+    /// for clipboard hash checks when they are enabled.
+    latest: Option<LatestContent>,
+}
 
-    let (peer_events_tx, mut peer_events_rx) = mpsc::channel::<PeerEvent>(32);
-    let (discovered_tx, mut discovered_rx) = mpsc::channel(32);
+/// How to show peers whose device name is already taken by another peer,
+/// e.g. machines set up from the same image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum DuplicateNames {
+    /// Append the start of the peer's id, e.g. `laptop#1a2b3c4d`.
+    #[default]
+    Suffix,
+    /// Show the name as advertised.
+    Keep,
+}
 
-    let discovery = Discovery::new(instance.id, args.name.clone(), args.port)?;
-    discovery.register()?;
-    discovery.browse(discovered_tx)?;
+impl DuplicateNames {
+    /// Name to show for peer `id` advertising `name`, given whether another
+    /// peer already goes by it. The advertised name itself is unchanged.
+    fn display_name(self, name: &str, id: Uuid, taken: bool) -> String {
+        match self {
+            Self::Suffix if taken => format!("{name}#{}", &id.simple().to_string()[..8]),
+            _ => name.to_string(),
+        }
+    }
+}
 
-    let listener = TcpListener::bind(("0.0.0.0", args.port)).await?;
-    info!(port = %args.port, "listening for connections");
+/// What to do with empty content from a peer. Local clipboards are never
+/// sent empty, so it only comes from peers that clear theirs on purpose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum EmptyContent {
+    /// Keep the local clipboard as it is.
+    Ignore,
+    /// Clear the local clipboard.
+    Clear,
+}
 
-    let peers: PeerMap = Arc::new(Mutex::new(HashMap::new()));
-    let last_content = Arc::new(Mutex::new(String::new()));
-    let clipboard = Arc::new(Mutex::new(Clipboard::new()?));
-
-    let peers_clone = peers.clone();
-    let psk = args.psk.clone();
-    let name = args.name.clone();
-    let id = instance.id;
-    let events_tx = peer_events_tx.clone();
-    let trust_clone = trust_store.clone();
-
-    tokio::spawn(async move {
-        loop {
-            match listener.accept().await {
-                Ok((stream, addr)) => {
-                    info!(%addr, "incoming connection");
-                    let mut conn = PeerConnection::from_stream(stream);
+/// Which way content flows with a peer, from our side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Direction {
+    /// Send to the peer but never accept content from it.
+    SendOnly,
+    /// Accept content from the peer but never send to it.
+    ReceiveOnly,
+    Both,
+}
 
-                    match conn.handshake_inbound(id, &name, &psk).await {
-                        Ok((peer_id, peer_name)) => {
-                            let mut trust = trust_clone.lock().await;
-                            if !trust.is_trusted(&peer_id) {
-                                info!(%peer_id, %peer_name, "trusting new peer");
-                                trust.trust(peer_id, peer_name.clone());
-                                let _ = trust.save();
-                            }
-                            drop(trust);
-
-                            let (clipboard_tx, clipboard_rx) = mpsc::channel(16);
-                            peers_clone.lock().await.insert(peer_id, clipboard_tx);
-
-                            let tx = events_tx.clone();
-                            tokio::spawn(async move {
-                                conn.run(tx, clipboard_rx).await;
-                            });
-                        }
-                        Err(e) => {
-                            warn!(%addr, error = %e, "handshake failed");
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!(error = %e, "accept failed");
+/// Parses a `--group-direction` value such as `demo=send-only`.
+fn parse_group_direction(value: &str) -> Result<(String, Direction), String> {
+    let (group, direction) = value
+        .split_once('=')
+        .ok_or("expected GROUP=DIRECTION, e.g. demo=send-only")?;
+    let direction = Direction::from_str(direction, false)?;
+    Ok((group.to_string(), direction))
+}
+
+/// Peers, given by name or id or by the group they advertise, that only
+/// sync in one direction.
+#[derive(Debug, Default)]
+struct PeerDirections {
+    /// Peers we send to but never accept content from.
+    send_only: HashSet<String>,
+    /// Peers we accept content from but never send to.
+    receive_only: HashSet<String>,
+    /// Direction for all peers advertising a group.
+    groups: HashMap<String, Direction>,
+}
+
+impl PeerDirections {
+    fn sends_to(&self, id: &Uuid, name: &str, group: Option<&str>) -> bool {
+        !Self::matches(&self.receive_only, id, name)
+            && self.group_direction(group) != Some(Direction::ReceiveOnly)
+    }
+
+    fn receives_from(&self, id: &Uuid, name: &str, group: Option<&str>) -> bool {
+        !Self::matches(&self.send_only, id, name)
+            && self.group_direction(group) != Some(Direction::SendOnly)
+    }
+
+    fn group_direction(&self, group: Option<&str>) -> Option<Direction> {
+        self.groups.get(group?).copied()
+    }
+
+    fn matches(peers: &HashSet<String>, id: &Uuid, name: &str) -> bool {
+        peers.contains(name) || peers.contains(&id.to_string())
+    }
+}
+
+impl Node {
+    async fn start_peer(
+        &self,
+        mut conn: PeerConnection,
+        peer_id: Uuid,
+        peer_name: String,
+        addr: Option<SocketAddr>,
+    ) {
+        let mut trust = self.trust.lock().await;
+        if trust.is_trusted(&peer_id) {
+            if trust.seen(&peer_id) {
+                save_trust(&trust).await;
+            }
+        } else if self.auto_trust {
+            match trust.trust(peer_id, peer_name.clone()) {
+                Trusted::Added => info!(%peer_id, %peer_name, "trusting new peer"),
+                Trusted::Evicted(evicted_id, evicted_name) => info!(
+                    %peer_id,
+                    %peer_name,
+                    %evicted_id,
+                    %evicted_name,
+                    "trusting new peer, forgetting least recently seen one"
+                ),
+                Trusted::Refused => {
+                    warn!(%peer_id, %peer_name, "trust store full, not trusting new peer")
                 }
             }
+            save_trust(&trust).await;
         }
-    });
+        let label = trust.label(&peer_id).map(str::to_string);
+        drop(trust);
+        self.disconnected.lock().await.remove(&peer_id);
+
+        let (clipboard_tx, clipboard_rx) = mpsc::channel(16);
+        let group = conn.peer_group().map(str::to_string);
+        let primary = conn.peer_primary();
+        self.flush_offline_queue(
+            peer_id,
+            &peer_name,
+            group.as_deref(),
+            primary,
+            &clipboard_tx,
+        )
+        .await;
+        let tx = self.events_tx.clone();
+        let inbound_interval = self.inbound_interval;
+        let ping_interval = self.ping_interval;
+        let mut peers = self.peers.lock().await;
+        if let Some(existing) = peers.get(&peer_id) {
+            if self.keep_existing(peer_id, existing.addr.is_some(), addr.is_some()) {
+                info!(%peer_id, "already connected to peer, closing duplicate connection");
+                drop(peers);
+                conn.close().await;
+                return;
+            }
+            info!(%peer_id, "replacing connection to peer");
+            existing.task.abort();
+        }
+        let taken = peers
+            .iter()
+            .any(|(id, peer)| *id != peer_id && peer.name == peer_name);
+        let display_name = self
+            .duplicate_names
+            .display_name(&peer_name, peer_id, taken);
+        if taken {
+            info!(%peer_id, %peer_name, %display_name, "another connected peer has the same name");
+        }
+        let (data_tx, data_rx) = mpsc::channel(4);
+        let mime_targets = conn.peer_mime_targets().to_vec();
+        let hash_check = conn.peer_hash_check();
+        let task = tokio::spawn(async move {
+            conn.with_inbound_interval(inbound_interval)
+                .with_ping_interval(ping_interval)
+                .with_outbound_data(data_rx)
+                .run(tx, clipboard_rx)
+                .await;
+        });
+        peers.insert(
+            peer_id,
+            PeerHandle {
+                tx: clipboard_tx,
+                data_tx,
+                mime_targets,
+                task: task.abort_handle(),
+                name: peer_name,
+                display_name,
+                label,
+                group,
+                addr,
+                health: PeerHealth::default(),
+                hash_check,
+                primary,
+            },
+        );
+        self.peer_connected.notify_waiters();
+    }
 
-    let peers_clone = peers.clone();
-    let psk = args.psk.clone();
-    let name = args.name.clone();
-    let id = instance.id;
-    let events_tx = peer_events_tx.clone();
-    let trust_clone = trust_store.clone();
+    async fn flush_offline_queue(
+        &self,
+        peer_id: Uuid,
+        peer_name: &str,
+        group: Option<&str>,
+        primary: bool,
+        tx: &ClipboardTx,
+    ) {
+        let Some(queue) = &self.offline_queue else {
+            return;
+        };
+        if self.is_muted(&peer_id).await || !self.directions.sends_to(&peer_id, peer_name, group) {
+            return;
+        }
+        // Delivered once: replaying it on every later connection would push
+        // this stale value over whatever peers copied in the meantime.
+        queue
+            .lock()
+            .await
+            .retain(|&selection, (content, timestamp)| {
+                if selection == Selection::Primary && !primary {
+                    return true;
+                }
+                debug!(peer = %peer_id, ?selection, "sending queued clipboard");
+                let busy = tx
+                    .try_send((content.clone(), *timestamp, selection))
+                    .is_err();
+                if busy {
+                    debug!(peer = %peer_id, ?selection, "peer busy, keeping queued clipboard");
+                }
+                busy
+            });
+    }
 
-    tokio::spawn(async move {
-        while let Some(peer) = discovered_rx.recv().await {
-            info!(id = %peer.id, name = %peer.name, addr = %peer.addr, "discovered peer");
+    /// Decides which of two connections to the same peer survives. A
+    /// duplicate dialed from the same side as the existing one is dropped;
+    /// when both sides dialed each other, both keep the connection dialed by
+    /// the smaller id so they agree on which one to close.
+    fn keep_existing(&self, peer_id: Uuid, existing_outbound: bool, new_outbound: bool) -> bool {
+        if existing_outbound == new_outbound {
+            return true;
+        }
+        let new_dialer = if new_outbound { self.id } else { peer_id };
+        new_dialer != self.id.min(peer_id)
+    }
 
-            if peers_clone.lock().await.contains_key(&peer.id) {
-                continue;
+    async fn connect(&self, addr: SocketAddr) -> Result<Uuid, ProtocolError> {
+        let mut conn = PeerConnection::connect(addr)
+            .await?
+            .with_keepalive(self.keepalive)
+            .with_compression(self.compression)
+            .with_group(self.group.as_deref())
+            .with_mime_targets(&self.mime_targets)
+            .with_hash_check(self.latest.is_some())
+            .with_primary(self.sync_primary);
+        let (peer_id, peer_name) = match conn
+            .handshake_outbound(self.id, &self.name, &self.psk)
+            .await
+        {
+            Ok(peer) => peer,
+            Err(e) => {
+                if matches!(e, ProtocolError::AuthFailed) {
+                    self.log_auth_failure(addr, &conn);
+                }
+                return Err(e);
             }
+        };
+        self.start_peer(conn, peer_id, peer_name, Some(addr)).await;
+        Ok(peer_id)
+    }
+
+    /// Warns about a peer at `addr` that failed to authenticate, usually
+    /// because its PSK differs, naming the id and name it claimed unless
+    /// that is disabled for privacy.
+    fn log_auth_failure(&self, addr: SocketAddr, conn: &PeerConnection) {
+        if !self.auth_failure_details {
+            warn!("peer failed authentication");
+            return;
+        }
+        let (claimed_id, claimed_name) = conn.claimed().unzip();
+        warn!(
+            %addr,
+            claimed_id = ?claimed_id,
+            claimed_name = ?claimed_name,
+            "peer failed authentication"
+        );
+    }
 
-            let mut conn = match PeerConnection::connect(peer.addr).await {
-                Ok(c) => c,
+    async fn reconnect(&self, id: Uuid, addr: SocketAddr, policy: ReconnectPolicy) {
+        let attempt = || async move {
+            if self.peers.lock().await.contains_key(&id) {
+                return Ok(());
+            }
+            match self.connect(addr).await {
+                Ok(_) => Ok(()),
                 Err(e) => {
-                    warn!(peer = %peer.id, error = %e, "failed to connect");
-                    continue;
+                    warn!(%id, %addr, error = %e, "reconnect failed");
+                    Err(e)
                 }
-            };
+            }
+        };
+        policy.run(id, attempt, &self.events_tx).await;
+    }
 
-            match conn.handshake_outbound(id, &name, &psk).await {
-                Ok((peer_id, peer_name)) => {
-                    let mut trust = trust_clone.lock().await;
-                    if !trust.is_trusted(&peer_id) {
-                        info!(%peer_id, %peer_name, "trusting new peer");
-                        trust.trust(peer_id, peer_name.clone());
-                        let _ = trust.save();
-                    }
-                    drop(trust);
+    async fn disconnect(&self, id: &Uuid) {
+        if let Some(handle) = self.peers.lock().await.remove(id) {
+            handle.task.abort();
+        }
+    }
 
-                    let (clipboard_tx, clipboard_rx) = mpsc::channel(16);
-                    peers_clone.lock().await.insert(peer_id, clipboard_tx);
+    /// Sends clipboard content to every connected peer that isn't muted, or
+    /// queues it for the next peer to connect while there are none.
+    async fn broadcast(&self, content: &str, timestamp: u64, selection: Selection) {
+        if let Some(queue) = &self.offline_queue {
+            let mut queue = queue.lock().await;
+            if self.peers.lock().await.is_empty() {
+                queue.insert(selection, (content.to_string(), timestamp));
+                return;
+            }
+            // Whatever was queued is older than this.
+            queue.remove(&selection);
+        }
+        self.send_clipboard(None, content, timestamp, selection)
+            .await;
+    }
 
-                    let tx = events_tx.clone();
-                    tokio::spawn(async move {
-                        conn.run(tx, clipboard_rx).await;
-                    });
-                }
-                Err(e) => {
-                    warn!(peer = %peer.id, error = %e, "handshake failed");
-                }
+    /// Sends clipboard content to the given peers only, skipping any that
+    /// aren't connected.
+    async fn broadcast_to(
+        &self,
+        targets: &[Uuid],
+        content: &str,
+        timestamp: u64,
+        selection: Selection,
+    ) {
+        self.send_clipboard(Some(targets), content, timestamp, selection)
+            .await;
+    }
+
+    async fn send_clipboard(
+        &self,
+        targets: Option<&[Uuid]>,
+        content: &str,
+        timestamp: u64,
+        selection: Selection,
+    ) {
+        let peers = self.peers.lock().await;
+        let muted = self.muted.lock().await;
+        if let Some(targets) = targets {
+            for id in targets.iter().filter(|id| !peers.contains_key(id)) {
+                debug!(peer = %id, "peer not connected, not sending clipboard");
             }
         }
-    });
+        for (id, peer) in peers.iter() {
+            if targets.is_some_and(|targets| !targets.contains(id)) {
+                continue;
+            }
+            if muted.contains(id) {
+                debug!(peer = %id, "peer muted, not sending clipboard");
+                continue;
+            }
+            if !self
+                .directions
+                .sends_to(id, &peer.name, peer.group.as_deref())
+            {
+                debug!(peer = %id, "peer is receive-only, not sending clipboard");
+                continue;
+            }
+            if selection == Selection::Primary && !peer.primary {
+                debug!(peer = %id, "peer doesn't sync PRIMARY, not sending it");
+                continue;
+            }
+            if peer
+                .tx
+                .send((content.to_string(), timestamp, selection))
+                .await
+                .is_err()
+            {
+                warn!(peer = %id, "failed to send clipboard");
+            }
+        }
+    }
+
+    /// Sends data for a MIME target to connected peers that accept it.
+    async fn broadcast_data(&self, target: &str, data: &[u8], timestamp: u64) {
+        let msg = protocol::Message::Data {
+            target: target.to_string(),
+            data: protocol::encode_data(data),
+            timestamp,
+        };
+        let peers = self.peers.lock().await;
+        let muted = self.muted.lock().await;
+        for (id, peer) in peers.iter() {
+            if !peer.mime_targets.iter().any(|accepted| accepted == target) {
+                debug!(peer = %id, %target, "peer doesn't accept target, not sending");
+                continue;
+            }
+            if muted.contains(id)
+                || !self
+                    .directions
+                    .sends_to(id, &peer.name, peer.group.as_deref())
+            {
+                continue;
+            }
+            if peer.data_tx.send(msg.clone()).await.is_err() {
+                warn!(peer = %id, "failed to send clipboard data");
+            }
+        }
+    }
 
-    let peers_clone = peers.clone();
-    let last_clone = last_content.clone();
-    let clipboard_clone = clipboard.clone();
-    let poll_interval = Duration::from_millis(args.poll_ms);
+    /// Remembers `content` as the latest synced through `selection` for
+    /// hash checks, unless it is older than what was recorded already.
+    async fn record_latest(&self, selection: Selection, content: &str, timestamp: u64) {
+        let Some(latest) = &self.latest else { return };
+        let mut latest = latest.lock().await;
+        if latest
+            .get(&selection)
+            .is_some_and(|(_, recorded)| *recorded > timestamp)
+        {
+            return;
+        }
+        latest.insert(selection, (content.to_string(), timestamp));
+    }
 
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(poll_interval);
-        loop {
-            interval.tick().await;
+    /// Whether a peer's hash check shows it has newer content for
+    /// `selection` than we last synced.
+    async fn behind(&self, selection: Selection, hash: &str, timestamp: u64) -> bool {
+        let Some(latest) = &self.latest else {
+            return false;
+        };
+        match latest.lock().await.get(&selection) {
+            Some((content, recorded)) => timestamp > *recorded && content_hash(content) != hash,
+            None => true,
+        }
+    }
 
-            let content = {
-                let mut cb = clipboard_clone.lock().await;
-                cb.get_text().unwrap_or_default()
-            };
+    /// Whether content from the peer would be accepted at all.
+    async fn accepts_from(&self, id: &Uuid) -> bool {
+        if self.is_muted(id).await {
+            return false;
+        }
+        let name = self.peer_name(id).await.unwrap_or_default();
+        let group = self.peer_group(id).await;
+        self.directions.receives_from(id, &name, group.as_deref())
+    }
 
-            let mut last = last_clone.lock().await;
-            if content != *last && !content.is_empty() {
-                *last = content.clone();
-                drop(last);
+    /// Sends a protocol message to one peer as it is.
+    async fn send_message(&self, id: &Uuid, msg: protocol::Message) {
+        let Some(data_tx) = self
+            .peers
+            .lock()
+            .await
+            .get(id)
+            .map(|peer| peer.data_tx.clone())
+        else {
+            return;
+        };
+        if data_tx.send(msg).await.is_err() {
+            debug!(peer = %id, "peer gone, message not sent");
+        }
+    }
 
-                let timestamp = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis() as u64;
+    async fn peer_name(&self, id: &Uuid) -> Option<String> {
+        self.peers
+            .lock()
+            .await
+            .get(id)
+            .map(|handle| handle.name.clone())
+    }
 
-                let peers = peers_clone.lock().await;
-                for (id, tx) in peers.iter() {
-                    if tx.send((content.clone(), timestamp)).await.is_err() {
-                        warn!(peer = %id, "failed to send clipboard");
-                    }
+    async fn peer_group(&self, id: &Uuid) -> Option<String> {
+        self.peers
+            .lock()
+            .await
+            .get(id)
+            .and_then(|handle| handle.group.clone())
+    }
+
+    async fn display_name(&self, id: &Uuid) -> Option<String> {
+        self.peers
+            .lock()
+            .await
+            .get(id)
+            .map(|handle| handle.display_name.clone())
+    }
+
+    async fn peer_label(&self, id: &Uuid) -> Option<String> {
+        self.peers
+            .lock()
+            .await
+            .get(id)
+            .and_then(|handle| handle.label.clone())
+    }
+
+    async fn is_muted(&self, id: &Uuid) -> bool {
+        self.muted.lock().await.contains(id)
+    }
+
+    /// Resolves a peer given by id or by the name or display name of a
+    /// connected peer.
+    async fn resolve_peer(&self, peer: &str) -> Option<Uuid> {
+        if let Ok(id) = peer.parse() {
+            return Some(id);
+        }
+        self.peers
+            .lock()
+            .await
+            .iter()
+            .find(|(_, handle)| handle.name == peer || handle.display_name == peer)
+            .map(|(id, _)| *id)
+    }
+
+    #[cfg(unix)]
+    async fn handle_control(&self, command: control::Command) -> String {
+        match command {
+            control::Command::Mute(peer) => match self.resolve_peer(&peer).await {
+                Some(id) => {
+                    self.muted.lock().await.insert(id);
+                    info!(%id, "peer muted");
+                    format!("muted {id}")
+                }
+                None => format!("error: no connected peer named {peer}"),
+            },
+            control::Command::Unmute(peer) => match self.resolve_peer(&peer).await {
+                Some(id) => {
+                    self.muted.lock().await.remove(&id);
+                    info!(%id, "peer unmuted");
+                    format!("unmuted {id}")
+                }
+                None => format!("error: no connected peer named {peer}"),
+            },
+            control::Command::Pin(duration) => {
+                let Some(until) = Instant::now().checked_add(duration) else {
+                    return format!("error: can't pin for {}s", duration.as_secs());
+                };
+                self.pinned_until.send_replace(Some(until));
+                info!(?duration, "clipboard pinned");
+                format!("pinned for {}s", duration.as_secs())
+            }
+            control::Command::Unpin => {
+                self.pinned_until.send_replace(None);
+                info!("clipboard unpinned");
+                "unpinned".to_string()
+            }
+            control::Command::Send(text) => {
+                self.broadcast(&text, unix_millis(), Selection::Clipboard)
+                    .await;
+                format!(
+                    "sent {} chars: {}",
+                    text.chars().count(),
+                    preview(&text, PREVIEW_LEN)
+                )
+            }
+            control::Command::SendTo { peer, text } => match self.resolve_peer(&peer).await {
+                Some(id) => {
+                    self.broadcast_to(&[id], &text, unix_millis(), Selection::Clipboard)
+                        .await;
+                    format!(
+                        "sent {} chars to {id}: {}",
+                        text.chars().count(),
+                        preview(&text, PREVIEW_LEN)
+                    )
                 }
+                None => format!("error: no connected peer named {peer}"),
+            },
+            control::Command::SyncNow => {
+                if self.peers.lock().await.is_empty() {
+                    return "error: no connected peers".to_string();
+                }
+                self.sync_now.notify_one();
+                info!("syncing current clipboard on request");
+                "syncing current clipboard".to_string()
             }
+            control::Command::Label { peer, label } => match self.resolve_peer(&peer).await {
+                Some(id) => {
+                    let mut trust = self.trust.lock().await;
+                    if !trust.set_label(&id, label.clone()) {
+                        return format!("error: {peer} is not trusted");
+                    }
+                    save_trust(&trust).await;
+                    drop(trust);
+                    if let Some(handle) = self.peers.lock().await.get_mut(&id) {
+                        handle.label = label.clone();
+                    }
+                    match label {
+                        Some(label) => {
+                            info!(%id, %label, "peer labeled");
+                            format!("labeled {id} {label}")
+                        }
+                        None => {
+                            info!(%id, "peer label cleared");
+                            format!("unlabeled {id}")
+                        }
+                    }
+                }
+                None => format!("error: no connected peer named {peer}"),
+            },
+            control::Command::ConfigSources => self.config_sources.to_string(),
         }
-    });
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::from_default_env()
+                .add_directive("cursedboard=info".parse()?),
+        )
+        .init();
 
-    let clipboard_clone = clipboard.clone();
-    let last_clone = last_content.clone();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches)?;
+    if args.dump_config {
+        print!("{}", toml::to_string(&args)?);
+        return Ok(());
+    }
+    let started = Instant::now();
+    let paths = StatePaths::new(args.instance_file.as_deref());
+    check_command_persists(&args)?;
+    if let Some(Command::RegenerateId) = args.command {
+        let instance = Instance::load_or_create_from(paths.instance.clone())?;
+        let old_id = instance.id;
+        let instance = instance.regenerate()?;
+        println!("instance id changed from {old_id} to {}", instance.id);
+        println!("peers will see this machine as a new device and re-trust it on first connection");
+        return Ok(());
+    }
+    if let Some(Command::Trust { id, name }) = &args.command {
+        let mut trust = limit_trust(TrustStore::load_from(paths.trusted())?, &args)?;
+        println!("{}", trust_peer(&mut trust, *id, name.clone())?);
+        return Ok(());
+    }
+    let config_writable = state_writable(&paths, args.no_persist);
+    let instance = load_instance(&paths, config_writable, args.no_persist)?;
 
-    while let Some(event) = peer_events_rx.recv().await {
-        match event {
-            PeerEvent::Connected { id, name } => {
-                info!(%id, %name, "peer connected");
+    match &args.command {
+        #[cfg(feature = "discovery")]
+        Some(Command::Scan { seconds }) => {
+            return scan::run(
+                instance.id,
+                paths.trusted(),
+                args.service_subtype.clone(),
+                Duration::from_secs(*seconds),
+            )
+            .await;
+        }
+        Some(Command::Ctl { command }) => {
+            #[cfg(unix)]
+            {
+                let socket = paths.control_socket().unwrap_or_else(control::socket_path);
+                let response = control::send(&socket, &command.join(" ")).await?;
+                print!("{response}");
+                return Ok(());
             }
-            PeerEvent::Clipboard { content, timestamp } => {
-                info!(len = content.len(), %timestamp, "received clipboard");
-                let mut last = last_clone.lock().await;
-                *last = content.clone();
-                drop(last);
+            #[cfg(not(unix))]
+            anyhow::bail!("control commands are only supported on unix ({command:?})");
+        }
+        Some(Command::RegenerateId | Command::Trust { .. }) | None => {}
+    }
 
-                let mut cb = clipboard_clone.lock().await;
-                if let Err(e) = cb.set_text(&content) {
-                    error!(error = %e, "failed to set clipboard");
-                }
-            }
-            PeerEvent::Disconnected { id } => {
-                info!(%id, "peer disconnected");
-                peers.lock().await.remove(&id);
+    let psk = resolve_psk(
+        args.psk.as_deref(),
+        args.psk_file.as_deref(),
+        args.insecure_no_psk,
+    )?;
+    if !args.insecure_no_psk {
+        check_psk(&psk, args.min_psk_length, args.strict_psk)?;
+    }
+
+    let mut trust_store = TrustStore::load_from(paths.trusted())?;
+    if args.ephemeral {
+        info!("ephemeral mode, trust changes will not be saved");
+        trust_store = trust_store.ephemeral();
+    } else if !config_writable {
+        trust_store = trust_store.ephemeral();
+    }
+    let trust_store = limit_trust(trust_store, &args)?;
+    let trust_store = Arc::new(Mutex::new(trust_store));
+
+    let backend = clipboard::Backend::detect();
+    if args.sync_primary && !backend.has_primary() {
+        warn!(%backend, "--sync-primary needs an X11 or Wayland session, PRIMARY won't sync");
+    }
+
+    let (peer_events_tx, peer_events_rx) = mpsc::channel::<PeerEvent>(32);
+
+    let group = args
+        .group
+        .clone()
+        .or_else(|| args.group_secret.as_deref().map(protocol::group_token));
+    let listener = listener::bind_with_retry(
+        || listener::bind(([0, 0, 0, 0], args.port).into(), args.listen_backlog),
+        args.bind_retries,
+        Duration::from_millis(args.bind_retry_ms),
+    )
+    .await?;
+    // Only advertise once peers can connect, and never if the port is taken.
+    #[cfg(feature = "discovery")]
+    let (discovery, discovered) = start_discovery(|| {
+        Discovery::new(
+            instance.id,
+            args.name.clone(),
+            group.clone(),
+            args.service_subtype.clone(),
+            args.port,
+        )
+    })
+    .unzip();
+    StartupSummary {
+        id: instance.id,
+        name: &args.name,
+        group: group.as_deref(),
+        listen: listener.local_addr()?,
+        #[cfg(feature = "discovery")]
+        discovery: discovery.is_some(),
+        #[cfg(not(feature = "discovery"))]
+        discovery: false,
+        psk: PskSource::of(&psk, args.psk_file.is_some()),
+        backend,
+    }
+    .log();
+
+    let peers: PeerMap = Arc::new(Mutex::new(HashMap::new()));
+    let mime = mime_passthrough(&args.sync_mime);
+    let mime_targets = if mime.is_some() {
+        args.sync_mime.clone()
+    } else {
+        Vec::new()
+    };
+    let (mime_apply, mime_apply_rx) = mpsc::channel(4);
+    let mime_apply = mime.is_some().then_some(mime_apply);
+    let mut clipboard =
+        SyncedClipboard::new().with_echo_window(Duration::from_millis(args.echo_window_ms));
+    if config_writable {
+        clipboard = clipboard.with_last_synced(LastSynced::load_from(paths.last_synced()));
+    }
+    let clipboard: Arc<Mutex<SyncedClipboard>> = Arc::new(Mutex::new(clipboard));
+
+    let node = Node {
+        id: instance.id,
+        name: args.name.clone(),
+        group: group.clone(),
+        psk,
+        peers: peers.clone(),
+        reconnecting: Arc::new(Mutex::new(HashMap::new())),
+        disconnected: Arc::new(Mutex::new(RecentlyDisconnected::new(
+            args.keep_disconnected_secs.map(Duration::from_secs),
+        ))),
+        trust: trust_store.clone(),
+        auto_trust: !args.no_auto_trust,
+        auth_failure_details: !args.no_auth_failure_details,
+        events_tx: peer_events_tx.clone(),
+        peer_connected: Arc::new(Notify::new()),
+        sync_now: Arc::new(Notify::new()),
+        muted: Arc::new(Mutex::new(HashSet::new())),
+        directions: Arc::new(PeerDirections {
+            send_only: args.send_only.iter().cloned().collect(),
+            receive_only: args.receive_only.iter().cloned().collect(),
+            groups: args.group_directions.iter().cloned().collect(),
+        }),
+        duplicate_names: args.duplicate_names,
+        inbound_interval: args
+            .max_inbound_rate
+            .map(|rate| Duration::from_secs(1) / rate),
+        keepalive: (args.tcp_keepalive_secs > 0).then(|| Keepalive {
+            idle: Duration::from_secs(args.tcp_keepalive_secs),
+            interval: Duration::from_secs(args.tcp_keepalive_interval_secs),
+            retries: args.tcp_keepalive_retries,
+        }),
+        ping_interval: (args.ping_interval_secs > 0)
+            .then(|| Duration::from_secs(args.ping_interval_secs)),
+        allowed_cidrs: args.allowed_cidrs.clone().into(),
+        compression: if args.no_compression {
+            &[]
+        } else {
+            Compression::SUPPORTED
+        },
+        mime_targets: mime_targets.into(),
+        sync_primary: args.sync_primary,
+        mime_apply,
+        inbound_dropped: Arc::new(AtomicU64::new(0)),
+        pinned_until: Arc::new(watch::Sender::new(None)),
+        config_sources: config_sources(&args, &matches)?.into(),
+        offline_queue: args.offline_queue.then(Default::default),
+        latest: args.hash_check_secs.map(|_| Default::default()),
+    };
+
+    #[cfg(unix)]
+    if let Some(listener) = control_socket(&paths, args.no_persist).and_then(|socket| {
+        control::bind(&socket)
+            .inspect_err(|e| {
+                warn!(path = %socket.display(), error = %e, "control socket unavailable, ctl commands are disabled");
+            })
+            .ok()
+    }) {
+        let (control_tx, mut control_rx) = mpsc::channel::<control::Request>(8);
+        tokio::spawn(control::serve(listener, control_tx));
+        let control_node = node.clone();
+        tokio::spawn(async move {
+            while let Some(request) = control_rx.recv().await {
+                let response = control_node.handle_control(request.command).await;
+                let _ = request.reply.send(response);
             }
+        });
+    }
+
+    tokio::spawn(accept_peers(node.clone(), listener));
+
+    #[cfg(feature = "discovery")]
+    if let Some(discovered) = discovered {
+        tokio::spawn(connect_discovered(
+            node.clone(),
+            discovered,
+            group,
+            args.group_policy,
+            Duration::from_millis(args.discovery_grace_ms),
+        ));
+        if let Some(secs) = args.no_peers_hint_secs {
+            tokio::spawn(hint_no_peers(
+                node.clone(),
+                Duration::from_secs(secs),
+                args.port,
+            ));
         }
     }
 
+    if let Some(secs) = args.hash_check_secs {
+        tokio::spawn(check_hashes(node.clone(), Duration::from_secs(secs)));
+    }
+
+    if let Some(addr) = args.status_addr {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        tokio::spawn(status::serve(
+            listener,
+            peers.clone(),
+            node.reconnecting.clone(),
+            node.disconnected.clone(),
+            started,
+            backend,
+            node.inbound_dropped.clone(),
+        ));
+    }
+
+    if let Some(path) = args.peers_file.clone() {
+        tokio::spawn(watch_peers_file(node.clone(), path));
+    }
+
+    let last_active = Arc::new(AtomicU64::new(0));
+    #[cfg(feature = "discovery")]
+    if let Some(discovery) = discovery.as_ref().filter(|_| args.advertise_activity) {
+        tokio::spawn(advertise_activity(discovery.clone(), last_active.clone()));
+    }
+
+    let options = SyncOptions::from_args(&args);
+    if let Some(mime) = mime {
+        tokio::spawn(sync_mime(
+            node.clone(),
+            mime,
+            mime_apply_rx,
+            options.poll_interval,
+        ));
+    }
+    tokio::spawn(poll_clipboard(
+        node.clone(),
+        clipboard.clone(),
+        options.clone(),
+        last_active,
+    ));
+    handle_events(node, peer_events_rx, clipboard, options).await;
+
+    #[cfg(feature = "discovery")]
+    if let Some(discovery) = discovery {
+        discovery.shutdown()?;
+    }
     Ok(())
 }
+
+#[cfg(feature = "discovery")]
+/// Registers our mDNS service and starts browsing for peers. Containers and
+/// WSL often lack working multicast, so a failure leaves the daemon running
+/// with manual peers only instead of stopping it.
+fn start_discovery(
+    init: impl FnOnce() -> Result<Discovery, DiscoveryError>,
+) -> Option<(Discovery, DiscoveryStream)> {
+    let started = init().and_then(|discovery| match discovery.start() {
+        Ok(discovered) => Ok((discovery, discovered)),
+        Err(e) => {
+            let _ = discovery.shutdown();
+            Err(e)
+        }
+    });
+    match started {
+        Ok(started) => Some(started),
+        Err(e) => {
+            warn!(error = %e, "mDNS unavailable, only connecting to manual peers");
+            None
+        }
+    }
+}
+
+/// Connects to peers found over mDNS that pass the group policy.
+#[cfg(feature = "discovery")]
+async fn connect_discovered(
+    node: Node,
+    mut discovered: DiscoveryStream,
+    group: Option<String>,
+    group_policy: GroupPolicy,
+    grace: Duration,
+) {
+    let mut dialing: HashMap<Uuid, tokio::task::JoinHandle<()>> = HashMap::new();
+    while let Some(event) = discovered.next().await {
+        let peer = match event {
+            DiscoveryEvent::Resolved(peer) => peer,
+            DiscoveryEvent::Removed(id) => {
+                debug!(%id, "peer no longer advertised");
+                continue;
+            }
+        };
+        match group_policy.decide(group.as_deref(), peer.group.as_deref()) {
+            GroupDecision::Connect => {}
+            GroupDecision::Ignore => continue,
+            GroupDecision::IgnoreLogged => {
+                info!(id = %peer.id, name = %peer.name, group = ?peer.group, "ignoring peer from another group");
+                continue;
+            }
+        }
+
+        info!(id = %peer.id, name = %peer.name, addr = %peer.addr, "discovered peer");
+
+        if node.peers.lock().await.contains_key(&peer.id) {
+            continue;
+        }
+
+        // Dial in the background, so a peer that is slow to answer doesn't
+        // hold up connecting to the others, but only once at a time.
+        dialing.retain(|_, dial| !dial.is_finished());
+        if dialing.contains_key(&peer.id) {
+            continue;
+        }
+        let dial = tokio::spawn(dial_discovered(node.clone(), peer.id, peer.addr, grace));
+        dialing.insert(peer.id, dial);
+    }
+}
+
+#[cfg(feature = "discovery")]
+/// Dials a newly discovered peer after `grace`, since its mDNS record can
+/// show up before its listener is ready, and once more if that fails.
+async fn dial_discovered(node: Node, id: Uuid, addr: SocketAddr, grace: Duration) {
+    for attempt in 1..=DISCOVERED_CONNECT_ATTEMPTS {
+        tokio::time::sleep(grace).await;
+        // The peer may have dialed us in the meantime.
+        if node.peers.lock().await.contains_key(&id) {
+            return;
+        }
+        match node.connect(addr).await {
+            Ok(_) => return,
+            Err(e) if e.is_retryable() && attempt < DISCOVERED_CONNECT_ATTEMPTS => {
+                debug!(peer = %id, error = %e, "failed to connect, retrying");
+            }
+            Err(e) => {
+                warn!(peer = %id, error = %e, "failed to connect");
+                return;
+            }
+        }
+    }
+}
+
+/// Where the PSK came from, logged instead of the key itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PskSource {
+    File,
+    Given,
+    /// The publicly known key used with `--insecure-no-psk`.
+    Insecure,
+}
+
+impl PskSource {
+    fn of(psk: &str, from_file: bool) -> Self {
+        if from_file {
+            Self::File
+        } else if psk == OPEN_PSK {
+            Self::Insecure
+        } else {
+            Self::Given
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::File => "file",
+            Self::Given => "set",
+            Self::Insecure => "insecure",
+        }
+    }
+}
+
+/// The effective startup state as a single `startup` event, so logs have one
+/// line to anchor on and monitoring can pick the fields out of it.
+struct StartupSummary<'a> {
+    id: Uuid,
+    name: &'a str,
+    group: Option<&'a str>,
+    listen: SocketAddr,
+    discovery: bool,
+    psk: PskSource,
+    backend: clipboard::Backend,
+}
+
+impl StartupSummary<'_> {
+    fn log(&self) {
+        info!(
+            version = env!("CARGO_PKG_VERSION"),
+            id = %self.id,
+            name = self.name,
+            group = self.group,
+            listen = %self.listen,
+            discovery = self.discovery,
+            psk = self.psk.as_str(),
+            backend = %self.backend,
+            "startup"
+        );
+    }
+}
+
+/// Picks the PSK from `--psk-file` or `--psk`. Running without one falls
+/// back to the key every instance used to default to, which anyone can
+/// guess, so it has to be asked for with `--insecure-no-psk`.
+fn resolve_psk(
+    psk: Option<&str>,
+    psk_file: Option<&Path>,
+    insecure_no_psk: bool,
+) -> anyhow::Result<String> {
+    if let Some(path) = psk_file {
+        return Ok(protocol::read_psk_file(path)?);
+    }
+    if let Some(psk) = psk {
+        // Most likely an unset variable expanded into `--psk "$PSK"`.
+        if psk.is_empty() {
+            anyhow::bail!("--psk or CURSEDBOARD_PSK is set but empty");
+        }
+        return Ok(psk.to_string());
+    }
+    if !insecure_no_psk {
+        anyhow::bail!(
+            "no PSK configured; set --psk, --psk-file or CURSEDBOARD_PSK, \
+             or pass --insecure-no-psk to run with the publicly known default key"
+        );
+    }
+    warn!("running without a PSK: anyone on the network can read and replace this clipboard");
+    Ok(OPEN_PSK.to_string())
+}
+
+/// Warns about a weak PSK, or refuses it with `strict`.
+fn check_psk(psk: &str, min_len: usize, strict: bool) -> anyhow::Result<()> {
+    let Err(weak) = protocol::check_psk_strength(psk, min_len) else {
+        return Ok(());
+    };
+    if strict {
+        anyhow::bail!("{weak}; use a longer random key, e.g. from `openssl rand -base64 24`");
+    }
+    warn!(reason = %weak, "weak PSK; use a longer random key, or --strict-psk to refuse weak keys");
+    Ok(())
+}
+
+/// Refuses subcommands whose only effect is a state file that `--no-persist`
+/// or `--ephemeral` would keep from being written.
+fn check_command_persists(args: &Args) -> anyhow::Result<()> {
+    match args.command {
+        Some(Command::RegenerateId) if args.no_persist => {
+            anyhow::bail!("regenerate-id can't be used with --no-persist, which never saves an id")
+        }
+        Some(Command::Trust { .. }) if args.no_persist || args.ephemeral => {
+            anyhow::bail!("trust can't be used with --no-persist or --ephemeral, which never save trusted peers")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether state files may be written: never with `--no-persist`, otherwise
+/// only if the state directory turns out to be writable.
+fn state_writable(paths: &StatePaths, no_persist: bool) -> bool {
+    if no_persist {
+        info!("--no-persist given, keeping instance id, trusted peers and last synced state in memory");
+        return false;
+    }
+    let writable = trust::is_writable(&paths.dir);
+    if !writable {
+        warn!(dir = %paths.dir.display(), "config directory is not writable, keeping instance id and trusted peers in memory");
+    }
+    writable
+}
+
+/// Loads the instance id, saving a newly made up one if `writable`. With
+/// `--no-persist` a fresh id is used on every run.
+fn load_instance(
+    paths: &StatePaths,
+    writable: bool,
+    no_persist: bool,
+) -> Result<Instance, trust::TrustError> {
+    if no_persist {
+        Ok(Instance::generate(paths.instance.clone()))
+    } else if writable {
+        Instance::load_or_create_from(paths.instance.clone())
+    } else {
+        Instance::load_or_generate_from(paths.instance.clone())
+    }
+}
+
+/// Where to listen for control commands. With `--no-persist` the socket is
+/// only created in the runtime directory, never under the config directory.
+#[cfg(unix)]
+fn control_socket(paths: &StatePaths, no_persist: bool) -> Option<PathBuf> {
+    if no_persist && dirs::runtime_dir().is_none() {
+        warn!("no runtime directory for the control socket with --no-persist, ctl commands are unavailable");
+        return None;
+    }
+    Some(paths.control_socket().unwrap_or_else(control::socket_path))
+}
+
+/// Applies `--max-trusted-peers`, forgetting the least recently seen peers
+/// of a store that already holds more.
+fn limit_trust(trust: TrustStore, args: &Args) -> Result<TrustStore, trust::TrustError> {
+    let Some(max) = args.max_trusted_peers else {
+        return Ok(trust);
+    };
+    let mut trust = trust.with_limit(max, args.strict_trust_limit);
+    let evicted = trust.trim();
+    for (id, name) in &evicted {
+        info!(%id, %name, "more trusted peers than --max-trusted-peers, forgetting least recently seen");
+    }
+    if !evicted.is_empty() {
+        trust.save()?;
+    }
+    Ok(trust)
+}
+
+/// Adds a peer to the trust store and saves it, for provisioning devices
+/// ahead of their first connection. A peer that is already trusted is left
+/// as it is.
+fn trust_peer(trust: &mut TrustStore, id: Uuid, name: Option<String>) -> anyhow::Result<String> {
+    if trust.is_trusted(&id) {
+        return Ok(format!("{id} is already trusted"));
+    }
+    let name = name.unwrap_or_else(|| id.to_string());
+    let message = match trust.trust(id, name) {
+        Trusted::Added => format!("trusted {id}"),
+        Trusted::Evicted(evicted_id, evicted_name) => {
+            format!("trusted {id}, forgetting least recently seen {evicted_name} ({evicted_id})")
+        }
+        Trusted::Refused => anyhow::bail!("trust store is full, not trusting {id}"),
+    };
+    trust.save()?;
+    Ok(message)
+}
+
+/// Saves the trust store, retrying failures that may be transient (e.g. a
+/// full disk being cleaned up) before giving up loudly.
+async fn save_trust(trust: &TrustStore) {
+    let mut delay = SAVE_RETRY_DELAY;
+    for attempt in 1..=SAVE_ATTEMPTS {
+        match trust.save() {
+            Ok(()) => return,
+            Err(e) if attempt < SAVE_ATTEMPTS => {
+                warn!(error = %e, attempt, retry_in = ?delay, "failed to save trusted peers");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => {
+                error!(
+                    error = %e,
+                    "could not save trusted peers; peers trusted since startup will be re-trusted as new after a restart"
+                );
+            }
+        }
+    }
+}
+
+/// Lists every effective setting (PSK redacted) together with where its
+/// value came from: the command line, an environment variable or the default.
+fn config_sources(args: &Args, matches: &ArgMatches) -> anyhow::Result<String> {
+    let toml::Value::Table(settings) = toml::Value::try_from(args)? else {
+        anyhow::bail!("settings did not serialize to a table");
+    };
+    let command = Args::command();
+    let mut lines = Vec::new();
+    for (key, value) in settings {
+        let source = match matches.value_source(&key) {
+            Some(ValueSource::CommandLine) => "command line".to_string(),
+            Some(ValueSource::EnvVariable) => command
+                .get_arguments()
+                .find(|arg| arg.get_id() == key.as_str())
+                .and_then(|arg| arg.get_env())
+                .map_or("environment".to_string(), |env| {
+                    format!("env {}", env.to_string_lossy())
+                }),
+            _ => "default".to_string(),
+        };
+        lines.push(format!("{key} = {value}  ({source})"));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Rules for which clipboard content is synced in each direction and how.
+#[derive(Clone)]
+struct SyncOptions {
+    poll_interval: Duration,
+    active_hours: Option<ActiveHours>,
+    active_hours_inbound: bool,
+    limits: ContentLimits,
+    outbound_filter: Option<FilterCommand>,
+    inbound_filter: Option<FilterCommand>,
+    apply_delay: Duration,
+    min_apply_interval: Duration,
+    reconnect: ReconnectPolicy,
+    sync_primary: bool,
+    empty_content: EmptyContent,
+    /// Pauses syncing while the screen is locked.
+    screen_lock: Option<LockPause>,
+}
+
+impl SyncOptions {
+    fn from_args(args: &Args) -> Self {
+        let filter = args.filter_command.clone().map(|command| {
+            FilterCommand::new(command, Duration::from_millis(args.filter_timeout_ms))
+        });
+        Self {
+            poll_interval: Duration::from_millis(args.poll_ms),
+            active_hours: args.active_hours,
+            active_hours_inbound: args.active_hours_inbound,
+            limits: ContentLimits {
+                max_lines: args.max_lines,
+                max_chars: args.max_chars,
+                min_chars: args.min_chars,
+                allow_patterns: args
+                    .allow_patterns
+                    .iter()
+                    .map(|pattern| Regex::new(pattern).expect("validated when parsing"))
+                    .collect(),
+            },
+            outbound_filter: filter.clone().filter(|_| args.filter_direction.outbound()),
+            inbound_filter: filter.filter(|_| args.filter_direction.inbound()),
+            apply_delay: Duration::from_millis(args.apply_delay_ms),
+            min_apply_interval: Duration::from_millis(args.min_apply_interval_ms),
+            reconnect: ReconnectPolicy::new(args.reconnect_attempts).with_schedule(
+                args.reconnect_schedule_ms
+                    .iter()
+                    .map(|&ms| Duration::from_millis(ms))
+                    .collect(),
+            ),
+            sync_primary: args.sync_primary,
+            empty_content: args.empty_content,
+            screen_lock: args
+                .pause_when_locked
+                .map(|direction| LockPause::watch(SystemLockState, direction, LOCK_CHECK_INTERVAL)),
+        }
+    }
+
+    fn selections(&self) -> &'static [Selection] {
+        if self.sync_primary {
+            &[Selection::Clipboard, Selection::Primary]
+        } else {
+            &[Selection::Clipboard]
+        }
+    }
+
+    fn outbound_paused_by_lock(&self) -> bool {
+        self.screen_lock
+            .as_ref()
+            .is_some_and(LockPause::pauses_outbound)
+    }
+
+    fn inbound_paused_by_lock(&self) -> bool {
+        self.screen_lock
+            .as_ref()
+            .is_some_and(LockPause::pauses_inbound)
+    }
+
+    fn outside_active_hours(&self) -> bool {
+        self.active_hours
+            .is_some_and(|hours| !hours.is_active_now())
+    }
+}
+
+/// Accepts incoming connections and starts a peer for each one that
+/// completes the handshake.
+async fn accept_peers(node: Node, mut listener: tokio::net::TcpListener) {
+    loop {
+        let (stream, addr) = listener::accept_next(&mut listener).await;
+        if !cidr::allows(&node.allowed_cidrs, addr.ip()) {
+            info!(%addr, "dropping connection from address outside allowed ranges");
+            continue;
+        }
+        info!(%addr, "incoming connection");
+        let conn = PeerConnection::from_stream(stream)
+            .with_keepalive(node.keepalive)
+            .with_compression(node.compression)
+            .with_group(node.group.as_deref())
+            .with_mime_targets(&node.mime_targets)
+            .with_hash_check(node.latest.is_some())
+            .with_primary(node.sync_primary);
+        // A peer that stalls mid-handshake must not hold up the next one.
+        tokio::spawn(accept_peer(node.clone(), conn, addr));
+    }
+}
+
+async fn accept_peer(node: Node, mut conn: PeerConnection, addr: SocketAddr) {
+    let handshake = conn.handshake_inbound(node.id, &node.name, &node.psk).await;
+    match handshake {
+        Ok((peer_id, peer_name)) => {
+            node.start_peer(conn, peer_id, peer_name, None).await;
+        }
+        Err(ProtocolError::AuthFailed) => node.log_auth_failure(addr, &conn),
+        Err(e) => {
+            warn!(%addr, error = %e, "handshake failed");
+        }
+    }
+}
+
+/// Polls the local clipboard while peers are connected and broadcasts
+/// changes that pass the outbound rules.
+async fn poll_clipboard<P: ClipboardProvider>(
+    node: Node,
+    clipboard: Arc<Mutex<SyncedClipboard<P>>>,
+    options: SyncOptions,
+    last_active: Arc<AtomicU64>,
+) {
+    let mut interval = tokio::time::interval(options.poll_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        // Changes must still be picked up while alone to be queued.
+        if node.offline_queue.is_none() {
+            wait_for_peers(&node.peers, &node.peer_connected).await;
+        }
+        let forced = tokio::select! {
+            _ = interval.tick() => false,
+            _ = node.sync_now.notified() => true,
+        };
+
+        for &selection in options.selections() {
+            let mut synced = clipboard.lock().await;
+            let content = if forced {
+                synced.current(selection)
+            } else {
+                synced.poll_change(selection)
+            };
+            drop(synced);
+            let content = match content {
+                Some(content) => content,
+                None => continue,
+            };
+
+            if options.outside_active_hours() {
+                debug!("outside active hours, not broadcasting");
+                continue;
+            }
+            if options.outbound_paused_by_lock() {
+                debug!("screen locked, not broadcasting");
+                continue;
+            }
+
+            let content = match &options.outbound_filter {
+                Some(filter) => filter.apply(&content).await,
+                None => content,
+            };
+
+            if let Err(e) = options.limits.check(&content) {
+                info!(reason = %e, ?selection, "not broadcasting clipboard");
+                continue;
+            }
+
+            let timestamp = unix_millis();
+            node.broadcast(&content, timestamp, selection).await;
+            node.record_latest(selection, &content, timestamp).await;
+            last_active.store(timestamp, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Sets up syncing of the `--sync-mime` targets, if any were given and the
+/// platform has a backend for them.
+fn mime_passthrough(targets: &[String]) -> Option<MimePassthrough<mime::PlatformMime>> {
+    if targets.is_empty() {
+        return None;
+    }
+    match mime::PlatformMime::new() {
+        Some(backend) => Some(MimePassthrough::new(backend, targets.to_vec())),
+        None => {
+            warn!("--sync-mime needs a Wayland session on Linux, only syncing text");
+            None
+        }
+    }
+}
+
+/// Polls the `--sync-mime` targets while peers are connected and applies
+/// data received from peers, holding back the latest while the clipboard is
+/// pinned. Backend calls block on other clients, so they run on the
+/// blocking pool.
+async fn sync_mime<B: MimeBackend + Send + 'static>(
+    node: Node,
+    passthrough: MimePassthrough<B>,
+    mut apply_rx: mpsc::Receiver<(String, Vec<u8>)>,
+    poll_interval: Duration,
+) {
+    let passthrough = Arc::new(std::sync::Mutex::new(passthrough));
+    let mut interval = tokio::time::interval(poll_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut pinned_until = node.pinned_until.subscribe();
+    let mut held = None;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if node.peers.lock().await.is_empty() {
+                    continue;
+                }
+                let passthrough = passthrough.clone();
+                let change = tokio::task::spawn_blocking(move || {
+                    passthrough
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .poll_change()
+                })
+                .await;
+                if let Ok(Some((target, data))) = change {
+                    info!(%target, len = data.len(), "broadcasting clipboard data");
+                    node.broadcast_data(&target, &data, unix_millis()).await;
+                }
+            }
+            Some((target, data)) = apply_rx.recv() => {
+                if pinned(&node.pinned_until.borrow()) {
+                    debug!(%target, "clipboard pinned, holding clipboard data");
+                    held = Some((target, data));
+                    continue;
+                }
+                apply_data(&passthrough, target, data).await;
+            }
+            _ = pin_ended(&mut pinned_until), if held.is_some() => {
+                if let Some((target, data)) = held.take() {
+                    apply_data(&passthrough, target, data).await;
+                }
+            }
+        }
+    }
+}
+
+async fn apply_data<B: MimeBackend + Send + 'static>(
+    passthrough: &Arc<std::sync::Mutex<MimePassthrough<B>>>,
+    target: String,
+    data: Vec<u8>,
+) {
+    let passthrough = passthrough.clone();
+    let applied = tokio::task::spawn_blocking(move || {
+        passthrough
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .apply_remote(&target, &data)
+            .map_err(|e| format!("{target}: {e}"))
+    })
+    .await;
+    if let Ok(Err(e)) = applied {
+        warn!(error = %e, "failed to apply clipboard data");
+    }
+}
+
+fn pinned(until: &Option<Instant>) -> bool {
+    until.is_some_and(|until| until > Instant::now())
+}
+
+/// Returns once the clipboard is no longer pinned.
+async fn pin_ended(pinned_until: &mut watch::Receiver<Option<Instant>>) {
+    loop {
+        let until = match *pinned_until.borrow_and_update() {
+            Some(until) if until > Instant::now() => until,
+            _ => return,
+        };
+        tokio::select! {
+            _ = tokio::time::sleep_until(until.into()) => {}
+            changed = pinned_until.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Reacts to peer events: applies received clipboard content that passes the
+/// inbound rules and reconnects to outbound peers that dropped.
+async fn handle_events<P: ClipboardProvider + Send + 'static>(
+    node: Node,
+    mut events_rx: mpsc::Receiver<PeerEvent>,
+    clipboard: Arc<Mutex<SyncedClipboard<P>>>,
+    options: SyncOptions,
+) {
+    let (apply_tx, apply_rx) = inbound::channel(node.inbound_dropped.clone());
+    tokio::spawn(clipboard::apply_coalesced(
+        clipboard,
+        apply_rx,
+        options.apply_delay,
+        options.min_apply_interval,
+        node.pinned_until.subscribe(),
+    ));
+
+    while let Some(event) = events_rx.recv().await {
+        match event {
+            PeerEvent::Connected { id, name } => {
+                let name = node.display_name(&id).await.unwrap_or(name);
+                let label = node.peer_label(&id).await;
+                info!(%id, %name, label, "peer connected");
+                node.reconnecting.lock().await.remove(&id);
+            }
+            PeerEvent::Clipboard {
+                id,
+                content,
+                timestamp,
+                selection,
+            } => {
+                let name = node.peer_name(&id).await.unwrap_or_default();
+                let display_name = node.display_name(&id).await.unwrap_or_default();
+                let label = node.peer_label(&id).await;
+                info!(peer = %id, name = %display_name, label, len = content.len(), %timestamp, ?selection, "received clipboard");
+                if !options.selections().contains(&selection) {
+                    debug!(peer = %id, ?selection, "selection not synced, ignoring clipboard");
+                    continue;
+                }
+                if node.is_muted(&id).await {
+                    debug!(peer = %id, "peer muted, ignoring clipboard");
+                    continue;
+                }
+                let group = node.peer_group(&id).await;
+                if !node.directions.receives_from(&id, &name, group.as_deref()) {
+                    debug!(peer = %id, "peer is send-only, ignoring clipboard");
+                    continue;
+                }
+                if options.active_hours_inbound && options.outside_active_hours() {
+                    debug!("outside active hours, ignoring clipboard");
+                    continue;
+                }
+                if options.inbound_paused_by_lock() {
+                    debug!("screen locked, ignoring clipboard");
+                    continue;
+                }
+                if content.is_empty() {
+                    match options.empty_content {
+                        EmptyContent::Ignore => {
+                            debug!(peer = %id, ?selection, "ignoring empty clipboard");
+                            continue;
+                        }
+                        EmptyContent::Clear => {
+                            info!(peer = %id, ?selection, "peer cleared clipboard");
+                        }
+                    }
+                }
+                let content = match &options.inbound_filter {
+                    Some(filter) => filter.apply(&content).await,
+                    None => content,
+                };
+                // Only what we apply ourselves is advertised and handed out
+                // to peers asking for it.
+                node.record_latest(selection, &content, timestamp).await;
+                apply_tx.send((selection, content));
+            }
+            PeerEvent::Data {
+                id,
+                target,
+                data,
+                timestamp,
+            } => {
+                let name = node.peer_name(&id).await.unwrap_or_default();
+                let display_name = node.display_name(&id).await.unwrap_or_default();
+                let label = node.peer_label(&id).await;
+                info!(peer = %id, name = %display_name, label, %target, len = data.len(), %timestamp, "received clipboard data");
+                let Some(mime_apply) = &node.mime_apply else {
+                    debug!(peer = %id, %target, "not syncing MIME targets, ignoring data");
+                    continue;
+                };
+                let group = node.peer_group(&id).await;
+                if node.is_muted(&id).await
+                    || !node.directions.receives_from(&id, &name, group.as_deref())
+                {
+                    debug!(peer = %id, "not accepting content from peer, ignoring data");
+                    continue;
+                }
+                if options.active_hours_inbound && options.outside_active_hours() {
+                    debug!("outside active hours, ignoring clipboard data");
+                    continue;
+                }
+                if options.inbound_paused_by_lock() {
+                    debug!("screen locked, ignoring clipboard data");
+                    continue;
+                }
+                let _ = mime_apply.send((target, data)).await;
+            }
+            PeerEvent::ClipboardHash {
+                id,
+                hash,
+                timestamp,
+                selection,
+            } => {
+                if !options.selections().contains(&selection) || !node.accepts_from(&id).await {
+                    continue;
+                }
+                if node.behind(selection, &hash, timestamp).await {
+                    info!(peer = %id, ?selection, "clipboard out of sync with peer, requesting its content");
+                    node.send_message(&id, protocol::Message::ClipboardRequest { selection })
+                        .await;
+                }
+            }
+            PeerEvent::ClipboardRequest { id, selection } => {
+                let Some(latest) = &node.latest else { continue };
+                let latest = latest.lock().await.get(&selection).cloned();
+                if let Some((content, timestamp)) = latest {
+                    debug!(peer = %id, ?selection, "sending requested clipboard");
+                    node.broadcast_to(&[id], &content, timestamp, selection)
+                        .await;
+                }
+            }
+            PeerEvent::Disconnected { id } => {
+                info!(%id, "peer disconnected");
+                let Some(handle) = node.peers.lock().await.remove(&id) else {
+                    continue;
+                };
+                // The task normally ends right after sending this event, but
+                // one still blocked on a send would otherwise outlive its
+                // handle along with the connection it owns.
+                handle.task.abort();
+                node.disconnected.lock().await.insert(
+                    id,
+                    DisconnectedPeer {
+                        name: handle.display_name.clone(),
+                        label: handle.label.clone(),
+                        outbound: handle.addr.is_some(),
+                        last_seen: Instant::now(),
+                    },
+                );
+                if let Some(addr) = handle.addr {
+                    node.reconnecting.lock().await.insert(
+                        id,
+                        ReconnectingPeer {
+                            name: handle.name,
+                            attempts: 0,
+                        },
+                    );
+                    let node = node.clone();
+                    let policy = options.reconnect.clone();
+                    tokio::spawn(async move { node.reconnect(id, addr, policy).await });
+                }
+            }
+            PeerEvent::Reconnecting { id, attempts } => {
+                if let Some(peer) = node.reconnecting.lock().await.get_mut(&id) {
+                    peer.attempts = attempts;
+                }
+            }
+            PeerEvent::ReconnectFailed { id, attempts } => {
+                warn!(%id, attempts, "giving up reconnecting to peer");
+                node.reconnecting.lock().await.remove(&id);
+            }
+            PeerEvent::Health { id, health } => {
+                if let Some(peer) = node.peers.lock().await.get_mut(&id) {
+                    peer.health = health;
+                }
+            }
+        }
+    }
+}
+
+/// Logs a hint after every `interval` in which no peer was connected, since
+/// a firewall blocking mDNS otherwise just leaves the daemon sitting idle.
+#[cfg(feature = "discovery")]
+async fn hint_no_peers(node: Node, interval: Duration, port: u16) {
+    loop {
+        if no_peers_for(&node, interval).await {
+            warn!(
+                "no peers connected in the last {}s; check that mDNS (UDP 5353) and TCP port \
+                 {port} aren't blocked by a firewall",
+                interval.as_secs()
+            );
+        }
+    }
+}
+
+/// Waits `interval` and returns whether no peer was connected at any point
+/// during it.
+#[cfg(feature = "discovery")]
+async fn no_peers_for(node: &Node, interval: Duration) -> bool {
+    let deadline = tokio::time::sleep(interval);
+    let connected = node.peer_connected.notified();
+    tokio::pin!(deadline, connected);
+    connected.as_mut().enable();
+    if !node.peers.lock().await.is_empty() {
+        deadline.await;
+        return false;
+    }
+    tokio::select! {
+        _ = &mut deadline => node.peers.lock().await.is_empty(),
+        _ = connected => {
+            deadline.await;
+            false
+        }
+    }
+}
+
+/// Re-announces our mDNS record whenever the minute of our last broadcast
+/// changes, checked every [`ACTIVITY_REFRESH`].
+#[cfg(feature = "discovery")]
+async fn advertise_activity(discovery: Discovery, last_active: Arc<AtomicU64>) {
+    let mut interval = tokio::time::interval(ACTIVITY_REFRESH);
+    let mut advertised = 0;
+    loop {
+        interval.tick().await;
+        let secs = last_active.load(Ordering::Relaxed) / 1000;
+        if secs / 60 == advertised / 60 {
+            continue;
+        }
+        advertised = secs;
+        if let Err(e) = discovery.advertise_last_active(secs) {
+            warn!(error = %e, "failed to advertise last activity");
+        }
+    }
+}
+
+/// Sends every peer taking part the hash of the content last synced through
+/// each selection once per `interval`, so one that missed an update notices
+/// and asks for it.
+async fn check_hashes(node: Node, interval: Duration) {
+    let Some(latest) = node.latest.clone() else {
+        return;
+    };
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let hashes: Vec<(Selection, protocol::Message)> = latest
+            .lock()
+            .await
+            .iter()
+            .map(|(&selection, (content, timestamp))| {
+                let msg = protocol::Message::ClipboardHash {
+                    hash: content_hash(content),
+                    timestamp: *timestamp,
+                    selection,
+                };
+                (selection, msg)
+            })
+            .collect();
+        let peers = node.peers.lock().await;
+        for (id, peer) in peers.iter().filter(|(_, peer)| peer.hash_check) {
+            for (selection, msg) in &hashes {
+                if *selection == Selection::Primary && !peer.primary {
+                    continue;
+                }
+                if peer.data_tx.try_send(msg.clone()).is_err() {
+                    debug!(peer = %id, "peer busy, skipping clipboard hash check");
+                }
+            }
+        }
+    }
+}
+
+/// Returns once at least one peer is connected, so the clipboard isn't polled
+/// while there is nobody to sync with.
+async fn wait_for_peers(peers: &PeerMap, peer_connected: &Notify) {
+    loop {
+        let connected = peer_connected.notified();
+        if !peers.lock().await.is_empty() {
+            return;
+        }
+        debug!("no peers connected, pausing clipboard polling");
+        connected.await;
+    }
+}
+
+/// Connects to the peers listed in `path`, re-reading it on SIGHUP and
+/// connecting or disconnecting peers that were added or removed.
+async fn watch_peers_file(node: Node, path: PathBuf) {
+    #[cfg(unix)]
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!(error = %e, "failed to install SIGHUP handler, peers file will not reload");
+            return;
+        }
+    };
+
+    let mut connected: HashMap<SocketAddr, Uuid> = HashMap::new();
+    loop {
+        match peers_file::load(&path) {
+            Ok(addrs) => {
+                let current: Vec<SocketAddr> = connected.keys().copied().collect();
+                let diff = peers_file::diff(&current, &addrs);
+
+                for addr in diff.removed {
+                    if let Some(id) = connected.remove(&addr) {
+                        info!(%addr, %id, "peer removed from peers file");
+                        node.disconnect(&id).await;
+                    }
+                }
+
+                for addr in diff.added {
+                    match node.connect(addr).await {
+                        Ok(id) => {
+                            connected.insert(addr, id);
+                        }
+                        Err(e) => {
+                            warn!(%addr, error = %e, "failed to connect");
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "failed to load peers file");
+            }
+        }
+
+        #[cfg(unix)]
+        {
+            if hangup.recv().await.is_none() {
+                break;
+            }
+            info!(path = %path.display(), "reloading peers file");
+        }
+        #[cfg(not(unix))]
+        break;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clipboard::{InMemoryClipboardProvider, LazyClipboard};
+    use std::sync::{PoisonError, RwLock};
+
+    /// Clap reads `CURSEDBOARD_*` variables while parsing, so tests setting
+    /// one hold this exclusively and all other parsing shares it.
+    static ENV: RwLock<()> = RwLock::new(());
+
+    fn try_parse_args<T: Into<std::ffi::OsString> + Clone>(
+        argv: impl IntoIterator<Item = T>,
+    ) -> Result<Args, clap::Error> {
+        let _env = ENV.read().unwrap_or_else(PoisonError::into_inner);
+        Args::try_parse_from(argv)
+    }
+
+    fn parse_args<T: Into<std::ffi::OsString> + Clone>(argv: impl IntoIterator<Item = T>) -> Args {
+        try_parse_args(argv).unwrap()
+    }
+
+    /// Runs `f` with the environment variable `name` set to `value`.
+    fn with_env<T>(name: &str, value: &str, f: impl FnOnce() -> T) -> T {
+        let _env = ENV.write().unwrap_or_else(PoisonError::into_inner);
+        std::env::set_var(name, value);
+        let result = f();
+        std::env::remove_var(name);
+        result
+    }
+
+    /// Log output written by a subscriber from [`Captured::subscriber`].
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Captured {
+        fn subscriber() -> (Self, impl tracing::Subscriber + Send + Sync) {
+            let captured = Self::default();
+            let subscriber = tracing_subscriber::fmt()
+                .with_writer({
+                    let captured = captured.clone();
+                    move || captured.clone()
+                })
+                .with_ansi(false)
+                .finish();
+            (captured, subscriber)
+        }
+
+        fn output(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_node() -> (Node, mpsc::Receiver<PeerEvent>) {
+        let (events_tx, events_rx) = mpsc::channel(16);
+        let node = Node {
+            id: Uuid::new_v4(),
+            name: "test".into(),
+            group: None,
+            psk: "psk".into(),
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            reconnecting: Arc::new(Mutex::new(HashMap::new())),
+            disconnected: Arc::new(Mutex::new(RecentlyDisconnected::default())),
+            trust: Arc::new(Mutex::new(TrustStore::default().ephemeral())),
+            auto_trust: true,
+            auth_failure_details: true,
+            events_tx,
+            peer_connected: Arc::new(Notify::new()),
+            sync_now: Arc::new(Notify::new()),
+            muted: Arc::new(Mutex::new(HashSet::new())),
+            directions: Arc::new(PeerDirections::default()),
+            duplicate_names: DuplicateNames::Suffix,
+            inbound_interval: None,
+            keepalive: None,
+            ping_interval: None,
+            allowed_cidrs: Arc::new([]),
+            compression: Compression::SUPPORTED,
+            mime_targets: Arc::new([]),
+            sync_primary: false,
+            mime_apply: None,
+            inbound_dropped: Arc::new(AtomicU64::new(0)),
+            pinned_until: Arc::new(watch::Sender::new(None)),
+            config_sources: "".into(),
+            offline_queue: None,
+            latest: None,
+        };
+        (node, events_rx)
+    }
+
+    /// Sync options with every rule off, polling every 10ms.
+    fn test_options() -> SyncOptions {
+        SyncOptions {
+            poll_interval: Duration::from_millis(10),
+            active_hours: None,
+            active_hours_inbound: false,
+            limits: ContentLimits::default(),
+            outbound_filter: None,
+            inbound_filter: None,
+            apply_delay: Duration::ZERO,
+            min_apply_interval: Duration::ZERO,
+            reconnect: ReconnectPolicy::new(0),
+            sync_primary: false,
+            empty_content: EmptyContent::Ignore,
+            screen_lock: None,
+        }
+    }
+
+    /// Handle for a connected inbound peer called `name` whose task never
+    /// finishes.
+    pub(crate) fn test_handle(name: &str, tx: ClipboardTx) -> PeerHandle {
+        let task = tokio::spawn(std::future::pending::<()>());
+        PeerHandle {
+            tx,
+            data_tx: mpsc::channel(1).0,
+            mime_targets: Vec::new(),
+            task: task.abort_handle(),
+            name: name.into(),
+            display_name: name.into(),
+            label: None,
+            group: None,
+            addr: None,
+            health: PeerHealth::default(),
+            hash_check: false,
+            primary: false,
+        }
+    }
+
+    async fn add_test_peer(
+        node: &Node,
+        name: &str,
+    ) -> (Uuid, mpsc::Receiver<(String, u64, Selection)>) {
+        let id = Uuid::new_v4();
+        let (tx, rx) = mpsc::channel(16);
+        node.peers.lock().await.insert(id, test_handle(name, tx));
+        (id, rx)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_muted_peer_skipped_both_ways() {
+        let (node, _events) = test_node();
+        let (laptop, mut laptop_rx) = add_test_peer(&node, "laptop").await;
+        let (_, mut desktop_rx) = add_test_peer(&node, "desktop").await;
+
+        let response = node
+            .handle_control(control::Command::Mute("laptop".into()))
+            .await;
+        assert_eq!(response, format!("muted {laptop}"));
+        assert!(node.is_muted(&laptop).await);
+
+        node.broadcast("secret", 1, Selection::Clipboard).await;
+        assert_eq!(desktop_rx.try_recv().unwrap().0, "secret");
+        assert!(laptop_rx.try_recv().is_err());
+
+        node.handle_control(control::Command::Unmute(laptop.to_string()))
+            .await;
+        assert!(!node.is_muted(&laptop).await);
+
+        node.broadcast("shared", 2, Selection::Clipboard).await;
+        assert_eq!(desktop_rx.try_recv().unwrap().0, "shared");
+        assert_eq!(laptop_rx.try_recv().unwrap().0, "shared");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_send_command_reaches_peers() {
+        let (node, _events) = test_node();
+        let (_, mut laptop_rx) = add_test_peer(&node, "laptop").await;
+
+        let response = node
+            .handle_control(control::Command::Send("injected".into()))
+            .await;
+        assert_eq!(response, "sent 8 chars: injected");
+        let (content, _, selection) = laptop_rx.try_recv().unwrap();
+        assert_eq!(content, "injected");
+        assert_eq!(selection, Selection::Clipboard);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sync_now_resends_unchanged_clipboard() {
+        let (node, _events) = test_node();
+        let (_, mut laptop_rx) = add_test_peer(&node, "laptop").await;
+        let mut local = InMemoryClipboardProvider::default();
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(local.clone()),
+        )));
+        let options = test_options();
+        tokio::spawn(poll_clipboard(
+            node.clone(),
+            clipboard,
+            options,
+            Arc::new(AtomicU64::new(0)),
+        ));
+
+        local.set_text("pasted badly").unwrap();
+        let (content, _, _) = tokio::time::timeout(Duration::from_secs(1), laptop_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(content, "pasted badly");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(laptop_rx.try_recv().is_err());
+
+        let response = node.handle_control(control::Command::SyncNow).await;
+        assert_eq!(response, "syncing current clipboard");
+        let (content, _, _) = tokio::time::timeout(Duration::from_secs(1), laptop_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(content, "pasted badly");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(laptop_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_targeted_send_reaches_only_target() {
+        let (node, _events) = test_node();
+        let (phone, mut phone_rx) = add_test_peer(&node, "phone").await;
+        let (_, mut laptop_rx) = add_test_peer(&node, "laptop").await;
+
+        node.broadcast_to(
+            &[phone, Uuid::new_v4()],
+            "just for you",
+            1,
+            Selection::Clipboard,
+        )
+        .await;
+        assert_eq!(phone_rx.try_recv().unwrap().0, "just for you");
+        assert!(laptop_rx.try_recv().is_err());
+    }
+
+    type Offers = Vec<(String, Vec<u8>)>;
+
+    /// MIME backend recording what is set on it.
+    #[derive(Clone, Default)]
+    struct RecordingMime(Arc<std::sync::Mutex<Offers>>);
+
+    impl MimeBackend for RecordingMime {
+        type Error = String;
+
+        fn targets(&mut self) -> Result<Vec<String>, Self::Error> {
+            Ok(Vec::new())
+        }
+
+        fn get(&mut self, target: &str) -> Result<Vec<u8>, Self::Error> {
+            Err(format!("{target} not offered"))
+        }
+
+        fn set(&mut self, target: &str, data: &[u8]) -> Result<(), Self::Error> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((target.to_string(), data.to_vec()));
+            Ok(())
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_pin_holds_clipboard_data_until_unpinned() {
+        let (node, _events) = test_node();
+        let backend = RecordingMime::default();
+        let (apply_tx, apply_rx) = mpsc::channel(4);
+        tokio::spawn(sync_mime(
+            node.clone(),
+            MimePassthrough::new(backend.clone(), vec!["image/png".into()]),
+            apply_rx,
+            Duration::from_millis(10),
+        ));
+
+        let response = node
+            .handle_control(control::Command::Pin(Duration::from_secs(u64::MAX)))
+            .await;
+        assert!(response.starts_with("error: "), "{response}");
+        assert_eq!(*node.pinned_until.borrow(), None);
+
+        node.handle_control(control::Command::Pin(Duration::from_secs(60)))
+            .await;
+        for data in [b"first", b"later"] {
+            apply_tx
+                .send(("image/png".into(), data.to_vec()))
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(backend.0.lock().unwrap().is_empty());
+
+        node.handle_control(control::Command::Unpin).await;
+        for _ in 0..100 {
+            if !backend.0.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            *backend.0.lock().unwrap(),
+            [("image/png".to_string(), b"later".to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_data_sent_only_to_peers_accepting_target() {
+        let (node, _events) = test_node();
+        let mut data_rxs = Vec::new();
+        for (name, targets) in [("new", vec!["image/png".to_string()]), ("old", vec![])] {
+            let (data_tx, data_rx) = mpsc::channel(1);
+            node.peers.lock().await.insert(
+                Uuid::new_v4(),
+                PeerHandle {
+                    data_tx,
+                    mime_targets: targets,
+                    ..test_handle(name, mpsc::channel(1).0)
+                },
+            );
+            data_rxs.push(data_rx);
+        }
+
+        node.broadcast_data("image/png", b"\x89PNG", 1).await;
+        match data_rxs[0].try_recv().unwrap() {
+            protocol::Message::Data { target, data, .. } => {
+                assert_eq!(target, "image/png");
+                assert_eq!(protocol::decode_data(&data).unwrap(), b"\x89PNG");
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+        assert!(data_rxs[1].try_recv().is_err());
+
+        node.broadcast_data("text/uri-list", b"file:///a", 2).await;
+        assert!(data_rxs[0].try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_second_connection_for_same_id_closed() {
+        let (node, _events) = test_node();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn({
+            let node = node.clone();
+            async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let mut conn = PeerConnection::from_stream(stream);
+                    let (id, name) = conn
+                        .handshake_inbound(node.id, &node.name, &node.psk)
+                        .await
+                        .unwrap();
+                    node.start_peer(conn, id, name, None).await;
+                }
+            }
+        });
+
+        let peer_id = Uuid::new_v4();
+        let mut first = PeerConnection::connect(addr).await.unwrap();
+        first
+            .handshake_outbound(peer_id, "laptop", "psk")
+            .await
+            .unwrap();
+        let mut second = PeerConnection::connect(addr).await.unwrap();
+        second
+            .handshake_outbound(peer_id, "laptop", "psk")
+            .await
+            .unwrap();
+
+        assert!(matches!(second.recv().await, Err(ProtocolError::Closed)));
+        assert_eq!(node.peers.lock().await.len(), 1);
+        let ping = protocol::Message::Ping {
+            seq: Some(1),
+            sent_ms: Some(1_000),
+        };
+        first.send(&ping).await.unwrap();
+        assert_eq!(
+            first.recv().await.unwrap(),
+            protocol::Message::Pong {
+                seq: Some(1),
+                sent_ms: Some(1_000),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peers_with_same_name_get_distinct_display_names() {
+        let (node, _events) = test_node();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn({
+            let node = node.clone();
+            async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let mut conn = PeerConnection::from_stream(stream);
+                    let (id, name) = conn
+                        .handshake_inbound(node.id, &node.name, &node.psk)
+                        .await
+                        .unwrap();
+                    node.start_peer(conn, id, name, None).await;
+                }
+            }
+        });
+
+        let mut conns = Vec::new();
+        for _ in 0..2 {
+            let mut conn = PeerConnection::connect(addr).await.unwrap();
+            conn.handshake_outbound(Uuid::new_v4(), "ubuntu", "psk")
+                .await
+                .unwrap();
+            conns.push(conn);
+        }
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while node.peers.lock().await.len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let peers = node.peers.lock().await;
+        let mut names: Vec<&str> = peers.values().map(|p| p.name.as_str()).collect();
+        names.dedup();
+        assert_eq!(names, ["ubuntu"]);
+        let display_names: HashSet<&str> =
+            peers.values().map(|p| p.display_name.as_str()).collect();
+        assert_eq!(display_names.len(), 2);
+        assert!(display_names.contains("ubuntu"));
+    }
+
+    #[tokio::test]
+    async fn test_offline_change_delivered_on_connect() {
+        let (mut node, _events) = test_node();
+        node.offline_queue = Some(Default::default());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut local = InMemoryClipboardProvider::default();
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(local.clone()),
+        )));
+        let options = test_options();
+        tokio::spawn(accept_peers(node.clone(), listener));
+        tokio::spawn(poll_clipboard(
+            node.clone(),
+            clipboard,
+            options,
+            Arc::new(AtomicU64::new(0)),
+        ));
+
+        local.set_text("first").unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        local.set_text("copied while alone").unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut peer = PeerConnection::connect(addr).await.unwrap();
+        peer.handshake_outbound(Uuid::new_v4(), "laptop", "psk")
+            .await
+            .unwrap();
+        match peer.recv().await.unwrap() {
+            protocol::Message::Clipboard { content, .. } => {
+                assert_eq!(content, "copied while alone")
+            }
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_with_offline_queues_keeps_clipboards_in_sync() {
+        let queued = || {
+            let (mut node, events_rx) = test_node();
+            node.offline_queue = Some(Default::default());
+            (node, events_rx)
+        };
+        let (a, _, mut a_clipboard) = start_node(queued(), false).await;
+        let (b, b_addr, mut b_clipboard) = start_node(queued(), false).await;
+        assert_eq!(a.connect(b_addr).await.unwrap(), b.id);
+
+        a_clipboard.set_text("from a").unwrap();
+        wait_for_text(&mut b_clipboard, "from a").await;
+        b_clipboard.set_text("from b").unwrap();
+        wait_for_text(&mut a_clipboard, "from b").await;
+
+        a.disconnect(&b.id).await;
+        for _ in 0..100 {
+            if b.peers.lock().await.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(a.connect(b_addr).await.unwrap(), b.id);
+
+        // Neither side replays its own earlier change over the other's.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(a_clipboard.get_text().unwrap(), "from b");
+        assert_eq!(b_clipboard.get_text().unwrap(), "from b");
+    }
+
+    #[tokio::test]
+    async fn test_only_content_matching_allow_patterns_broadcast() {
+        let (node, _events) = test_node();
+        let (_, mut peer_rx) = add_test_peer(&node, "laptop").await;
+        let mut local = InMemoryClipboardProvider::default();
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(local.clone()),
+        )));
+        let options = SyncOptions {
+            limits: ContentLimits {
+                allow_patterns: vec![Regex::new("^https?://").unwrap()],
+                ..Default::default()
+            },
+            ..test_options()
+        };
+        tokio::spawn(poll_clipboard(
+            node.clone(),
+            clipboard,
+            options,
+            Arc::new(AtomicU64::new(0)),
+        ));
+
+        local.set_text("hunter2").unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        local.set_text("https://example.com").unwrap();
+        let (content, _, _) = tokio::time::timeout(Duration::from_secs(1), peer_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(content, "https://example.com");
+    }
+
+    #[tokio::test]
+    async fn test_connections_filtered_by_allowed_cidrs() {
+        async fn handshake(allowed: &str) -> Result<(Uuid, String), ProtocolError> {
+            let (mut node, _events) = test_node();
+            node.allowed_cidrs = Arc::new([allowed.parse().unwrap()]);
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(accept_peers(node, listener));
+
+            let mut peer = PeerConnection::connect(addr).await.unwrap();
+            tokio::time::timeout(
+                Duration::from_secs(1),
+                peer.handshake_outbound(Uuid::new_v4(), "laptop", "psk"),
+            )
+            .await
+            .expect("connection should be accepted or dropped without waiting")
+        }
+
+        assert!(handshake("127.0.0.0/8").await.is_ok());
+        assert!(handshake("10.0.0.0/8").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stalled_dialer_does_not_block_others() {
+        let (node, _events) = test_node();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(accept_peers(node, listener));
+
+        // A dialer from before mutual authentication stops after the
+        // listener's answer and never sends the third Auth.
+        let mut old = PeerConnection::connect(addr).await.unwrap();
+        old.send(&protocol::Message::Hello {
+            id: Uuid::new_v4(),
+            name: "old".into(),
+            group: None,
+            compression: Vec::new(),
+            mime_targets: Vec::new(),
+            hash_check: false,
+            primary: false,
+        })
+        .await
+        .unwrap();
+        old.recv().await.unwrap();
+        old.send(&protocol::Message::Auth {
+            challenge: protocol::generate_challenge(),
+            response: [0; 32],
+        })
+        .await
+        .unwrap();
+        old.recv().await.unwrap();
+
+        let mut peer = PeerConnection::connect(addr).await.unwrap();
+        let handshake = tokio::time::timeout(
+            Duration::from_secs(1),
+            peer.handshake_outbound(Uuid::new_v4(), "laptop", "psk"),
+        )
+        .await
+        .expect("second dialer should not wait for the stalled one");
+        assert!(handshake.is_ok());
+        drop(old);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_peer_reported_as_reconnecting() {
+        let (node, events_rx) = test_node();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(1);
+        node.peers.lock().await.insert(
+            id,
+            PeerHandle {
+                addr: Some(addr),
+                ..test_handle("desktop", tx)
+            },
+        );
+        let options = SyncOptions {
+            reconnect: ReconnectPolicy {
+                max_attempts: 3,
+                initial_delay: Duration::from_millis(30),
+                max_delay: Duration::from_millis(30),
+                jitter: 0.0,
+                schedule: Vec::new(),
+            },
+            ..test_options()
+        };
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(InMemoryClipboardProvider::default()),
+        )));
+        tokio::spawn(handle_events(node.clone(), events_rx, clipboard, options));
+        node.events_tx
+            .send(PeerEvent::Disconnected { id })
+            .await
+            .unwrap();
+
+        let mut seen = Vec::new();
+        for _ in 0..200 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            match node.reconnecting.lock().await.get(&id) {
+                Some(peer) => {
+                    assert_eq!(peer.name, "desktop");
+                    if peer.attempts > 0 && seen.last() != Some(&peer.attempts) {
+                        seen.push(peer.attempts);
+                    }
+                }
+                None if !seen.is_empty() => break,
+                None => {}
+            }
+        }
+        assert_eq!(seen, [1, 2, 3]);
+        assert!(!node.reconnecting.lock().await.contains_key(&id));
+    }
+
+    #[tokio::test]
+    async fn test_label_persisted_and_shown_for_peer() {
+        let dir = std::env::temp_dir().join(format!("cursedboard-test-{}", Uuid::new_v4()));
+        let path = dir.join("trusted.toml");
+        let (mut node, _events) = test_node();
+        node.trust = Arc::new(Mutex::new(TrustStore::load_from(path.clone()).unwrap()));
+        let (laptop, _rx) = add_test_peer(&node, "laptop").await;
+
+        let response = node
+            .handle_control(control::Command::Label {
+                peer: "laptop".into(),
+                label: Some("work".into()),
+            })
+            .await;
+        assert_eq!(response, "error: laptop is not trusted");
+
+        node.trust.lock().await.trust(laptop, "laptop".into());
+        let response = node
+            .handle_control(control::Command::Label {
+                peer: "laptop".into(),
+                label: Some("work".into()),
+            })
+            .await;
+        assert_eq!(response, format!("labeled {laptop} work"));
+        assert_eq!(node.peer_label(&laptop).await.as_deref(), Some("work"));
+        let saved = TrustStore::load_from(path.clone()).unwrap();
+        assert_eq!(saved.label(&laptop), Some("work"));
+
+        node.handle_control(control::Command::Label {
+            peer: "laptop".into(),
+            label: None,
+        })
+        .await;
+        assert_eq!(node.peer_label(&laptop).await, None);
+        assert_eq!(TrustStore::load_from(path).unwrap().label(&laptop), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_no_auto_trust_leaves_trust_store_unchanged() {
+        let (mut node, _events) = test_node();
+        node.auto_trust = false;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(accept_peers(node.clone(), listener));
+
+        let peer_id = Uuid::new_v4();
+        let mut peer = PeerConnection::connect(addr).await.unwrap();
+        peer.handshake_outbound(peer_id, "laptop", "psk")
+            .await
+            .unwrap();
+        for _ in 0..100 {
+            if node.peers.lock().await.contains_key(&peer_id) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert!(node.peers.lock().await.contains_key(&peer_id));
+        assert!(!node.trust.lock().await.is_trusted(&peer_id));
+    }
+
+    #[test]
+    fn test_weak_psk_warns_or_refuses() {
+        let (captured, subscriber) = Captured::subscriber();
+        tracing::subscriber::with_default(subscriber, || {
+            check_psk("k3Jd9-qLx2vR7wPz", 16, true).unwrap();
+            assert!(captured.output().is_empty());
+
+            check_psk("cursedboard", 16, false).unwrap();
+            let output = captured.output();
+            assert!(output.contains("weak PSK"), "{output}");
+            assert!(output.contains("fewer than the minimum of 16"), "{output}");
+        });
+
+        let err = check_psk("cursedboard", 16, true).unwrap_err();
+        assert!(err.to_string().contains("fewer than the minimum"));
+    }
+
+    #[tokio::test]
+    async fn test_auth_failure_logs_source_address() {
+        let (captured, subscriber) = Captured::subscriber();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let (mut node, _events) = test_node();
+
+        for details in [true, false] {
+            node.auth_failure_details = details;
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let peer_id = Uuid::new_v4();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut peer = PeerConnection::from_stream(stream);
+                let _ = peer.handshake_inbound(peer_id, "laptop", "other psk").await;
+            });
+
+            let result = node.connect(addr).await;
+            assert!(matches!(result, Err(ProtocolError::AuthFailed)));
+            let output = captured.output();
+            let line = output.lines().last().unwrap();
+            assert!(line.contains("peer failed authentication"), "{line}");
+            for field in [
+                format!("addr={addr}"),
+                format!("claimed_id=Some({peer_id})"),
+                "claimed_name=Some(\"laptop\")".to_string(),
+            ] {
+                assert_eq!(line.contains(&field), details, "{field} in {line}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inbound_auth_failure_logs_claimed_identity() {
+        let (captured, subscriber) = Captured::subscriber();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let (mut node, _events) = test_node();
+        node.auth_failure_details = true;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(accept_peers(node.clone(), listener));
+
+        let peer_id = Uuid::new_v4();
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let source = stream.local_addr().unwrap();
+        let mut peer = PeerConnection::from_stream(stream);
+        let result = peer
+            .handshake_outbound(peer_id, "laptop", "other psk")
+            .await;
+        assert!(matches!(result, Err(ProtocolError::AuthFailed)));
+        drop(peer);
+
+        for _ in 0..100 {
+            if captured.output().contains("peer failed authentication") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let output = captured.output();
+        let line = output
+            .lines()
+            .find(|line| line.contains("peer failed authentication"))
+            .unwrap_or_else(|| panic!("no auth failure logged: {output}"));
+        for field in [
+            format!("addr={source}"),
+            format!("claimed_id=Some({peer_id})"),
+            "claimed_name=Some(\"laptop\")".to_string(),
+        ] {
+            assert!(line.contains(&field), "{field} in {line}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_disconnect_cycles_do_not_leak_tasks() {
+        let (node, addr, _local) = start_daemon(false).await;
+        // Let the daemon spawn its own long-lived tasks first.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let baseline = metrics.num_alive_tasks();
+
+        for _ in 0..20 {
+            let peer_id = Uuid::new_v4();
+            let mut peer = PeerConnection::connect(addr).await.unwrap();
+            peer.handshake_outbound(peer_id, "laptop", "psk")
+                .await
+                .unwrap();
+            for _ in 0..100 {
+                if node.peers.lock().await.contains_key(&peer_id) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            peer.close().await;
+            for _ in 0..100 {
+                if node.peers.lock().await.is_empty() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            assert!(node.peers.lock().await.is_empty());
+        }
+
+        for _ in 0..100 {
+            if metrics.num_alive_tasks() <= baseline {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(metrics.num_alive_tasks(), baseline);
+    }
+
+    #[tokio::test]
+    async fn test_disconnected_aborts_stuck_peer_task() {
+        let (node, events_rx) = test_node();
+        let (id, _rx) = add_test_peer(&node, "laptop").await;
+        let task = node.peers.lock().await[&id].task.clone();
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(InMemoryClipboardProvider::default()),
+        )));
+        let options = test_options();
+        tokio::spawn(handle_events(node.clone(), events_rx, clipboard, options));
+        node.events_tx
+            .send(PeerEvent::Disconnected { id })
+            .await
+            .unwrap();
+
+        for _ in 0..100 {
+            if task.is_finished() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(task.is_finished());
+        assert!(node.peers.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_manual_peers_connect_without_mdns() {
+        #[cfg(feature = "discovery")]
+        {
+            let failed = start_discovery(|| {
+                Err(DiscoveryError::Mdns(mdns_sd::Error::Msg(
+                    "no multicast interface".into(),
+                )))
+            });
+            assert!(failed.is_none());
+        }
+
+        let (remote, addr, _remote_clipboard) = start_daemon(false).await;
+        let (node, _events) = test_node();
+        let dir = std::env::temp_dir().join(format!("cursedboard-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("peers");
+        std::fs::write(&path, format!("{addr}\n")).unwrap();
+        tokio::spawn(watch_peers_file(node.clone(), path));
+
+        for _ in 0..100 {
+            if node.peers.lock().await.contains_key(&remote.id) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(node.peers.lock().await.contains_key(&remote.id));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "discovery")]
+    #[tokio::test]
+    async fn test_discovered_peer_dialed_after_grace() {
+        let (node, _events) = test_node();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let grace = Duration::from_millis(200);
+
+        let started = Instant::now();
+        let dial = tokio::spawn(async move {
+            dial_discovered(node, Uuid::new_v4(), addr, grace).await;
+        });
+        let _ = listener.accept().await.unwrap();
+        assert!(started.elapsed() >= grace);
+        dial.abort();
+    }
+
+    #[cfg(feature = "discovery")]
+    #[tokio::test]
+    async fn test_stuck_discovered_peer_does_not_block_others() {
+        let (remote, addr, _remote_clipboard) = start_daemon(false).await;
+        let (node, _events) = test_node();
+        // Accepts the connection but never answers the handshake.
+        let stuck = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let discovered = |id, addr| {
+            DiscoveryEvent::Resolved(discovery::Peer {
+                id,
+                name: "peer".into(),
+                group: None,
+                addr,
+                last_active: None,
+            })
+        };
+        let (tx, rx) = mpsc::channel(2);
+        tx.send(discovered(Uuid::new_v4(), stuck.local_addr().unwrap()))
+            .await
+            .unwrap();
+        tx.send(discovered(remote.id, addr)).await.unwrap();
+        tokio::spawn(connect_discovered(
+            node.clone(),
+            DiscoveryStream::from_receiver(rx),
+            None,
+            GroupPolicy::Strict,
+            Duration::ZERO,
+        ));
+
+        let _stuck_conn = stuck.accept().await.unwrap();
+        for _ in 0..100 {
+            if node.peers.lock().await.contains_key(&remote.id) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(node.peers.lock().await.contains_key(&remote.id));
+        drop(tx);
+    }
+
+    #[cfg(feature = "discovery")]
+    #[tokio::test]
+    async fn test_discovered_peer_dial_retried_once() {
+        let (node, _events) = test_node();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let grace = Duration::from_millis(100);
+
+        // Nothing listens for the first attempt; the retry finds the peer.
+        let dial = tokio::spawn(async move {
+            dial_discovered(node, Uuid::new_v4(), addr, grace).await;
+        });
+        tokio::time::sleep(grace + grace / 2).await;
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let accepted = tokio::time::timeout(Duration::from_secs(1), listener.accept()).await;
+        assert!(accepted.is_ok());
+        dial.abort();
+    }
+
+    #[cfg(feature = "discovery")]
+    #[tokio::test]
+    async fn test_no_peers_hint_stops_once_peer_appears() {
+        let (node, _events) = test_node();
+        let interval = Duration::from_millis(50);
+        assert!(no_peers_for(&node, interval).await);
+
+        let connecting = tokio::spawn({
+            let node = node.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                add_test_peer(&node, "laptop").await;
+                node.peer_connected.notify_waiters();
+            }
+        });
+        assert!(!no_peers_for(&node, interval).await);
+        connecting.await.unwrap();
+        assert!(!no_peers_for(&node, interval).await);
+    }
+
+    #[test]
+    fn test_simultaneous_dial_keeps_same_connection_on_both_sides() {
+        let (a, _a_events) = test_node();
+        let (b, _b_events) = test_node();
+        // Both sides registered their own dial first and then accepted the
+        // other's; they must end up keeping the same connection.
+        let a_keeps_own_dial = a.keep_existing(b.id, true, false);
+        let b_keeps_a_dial = !b.keep_existing(a.id, true, false);
+        assert_eq!(a_keeps_own_dial, b_keeps_a_dial);
+        assert!(a.keep_existing(b.id, false, false));
+        assert!(a.keep_existing(b.id, true, true));
+    }
+
+    /// Starts the accept, poll and event loops of a daemon on loopback with
+    /// an in-memory clipboard, without mDNS.
+    async fn start_daemon(sync_primary: bool) -> (Node, SocketAddr, InMemoryClipboardProvider) {
+        start_node(test_node(), sync_primary).await
+    }
+
+    async fn start_node(
+        (mut node, events_rx): (Node, mpsc::Receiver<PeerEvent>),
+        sync_primary: bool,
+    ) -> (Node, SocketAddr, InMemoryClipboardProvider) {
+        node.sync_primary = sync_primary;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let local = InMemoryClipboardProvider::default();
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(local.clone()),
+        )));
+        let options = SyncOptions {
+            sync_primary,
+            ..test_options()
+        };
+
+        tokio::spawn(accept_peers(node.clone(), listener));
+        tokio::spawn(poll_clipboard(
+            node.clone(),
+            clipboard.clone(),
+            options.clone(),
+            Arc::new(AtomicU64::new(0)),
+        ));
+        tokio::spawn(handle_events(node.clone(), events_rx, clipboard, options));
+        (node, addr, local)
+    }
+
+    async fn wait_for_text(clipboard: &mut InMemoryClipboardProvider, expected: &str) {
+        for _ in 0..200 {
+            if clipboard.get_text().unwrap() == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("clipboard never became {expected:?}");
+    }
+
+    #[tokio::test]
+    async fn test_clipboard_syncs_between_two_daemons() {
+        let (a, _, mut a_clipboard) = start_daemon(false).await;
+        let (b, b_addr, mut b_clipboard) = start_daemon(false).await;
+
+        assert_eq!(a.connect(b_addr).await.unwrap(), b.id);
+
+        a_clipboard.set_text("from a").unwrap();
+        wait_for_text(&mut b_clipboard, "from a").await;
+
+        b_clipboard.set_text("from b").unwrap();
+        wait_for_text(&mut a_clipboard, "from b").await;
+
+        // Neither side echoes the applied value back.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(a_clipboard.get_text().unwrap(), "from b");
+        assert_eq!(b_clipboard.get_text().unwrap(), "from b");
+    }
+
+    #[tokio::test]
+    async fn test_missed_update_healed_by_hash_check() {
+        let hash_checked = || {
+            let (mut node, events_rx) = test_node();
+            node.latest = Some(Default::default());
+            (node, events_rx)
+        };
+        let (a, _, mut a_clipboard) = start_node(hash_checked(), false).await;
+        let (b, b_addr, mut b_clipboard) = start_node(hash_checked(), false).await;
+        assert_eq!(a.connect(b_addr).await.unwrap(), b.id);
+
+        a_clipboard.set_text("first").unwrap();
+        wait_for_text(&mut b_clipboard, "first").await;
+
+        // Lose the next update on its way to b.
+        a.muted.lock().await.insert(b.id);
+        a_clipboard.set_text("second").unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(b_clipboard.get_text().unwrap(), "first");
+        a.muted.lock().await.remove(&b.id);
+
+        tokio::spawn(check_hashes(a.clone(), Duration::from_millis(20)));
+        wait_for_text(&mut b_clipboard, "second").await;
+    }
+
+    #[tokio::test]
+    async fn test_rejected_content_never_served_on_request() {
+        let (mut node, events_rx) = test_node();
+        node.latest = Some(Default::default());
+        node.directions = Arc::new(PeerDirections {
+            send_only: HashSet::from(["kiosk".to_string()]),
+            ..Default::default()
+        });
+        let (kiosk, _) = add_test_peer(&node, "kiosk").await;
+        let (muted, _) = add_test_peer(&node, "muted").await;
+        let (desktop, _) = add_test_peer(&node, "desktop").await;
+        let (laptop, mut laptop_rx) = add_test_peer(&node, "laptop").await;
+        node.muted.lock().await.insert(muted);
+        let mut local = InMemoryClipboardProvider::default();
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(local.clone()),
+        )));
+        tokio::spawn(handle_events(
+            node.clone(),
+            events_rx,
+            clipboard,
+            test_options(),
+        ));
+
+        let events = [
+            (desktop, "from desktop", 1),
+            (kiosk, "from kiosk", 2),
+            (muted, "from muted", 3),
+        ];
+        for (id, content, timestamp) in events {
+            node.events_tx
+                .send(PeerEvent::Clipboard {
+                    id,
+                    content: content.into(),
+                    timestamp,
+                    selection: Selection::Clipboard,
+                })
+                .await
+                .unwrap();
+        }
+        node.events_tx
+            .send(PeerEvent::ClipboardRequest {
+                id: laptop,
+                selection: Selection::Clipboard,
+            })
+            .await
+            .unwrap();
+
+        let (content, timestamp, _) =
+            tokio::time::timeout(Duration::from_secs(1), laptop_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!((content.as_str(), timestamp), ("from desktop", 1));
+        wait_for_text(&mut local, "from desktop").await;
+    }
+
+    #[tokio::test]
+    async fn test_primary_selection_synced_separately() {
+        let (a, _, mut a_clipboard) = start_daemon(true).await;
+        let (b, b_addr, mut b_clipboard) = start_daemon(true).await;
+        let (_, c_addr, mut c_clipboard) = start_daemon(false).await;
+
+        assert_eq!(a.connect(b_addr).await.unwrap(), b.id);
+        a.connect(c_addr).await.unwrap();
+
+        a_clipboard.set_primary("selected").unwrap();
+        for _ in 0..200 {
+            if b_clipboard.get_primary().unwrap() == "selected" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(b_clipboard.get_primary().unwrap(), "selected");
+        assert_eq!(b_clipboard.get_text().unwrap(), "");
+
+        a_clipboard.set_text("copied").unwrap();
+        wait_for_text(&mut b_clipboard, "copied").await;
+        wait_for_text(&mut c_clipboard, "copied").await;
+        assert_eq!(b_clipboard.get_primary().unwrap(), "selected");
+        assert_eq!(c_clipboard.get_primary().unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn test_primary_not_sent_to_peer_without_it() {
+        let (_, addr, mut clipboard) = start_daemon(true).await;
+        // Peers that don't advertise PRIMARY would put it on their CLIPBOARD.
+        let mut peer = PeerConnection::connect(addr).await.unwrap();
+        peer.handshake_outbound(Uuid::new_v4(), "laptop", "psk")
+            .await
+            .unwrap();
+
+        clipboard.set_primary("selected").unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        clipboard.set_text("copied").unwrap();
+        let msg = tokio::time::timeout(Duration::from_secs(1), peer.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        match msg {
+            protocol::Message::Clipboard {
+                content, selection, ..
+            } => assert_eq!(
+                (content.as_str(), selection),
+                ("copied", Selection::Clipboard)
+            ),
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_only_peer_inbound_ignored() {
+        let (mut node, events_rx) = test_node();
+        node.directions = Arc::new(PeerDirections {
+            send_only: HashSet::from(["kiosk".to_string()]),
+            receive_only: HashSet::from(["laptop".to_string()]),
+            ..Default::default()
+        });
+        let (kiosk, mut kiosk_rx) = add_test_peer(&node, "kiosk").await;
+        let (desktop, _) = add_test_peer(&node, "desktop").await;
+        let (_, mut laptop_rx) = add_test_peer(&node, "laptop").await;
+
+        let mut local = InMemoryClipboardProvider::default();
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(local.clone()),
+        )));
+        let options = test_options();
+        tokio::spawn(handle_events(node.clone(), events_rx, clipboard, options));
+
+        for (id, content) in [(kiosk, "from kiosk"), (desktop, "from desktop")] {
+            node.events_tx
+                .send(PeerEvent::Clipboard {
+                    id,
+                    content: content.into(),
+                    timestamp: 1,
+                    selection: Selection::Clipboard,
+                })
+                .await
+                .unwrap();
+        }
+        wait_for_text(&mut local, "from desktop").await;
+
+        node.events_tx
+            .send(PeerEvent::Clipboard {
+                id: kiosk,
+                content: "from kiosk again".into(),
+                timestamp: 2,
+                selection: Selection::Clipboard,
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(local.get_text().unwrap(), "from desktop");
+
+        node.broadcast("shared", 3, Selection::Clipboard).await;
+        assert_eq!(kiosk_rx.try_recv().unwrap().0, "shared");
+        assert!(laptop_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_empty_content_ignored_or_cleared_per_policy() {
+        for policy in [EmptyContent::Ignore, EmptyContent::Clear] {
+            let (node, events_rx) = test_node();
+            let (peer, _rx) = add_test_peer(&node, "laptop").await;
+            let mut local = InMemoryClipboardProvider::default();
+            let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+                LazyClipboard::from_provider(local.clone()),
+            )));
+            let options = SyncOptions {
+                empty_content: policy,
+                ..test_options()
+            };
+            tokio::spawn(handle_events(node.clone(), events_rx, clipboard, options));
+
+            for (content, timestamp) in [("kept?", 1), ("", 2)] {
+                node.events_tx
+                    .send(PeerEvent::Clipboard {
+                        id: peer,
+                        content: content.into(),
+                        timestamp,
+                        selection: Selection::Clipboard,
+                    })
+                    .await
+                    .unwrap();
+                if !content.is_empty() {
+                    wait_for_text(&mut local, content).await;
+                }
+            }
+            match policy {
+                EmptyContent::Ignore => {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    assert_eq!(local.get_text().unwrap(), "kept?");
+                }
+                EmptyContent::Clear => wait_for_text(&mut local, "").await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_only_group_inbound_ignored() {
+        let (mut node, events_rx) = test_node();
+        node.directions = Arc::new(PeerDirections {
+            groups: HashMap::from([
+                ("demo".to_string(), Direction::SendOnly),
+                ("home".to_string(), Direction::Both),
+            ]),
+            ..Default::default()
+        });
+        let (demo, mut demo_rx) = add_test_peer(&node, "demo-box").await;
+        let (home, mut home_rx) = add_test_peer(&node, "desktop").await;
+        for (id, group) in [(demo, "demo"), (home, "home")] {
+            node.peers.lock().await.get_mut(&id).unwrap().group = Some(group.into());
+        }
+
+        let mut local = InMemoryClipboardProvider::default();
+        let clipboard = Arc::new(Mutex::new(SyncedClipboard::with_clipboard(
+            LazyClipboard::from_provider(local.clone()),
+        )));
+        let options = test_options();
+        tokio::spawn(handle_events(node.clone(), events_rx, clipboard, options));
+
+        for (id, content) in [(demo, "from demo"), (home, "from home")] {
+            node.events_tx
+                .send(PeerEvent::Clipboard {
+                    id,
+                    content: content.into(),
+                    timestamp: 1,
+                    selection: Selection::Clipboard,
+                })
+                .await
+                .unwrap();
+        }
+        wait_for_text(&mut local, "from home").await;
+
+        node.events_tx
+            .send(PeerEvent::Clipboard {
+                id: demo,
+                content: "from demo again".into(),
+                timestamp: 2,
+                selection: Selection::Clipboard,
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(local.get_text().unwrap(), "from home");
+
+        node.broadcast("shared", 3, Selection::Clipboard).await;
+        assert_eq!(demo_rx.try_recv().unwrap().0, "shared");
+        assert_eq!(home_rx.try_recv().unwrap().0, "shared");
+    }
+
+    #[test]
+    fn test_parse_group_direction() {
+        assert_eq!(
+            parse_group_direction("demo=send-only").unwrap(),
+            ("demo".to_string(), Direction::SendOnly)
+        );
+        assert!(parse_group_direction("a=b=both").is_err());
+        assert!(parse_group_direction("demo").is_err());
+        assert!(parse_group_direction("demo=sideways").is_err());
+    }
+
+    #[test]
+    fn test_startup_summary_has_key_fields() {
+        let (captured, subscriber) = Captured::subscriber();
+        let id = Uuid::new_v4();
+        tracing::subscriber::with_default(subscriber, || {
+            StartupSummary {
+                id,
+                name: "laptop",
+                group: Some("home"),
+                listen: "0.0.0.0:42069".parse().unwrap(),
+                discovery: true,
+                psk: PskSource::of("hunter2", false),
+                backend: clipboard::Backend::Wayland,
+            }
+            .log();
+        });
+
+        let output = captured.output();
+        assert_eq!(output.lines().count(), 1);
+        for field in [
+            "startup".to_string(),
+            format!("version=\"{}\"", env!("CARGO_PKG_VERSION")),
+            format!("id={id}"),
+            "name=\"laptop\"".to_string(),
+            "group=\"home\"".to_string(),
+            "listen=0.0.0.0:42069".to_string(),
+            "discovery=true".to_string(),
+            "psk=\"set\"".to_string(),
+            "backend=wayland".to_string(),
+        ] {
+            assert!(output.contains(&field), "{field} missing from {output}");
+        }
+        assert!(!output.contains("hunter2"));
+        assert_eq!(PskSource::of(OPEN_PSK, false), PskSource::Insecure);
+    }
+
+    #[tokio::test]
+    async fn test_polling_waits_for_peers() {
+        let peers: PeerMap = Arc::new(Mutex::new(HashMap::new()));
+        let peer_connected = Arc::new(Notify::new());
+
+        let waiter = tokio::spawn({
+            let peers = peers.clone();
+            let peer_connected = peer_connected.clone();
+            async move { wait_for_peers(&peers, &peer_connected).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        let (tx, _rx) = mpsc::channel(1);
+        let handle = test_handle("laptop", tx);
+        let task = handle.task.clone();
+        peers.lock().await.insert(Uuid::new_v4(), handle);
+        peer_connected.notify_waiters();
+
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("polling should resume once a peer connects")
+            .unwrap();
+        task.abort();
+    }
+
+    #[test]
+    fn test_duplicate_names_suffixed() {
+        let id: Uuid = "1a2b3c4d-0000-4000-8000-000000000000".parse().unwrap();
+        assert_eq!(
+            DuplicateNames::Suffix.display_name("laptop", id, true),
+            "laptop#1a2b3c4d"
+        );
+        assert_eq!(
+            DuplicateNames::Suffix.display_name("laptop", id, false),
+            "laptop"
+        );
+        assert_eq!(
+            DuplicateNames::Keep.display_name("laptop", id, true),
+            "laptop"
+        );
+    }
+
+    #[test]
+    fn test_check_interval_alias() {
+        let args = parse_args(["cursedboard", "--check-interval", "2000"]);
+        assert_eq!(args.poll_ms, 2000);
+        let args = parse_args(["cursedboard", "--poll-ms", "750"]);
+        assert_eq!(args.poll_ms, 750);
+    }
+
+    #[test]
+    fn test_no_persist_creates_no_files() {
+        fn snapshot(dir: &Path) -> Vec<(PathBuf, Option<std::time::SystemTime>)> {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                return Vec::new();
+            };
+            let mut files: Vec<_> = entries
+                .flatten()
+                .map(|entry| {
+                    let modified = entry.metadata().and_then(|m| m.modified()).ok();
+                    (entry.path(), modified)
+                })
+                .collect();
+            files.sort();
+            files
+        }
+
+        assert!(
+            try_parse_args(["cursedboard", "--no-persist", "--instance-file", "x.toml"]).is_err()
+        );
+        let args = parse_args(["cursedboard", "--no-persist"]);
+        assert!(check_command_persists(&args).is_ok());
+        for argv in [
+            &["cursedboard", "--no-persist", "regenerate-id"][..],
+            &[
+                "cursedboard",
+                "--no-persist",
+                "trust",
+                &Uuid::new_v4().to_string(),
+            ],
+            &[
+                "cursedboard",
+                "--ephemeral",
+                "trust",
+                &Uuid::new_v4().to_string(),
+            ],
+        ] {
+            assert!(check_command_persists(&parse_args(argv)).is_err());
+        }
+
+        // The same paths the daemon uses without --instance-file.
+        let paths = StatePaths::new(args.instance_file.as_deref());
+        let before = snapshot(&paths.dir);
+        let writable = state_writable(&paths, args.no_persist);
+        assert!(!writable);
+        let first = load_instance(&paths, writable, args.no_persist).unwrap();
+        let second = load_instance(&paths, writable, args.no_persist).unwrap();
+        assert_ne!(first.id, second.id);
+
+        let mut trust = TrustStore::load_from(paths.trusted()).unwrap().ephemeral();
+        trust.trust(Uuid::new_v4(), "laptop".into());
+        trust.save().unwrap();
+        assert_eq!(snapshot(&paths.dir), before);
+    }
+
+    #[test]
+    fn test_trust_command_persists_peer() {
+        let path = std::env::temp_dir()
+            .join(format!("cursedboard-test-{}", Uuid::new_v4()))
+            .join("trusted.toml");
+        let id = Uuid::new_v4();
+        let args = parse_args(["cursedboard", "trust", &id.to_string(), "laptop"]);
+        let Some(Command::Trust { id: parsed, name }) = args.command else {
+            panic!("not a trust command");
+        };
+        assert_eq!(parsed, id);
+
+        let mut trust = TrustStore::load_from(path.clone()).unwrap();
+        assert_eq!(
+            trust_peer(&mut trust, id, name).unwrap(),
+            format!("trusted {id}")
+        );
+        let mut reloaded = TrustStore::load_from(path.clone()).unwrap();
+        assert!(reloaded.is_trusted(&id));
+        assert_eq!(
+            trust_peer(&mut reloaded, id, Some("other".into())).unwrap(),
+            format!("{id} is already trusted")
+        );
+
+        assert!(try_parse_args(["cursedboard", "trust", "not-a-uuid"]).is_err());
+        assert!(try_parse_args(["cursedboard", "--max-trusted-peers", "0"]).is_err());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_reconnect_schedule_parsed_and_positive() {
+        let args = parse_args(["cursedboard", "--reconnect-schedule-ms", "500,2000"]);
+        assert_eq!(args.reconnect_schedule_ms, [500, 2000]);
+        assert!(try_parse_args(["cursedboard", "--reconnect-schedule-ms", "500,0"]).is_err());
+    }
+
+    #[test]
+    fn test_missing_psk_refused_without_insecure_flag() {
+        let err = resolve_psk(None, None, false).unwrap_err();
+        assert!(err.to_string().contains("--insecure-no-psk"));
+        assert_eq!(resolve_psk(None, None, true).unwrap(), OPEN_PSK);
+        assert_eq!(
+            resolve_psk(Some("hunter2"), None, false).unwrap(),
+            "hunter2"
+        );
+        for insecure_no_psk in [false, true] {
+            let err = resolve_psk(Some(""), None, insecure_no_psk).unwrap_err();
+            assert!(err.to_string().contains("empty"));
+        }
+    }
+
+    #[test]
+    fn test_config_sources_reports_layering() {
+        let matches = with_env("CURSEDBOARD_PSK", "from-env", || {
+            Args::command().try_get_matches_from(["cursedboard", "--port", "1234"])
+        })
+        .unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+
+        let report = config_sources(&args, &matches).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines.contains(&"port = 1234  (command line)"));
+        assert!(lines.contains(&"psk = \"<redacted>\"  (env CURSEDBOARD_PSK)"));
+        assert!(lines.contains(&"poll_ms = 500  (default)"));
+        assert!(!report.contains("from-env"));
+    }
+
+    #[test]
+    fn test_group_from_env_unless_flag_given() {
+        let (from_env, from_flag) = with_env("CURSEDBOARD_GROUP", "from-env", || {
+            (
+                Args::try_parse_from(["cursedboard"]),
+                Args::try_parse_from(["cursedboard", "--group", "from-flag"]),
+            )
+        });
+        let (from_env, from_flag) = (from_env.unwrap(), from_flag.unwrap());
+
+        assert_eq!(from_env.group.as_deref(), Some("from-env"));
+        assert_eq!(from_flag.group.as_deref(), Some("from-flag"));
+    }
+
+    #[test]
+    fn test_dump_config_reflects_overrides() {
+        let args = with_env("CURSEDBOARD_PSK_FILE", "/run/secrets/from-env", || {
+            Args::try_parse_from([
+                "cursedboard",
+                "--port",
+                "1234",
+                "--psk",
+                "hunter2",
+                "--active-hours",
+                "09:00-17:00",
+            ])
+        })
+        .unwrap();
+
+        let dump = toml::to_string(&args).unwrap();
+        assert!(dump.contains("port = 1234"));
+        assert!(dump.contains("psk_file = \"/run/secrets/from-env\""));
+        assert!(dump.contains("active_hours = \"09:00-17:00\""));
+        assert!(dump.contains("duplicate_names = \"suffix\""));
+        assert!(dump.contains("psk = \"<redacted>\""));
+        assert!(!dump.contains("hunter2"));
+    }
+}
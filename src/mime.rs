@@ -0,0 +1,290 @@
+#[cfg(target_os = "linux")]
+use crate::clipboard::Backend;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use tracing::debug;
+
+/// Clipboard access by MIME target, for content other than text such as
+/// `image/png` or `text/uri-list` (copied files).
+pub trait MimeBackend {
+    type Error: std::fmt::Display;
+
+    /// Targets offered by the current clipboard owner.
+    fn targets(&mut self) -> Result<Vec<String>, Self::Error>;
+    fn get(&mut self, target: &str) -> Result<Vec<u8>, Self::Error>;
+    /// Replaces the clipboard with `data` offered as `target` only.
+    fn set(&mut self, target: &str, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Picks the first of `wanted`, in configured order, that `offered` has.
+pub fn select_target<'a>(wanted: &'a [String], offered: &[String]) -> Option<&'a str> {
+    wanted
+        .iter()
+        .find(|target| offered.contains(target))
+        .map(String::as_str)
+}
+
+/// Tracks the configured MIME targets of the local clipboard, reporting
+/// local changes and applying content received from peers without
+/// reporting it back.
+pub struct MimePassthrough<B> {
+    backend: B,
+    wanted: Vec<String>,
+    last: Option<(String, u64)>,
+}
+
+impl<B: MimeBackend> MimePassthrough<B> {
+    pub fn new(backend: B, wanted: Vec<String>) -> Self {
+        Self {
+            backend,
+            wanted,
+            last: None,
+        }
+    }
+
+    /// Returns the preferred wanted target and its data if the clipboard
+    /// offers one whose content changed since the last sync.
+    pub fn poll_change(&mut self) -> Option<(String, Vec<u8>)> {
+        let offered = match self.backend.targets() {
+            Ok(offered) => offered,
+            Err(e) => {
+                debug!(error = %e, "failed to list clipboard targets");
+                return None;
+            }
+        };
+        let target = select_target(&self.wanted, &offered)?.to_string();
+        let data = match self.backend.get(&target) {
+            Ok(data) if !data.is_empty() => data,
+            Ok(_) => return None,
+            Err(e) => {
+                debug!(%target, error = %e, "failed to read clipboard target");
+                return None;
+            }
+        };
+        let last = Some((target.clone(), hash(&data)));
+        if self.last == last {
+            return None;
+        }
+        self.last = last;
+        Some((target, data))
+    }
+
+    /// Sets `target` on the local clipboard. Targets that weren't asked for
+    /// are ignored.
+    pub fn apply_remote(&mut self, target: &str, data: &[u8]) -> Result<(), B::Error> {
+        if !self.wanted.iter().any(|wanted| wanted == target) {
+            debug!(%target, "target not synced, ignoring");
+            return Ok(());
+        }
+        self.last = Some((target.to_string(), hash(data)));
+        self.backend.set(target, data)
+    }
+}
+
+fn hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// MIME targets through the Wayland data-control protocol. X11 sessions
+/// aren't supported.
+#[cfg(target_os = "linux")]
+pub struct WaylandMime;
+
+#[cfg(target_os = "linux")]
+pub type PlatformMime = WaylandMime;
+
+#[cfg(target_os = "linux")]
+impl WaylandMime {
+    pub fn new() -> Option<Self> {
+        (Backend::detect() == Backend::Wayland).then_some(Self)
+    }
+}
+
+/// Stands in for a backend on platforms without one; never constructed.
+#[cfg(not(target_os = "linux"))]
+pub enum Unsupported {}
+
+#[cfg(not(target_os = "linux"))]
+pub type PlatformMime = Unsupported;
+
+#[cfg(not(target_os = "linux"))]
+impl Unsupported {
+    pub fn new() -> Option<Self> {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl MimeBackend for Unsupported {
+    type Error = std::convert::Infallible;
+
+    fn targets(&mut self) -> Result<Vec<String>, Self::Error> {
+        match *self {}
+    }
+
+    fn get(&mut self, _target: &str) -> Result<Vec<u8>, Self::Error> {
+        match *self {}
+    }
+
+    fn set(&mut self, _target: &str, _data: &[u8]) -> Result<(), Self::Error> {
+        match *self {}
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, thiserror::Error)]
+pub enum WaylandMimeError {
+    #[error("paste failed: {0}")]
+    Paste(#[from] wl_clipboard_rs::paste::Error),
+    #[error("copy failed: {0}")]
+    Copy(#[from] wl_clipboard_rs::copy::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[cfg(target_os = "linux")]
+impl MimeBackend for WaylandMime {
+    type Error = WaylandMimeError;
+
+    fn targets(&mut self) -> Result<Vec<String>, Self::Error> {
+        use wl_clipboard_rs::paste::{get_mime_types, ClipboardType, Error, Seat};
+
+        match get_mime_types(ClipboardType::Regular, Seat::Unspecified) {
+            Ok(targets) => Ok(targets.into_iter().collect()),
+            Err(Error::ClipboardEmpty | Error::NoSeats) => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get(&mut self, target: &str) -> Result<Vec<u8>, Self::Error> {
+        use std::io::Read;
+        use wl_clipboard_rs::paste::{get_contents, ClipboardType, MimeType, Seat};
+
+        let (mut pipe, _) = get_contents(
+            ClipboardType::Regular,
+            Seat::Unspecified,
+            MimeType::Specific(target),
+        )?;
+        let mut data = Vec::new();
+        pipe.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn set(&mut self, target: &str, data: &[u8]) -> Result<(), Self::Error> {
+        use wl_clipboard_rs::copy::{MimeType, Options, Source};
+
+        Options::new().copy(
+            Source::Bytes(data.into()),
+            MimeType::Specific(target.to_string()),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Offers = Vec<(String, Vec<u8>)>;
+
+    /// Clipboard holding data for any number of targets at once.
+    #[derive(Clone, Default)]
+    struct StubBackend {
+        offered: Arc<Mutex<Offers>>,
+    }
+
+    impl StubBackend {
+        fn offer(&self, targets: &[(&str, &[u8])]) {
+            *self.offered.lock().unwrap() = targets
+                .iter()
+                .map(|(target, data)| (target.to_string(), data.to_vec()))
+                .collect();
+        }
+    }
+
+    impl MimeBackend for StubBackend {
+        type Error = String;
+
+        fn targets(&mut self) -> Result<Vec<String>, Self::Error> {
+            Ok(self
+                .offered
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(target, _)| target.clone())
+                .collect())
+        }
+
+        fn get(&mut self, target: &str) -> Result<Vec<u8>, Self::Error> {
+            self.offered
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(offered, _)| offered == target)
+                .map(|(_, data)| data.clone())
+                .ok_or_else(|| format!("{target} not offered"))
+        }
+
+        fn set(&mut self, target: &str, data: &[u8]) -> Result<(), Self::Error> {
+            self.offer(&[(target, data)]);
+            Ok(())
+        }
+    }
+
+    fn targets(targets: &[&str]) -> Vec<String> {
+        targets.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_select_target_follows_configured_order() {
+        let wanted = targets(&["text/uri-list", "image/png"]);
+        let offered = targets(&["image/png", "text/plain", "text/uri-list"]);
+        assert_eq!(select_target(&wanted, &offered), Some("text/uri-list"));
+        assert_eq!(
+            select_target(&wanted, &targets(&["image/png"])),
+            Some("image/png")
+        );
+        assert_eq!(select_target(&wanted, &targets(&["text/plain"])), None);
+    }
+
+    #[test]
+    fn test_local_change_reported_once() {
+        let stub = StubBackend::default();
+        let mut passthrough = MimePassthrough::new(stub.clone(), targets(&["image/png"]));
+        assert_eq!(passthrough.poll_change(), None);
+
+        stub.offer(&[("text/plain", b"cat.png"), ("image/png", b"\x89PNG")]);
+        assert_eq!(
+            passthrough.poll_change(),
+            Some(("image/png".to_string(), b"\x89PNG".to_vec()))
+        );
+        assert_eq!(passthrough.poll_change(), None);
+
+        stub.offer(&[("text/plain", b"only text")]);
+        assert_eq!(passthrough.poll_change(), None);
+    }
+
+    #[test]
+    fn test_applied_data_passes_through_and_is_not_reported() {
+        let stub = StubBackend::default();
+        let mut passthrough =
+            MimePassthrough::new(stub.clone(), targets(&["text/uri-list", "image/png"]));
+
+        passthrough
+            .apply_remote("text/uri-list", b"file:///home/me/a.txt\r\n")
+            .unwrap();
+        assert_eq!(
+            stub.clone().get("text/uri-list").unwrap(),
+            b"file:///home/me/a.txt\r\n"
+        );
+        assert_eq!(passthrough.poll_change(), None);
+
+        passthrough
+            .apply_remote("application/x-unknown", b"ignored")
+            .unwrap();
+        assert!(stub.clone().get("application/x-unknown").is_err());
+    }
+}
@@ -1,24 +1,121 @@
+use crate::listener::Keepalive;
+use crate::ping::{PeerHealth, PingTracker};
 use crate::protocol::{
-    compute_auth_response, generate_challenge, verify_auth_response, Message, ProtocolError,
+    compute_auth_response, compute_dialer_response, decode_data, frame_len, generate_challenge,
+    verify_auth_response, verify_dialer_response, Compression, Message, ProtocolError, Selection,
+    MAX_HANDSHAKE_MESSAGE_SIZE, MAX_MESSAGE_SIZE,
 };
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
-use tracing::{debug, info};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
+/// Time allowed without any progress on a frame's body once its length
+/// header arrived. A peer that declares more than it sends would otherwise
+/// hold the read forever, while a large frame on a slow link keeps going as
+/// long as bytes trickle in.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time allowed for each handshake message, header included. Before a peer
+/// has authenticated nothing else bounds how long it may keep a connection
+/// open without sending anything, and keepalive doesn't fire on a live but
+/// idle socket. Dialers from before mutual authentication never send their
+/// answer to our challenge, and run into this too.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Frames read ahead of the connection loop while it is busy.
+const READ_AHEAD_FRAMES: usize = 16;
+
+/// Messages waiting to be written while an earlier one, e.g. large MIME
+/// data, is still going out.
+const WRITE_QUEUE: usize = 16;
+
 #[derive(Debug)]
 pub enum PeerEvent {
     Connected { id: Uuid, name: String },
-    Clipboard { content: String, timestamp: u64 },
-    Disconnected { id: Uuid },
+    Clipboard {
+        id: Uuid,
+        content: String,
+        timestamp: u64,
+        selection: Selection,
+    },
+    Data {
+        id: Uuid,
+        target: String,
+        data: Vec<u8>,
+        timestamp: u64,
+    },
+    ClipboardHash {
+        id: Uuid,
+        hash: String,
+        timestamp: u64,
+        selection: Selection,
+    },
+    ClipboardRequest {
+        id: Uuid,
+        selection: Selection,
+    },
+    Disconnected {
+        id: Uuid,
+    },
+    Reconnecting {
+        id: Uuid,
+        attempts: u32,
+    },
+    ReconnectFailed {
+        id: Uuid,
+        attempts: u32,
+    },
+    /// Latency or missed pings changed.
+    Health {
+        id: Uuid,
+        health: PeerHealth,
+    },
 }
 
 pub struct PeerConnection {
     stream: TcpStream,
     peer_id: Option<Uuid>,
     peer_name: Option<String>,
+    /// Id and name from the peer's `Hello`, before it has authenticated.
+    claimed: Option<(Uuid, String)>,
+    inbound_interval: Option<Duration>,
+    /// Compression we advertise in `Hello`, most preferred first.
+    compression: Vec<Compression>,
+    /// Negotiated compression for frames we send, from the peer's preferences.
+    send_compression: Option<Compression>,
+    /// Negotiated compression the peer uses for frames it sends us.
+    recv_compression: Option<Compression>,
+    /// Group we advertise in `Hello`.
+    group: Option<String>,
+    /// Group the peer advertises, from its `Hello`.
+    peer_group: Option<String>,
+    /// MIME targets we accept as `Data`, advertised in `Hello`.
+    mime_targets: Vec<String>,
+    /// MIME targets the peer accepts, from its `Hello`.
+    peer_mime_targets: Vec<String>,
+    /// Whether we take part in clipboard hash checks, advertised in `Hello`.
+    hash_check: bool,
+    /// Whether the peer does, from its `Hello`.
+    peer_hash_check: bool,
+    /// Whether we sync PRIMARY, advertised in `Hello`.
+    primary: bool,
+    /// Whether the peer does, from its `Hello`.
+    peer_primary: bool,
+    /// `Data` messages to forward to the peer as they are.
+    outbound_data: Option<mpsc::Receiver<Message>>,
+    /// How often to ping the peer to measure latency, if at all.
+    ping_interval: Option<Duration>,
+    /// How long the body of a frame may go without any bytes arriving.
+    frame_timeout: Duration,
+    /// How long to wait for each message of the handshake.
+    handshake_timeout: Duration,
 }
 
 impl PeerConnection {
@@ -28,6 +125,23 @@ impl PeerConnection {
             stream,
             peer_id: None,
             peer_name: None,
+            claimed: None,
+            inbound_interval: None,
+            compression: Vec::new(),
+            send_compression: None,
+            recv_compression: None,
+            group: None,
+            peer_group: None,
+            mime_targets: Vec::new(),
+            peer_mime_targets: Vec::new(),
+            hash_check: false,
+            peer_hash_check: false,
+            primary: false,
+            peer_primary: false,
+            outbound_data: None,
+            ping_interval: None,
+            frame_timeout: FRAME_TIMEOUT,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
         })
     }
 
@@ -36,9 +150,112 @@ impl PeerConnection {
             stream,
             peer_id: None,
             peer_name: None,
+            claimed: None,
+            inbound_interval: None,
+            compression: Vec::new(),
+            send_compression: None,
+            recv_compression: None,
+            group: None,
+            peer_group: None,
+            mime_targets: Vec::new(),
+            peer_mime_targets: Vec::new(),
+            hash_check: false,
+            peer_hash_check: false,
+            primary: false,
+            peer_primary: false,
+            outbound_data: None,
+            ping_interval: None,
+            frame_timeout: FRAME_TIMEOUT,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
         }
     }
 
+    /// Forwards clipboard updates from this peer at most once per `interval`,
+    /// keeping only the latest of any that arrive faster.
+    pub fn with_inbound_interval(mut self, interval: Option<Duration>) -> Self {
+        self.inbound_interval = interval;
+        self
+    }
+
+    /// Pings the peer every `interval`, reporting the measured round trip
+    /// and missed pings as `PeerEvent::Health`.
+    pub fn with_ping_interval(mut self, interval: Option<Duration>) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    /// Advertises `compression` in the handshake. Frames are only
+    /// compressed if the peer advertises a matching algorithm too.
+    pub fn with_compression(mut self, compression: &[Compression]) -> Self {
+        self.compression = compression.to_vec();
+        self
+    }
+
+    /// Advertises the group we are in, so the peer can apply per-group rules.
+    pub fn with_group(mut self, group: Option<&str>) -> Self {
+        self.group = group.map(str::to_string);
+        self
+    }
+
+    /// Group the peer said it is in; `None` before the handshake.
+    pub fn peer_group(&self) -> Option<&str> {
+        self.peer_group.as_deref()
+    }
+
+    /// Advertises the MIME targets we accept besides text.
+    pub fn with_mime_targets(mut self, targets: &[String]) -> Self {
+        self.mime_targets = targets.to_vec();
+        self
+    }
+
+    /// Advertises that we send and answer clipboard hash checks.
+    pub fn with_hash_check(mut self, enabled: bool) -> Self {
+        self.hash_check = enabled;
+        self
+    }
+
+    /// Whether the peer said it takes part in clipboard hash checks.
+    pub fn peer_hash_check(&self) -> bool {
+        self.peer_hash_check
+    }
+
+    /// Advertises that we sync PRIMARY, so the peer sends it.
+    pub fn with_primary(mut self, enabled: bool) -> Self {
+        self.primary = enabled;
+        self
+    }
+
+    /// Whether the peer said it syncs PRIMARY.
+    pub fn peer_primary(&self) -> bool {
+        self.peer_primary
+    }
+
+    /// Sends `Data` messages arriving on `rx` to the peer.
+    pub fn with_outbound_data(mut self, rx: mpsc::Receiver<Message>) -> Self {
+        self.outbound_data = Some(rx);
+        self
+    }
+
+    /// MIME targets the peer said it accepts; empty before the handshake.
+    pub fn peer_mime_targets(&self) -> &[String] {
+        &self.peer_mime_targets
+    }
+
+    pub fn with_keepalive(self, keepalive: Option<Keepalive>) -> Self {
+        if let Some(keepalive) = keepalive {
+            if let Err(e) = keepalive.apply(&self.stream) {
+                warn!(error = %e, "failed to enable TCP keepalive");
+            }
+        }
+        self
+    }
+
+    /// The id and name the peer claimed in its `Hello`, if it sent one.
+    /// Unverified unless the handshake succeeded.
+    pub fn claimed(&self) -> Option<(Uuid, &str)> {
+        self.claimed.as_ref().map(|(id, name)| (*id, name.as_str()))
+    }
+
     pub async fn handshake_outbound(
         &mut self,
         our_id: Uuid,
@@ -48,14 +265,44 @@ impl PeerConnection {
         let hello = Message::Hello {
             id: our_id,
             name: our_name.to_string(),
+            group: self.group.clone(),
+            compression: self.compression.clone(),
+            mime_targets: self.mime_targets.clone(),
+            hash_check: self.hash_check,
+            primary: self.primary,
         };
         self.send(&hello).await?;
 
-        let their_hello = self.recv().await?;
-        let (their_id, their_name) = match their_hello {
-            Message::Hello { id, name } => (id, name),
+        let their_hello = self.recv_handshake().await?;
+        let (
+            their_id,
+            their_name,
+            their_group,
+            their_compression,
+            their_targets,
+            their_hash_check,
+            their_primary,
+        ) = match their_hello {
+            Message::Hello {
+                id,
+                name,
+                group,
+                compression,
+                mime_targets,
+                hash_check,
+                primary,
+            } => (
+                id,
+                name,
+                group,
+                compression,
+                mime_targets,
+                hash_check,
+                primary,
+            ),
             _ => return Err(ProtocolError::AuthFailed),
         };
+        self.claimed = Some((their_id, their_name.clone()));
 
         let challenge = generate_challenge();
         let auth = Message::Auth {
@@ -64,16 +311,34 @@ impl PeerConnection {
         };
         self.send(&auth).await?;
 
-        let their_auth = self.recv().await?;
-        match their_auth {
-            Message::Auth { response, .. } => {
+        let their_auth = self.recv_handshake().await?;
+        let their_challenge = match their_auth {
+            Message::Auth {
+                challenge: their_challenge,
+                response,
+            } => {
                 if !verify_auth_response(psk, &challenge, &response) {
                     return Err(ProtocolError::AuthFailed);
                 }
+                their_challenge
             }
             _ => return Err(ProtocolError::AuthFailed),
+        };
+        // Listeners predating mutual authentication send no challenge and
+        // don't expect an answer.
+        if their_challenge != [0u8; 32] {
+            let auth = Message::Auth {
+                challenge: [0u8; 32],
+                response: compute_dialer_response(psk, &their_challenge, &challenge),
+            };
+            self.send(&auth).await?;
         }
 
+        self.negotiate_compression(&their_compression);
+        self.peer_group = their_group;
+        self.peer_mime_targets = their_targets;
+        self.peer_hash_check = their_hash_check;
+        self.peer_primary = their_primary;
         self.peer_id = Some(their_id);
         self.peer_name = Some(their_name.clone());
         Ok((their_id, their_name))
@@ -85,68 +350,163 @@ impl PeerConnection {
         our_name: &str,
         psk: &str,
     ) -> Result<(Uuid, String), ProtocolError> {
-        let their_hello = self.recv().await?;
-        let (their_id, their_name) = match their_hello {
-            Message::Hello { id, name } => (id, name),
+        let their_hello = self.recv_handshake().await?;
+        let (
+            their_id,
+            their_name,
+            their_group,
+            their_compression,
+            their_targets,
+            their_hash_check,
+            their_primary,
+        ) = match their_hello {
+            Message::Hello {
+                id,
+                name,
+                group,
+                compression,
+                mime_targets,
+                hash_check,
+                primary,
+            } => (
+                id,
+                name,
+                group,
+                compression,
+                mime_targets,
+                hash_check,
+                primary,
+            ),
             _ => return Err(ProtocolError::AuthFailed),
         };
+        self.claimed = Some((their_id, their_name.clone()));
 
         let hello = Message::Hello {
             id: our_id,
             name: our_name.to_string(),
+            group: self.group.clone(),
+            compression: self.compression.clone(),
+            mime_targets: self.mime_targets.clone(),
+            hash_check: self.hash_check,
+            primary: self.primary,
         };
         self.send(&hello).await?;
 
-        let their_auth = self.recv().await?;
+        let their_auth = self.recv_handshake().await?;
         let challenge = match their_auth {
             Message::Auth { challenge, .. } => challenge,
             _ => return Err(ProtocolError::AuthFailed),
         };
 
-        let response = compute_auth_response(psk, &challenge);
+        let our_challenge = generate_challenge();
         let auth = Message::Auth {
-            challenge: [0u8; 32],
-            response,
+            challenge: our_challenge,
+            response: compute_auth_response(psk, &challenge),
         };
         self.send(&auth).await?;
 
+        // A dialer hangs up here when our response doesn't match its PSK.
+        let dialer_auth = self.recv_handshake().await.map_err(|e| match e {
+            ProtocolError::HandshakeTimeout => ProtocolError::MutualAuthIncomplete,
+            ProtocolError::Closed => ProtocolError::AuthFailed,
+            e => e,
+        })?;
+        match dialer_auth {
+            Message::Auth { response, .. }
+                if verify_dialer_response(psk, &our_challenge, &challenge, &response) => {}
+            _ => return Err(ProtocolError::AuthFailed),
+        }
+
+        self.negotiate_compression(&their_compression);
+        self.peer_group = their_group;
+        self.peer_mime_targets = their_targets;
+        self.peer_hash_check = their_hash_check;
+        self.peer_primary = their_primary;
         self.peer_id = Some(their_id);
         self.peer_name = Some(their_name.clone());
         Ok((their_id, their_name))
     }
 
+    fn negotiate_compression(&mut self, theirs: &[Compression]) {
+        self.send_compression = Compression::negotiate(theirs, &self.compression);
+        self.recv_compression = Compression::negotiate(&self.compression, theirs);
+        debug!(send = ?self.send_compression, recv = ?self.recv_compression, "negotiated compression");
+    }
+
     pub async fn send(&mut self, msg: &Message) -> Result<(), ProtocolError> {
-        let data = msg.encode();
-        self.stream.write_all(&data).await?;
-        Ok(())
+        write_frame(&mut self.stream, msg, self.send_compression).await
     }
 
+    #[cfg(test)]
     pub async fn recv(&mut self) -> Result<Message, ProtocolError> {
-        let mut len_buf = [0u8; 4];
-        self.stream.read_exact(&mut len_buf).await?;
-        let len = u32::from_be_bytes(len_buf) as usize;
+        self.recv_limited(MAX_MESSAGE_SIZE).await
+    }
 
-        let mut buf = vec![0u8; 4 + len];
-        buf[..4].copy_from_slice(&len_buf);
-        self.stream.read_exact(&mut buf[4..]).await?;
+    async fn recv_handshake(&mut self) -> Result<Message, ProtocolError> {
+        tokio::time::timeout(
+            self.handshake_timeout,
+            self.recv_limited(MAX_HANDSHAKE_MESSAGE_SIZE),
+        )
+        .await
+        .map_err(|_| ProtocolError::HandshakeTimeout)?
+    }
 
-        Message::decode(&buf)
+    async fn recv_limited(&mut self, max_len: usize) -> Result<Message, ProtocolError> {
+        read_frame(
+            &mut self.stream,
+            max_len,
+            self.recv_compression,
+            self.frame_timeout,
+        )
+        .await
     }
 
-    pub fn peer_id(&self) -> Option<Uuid> {
-        self.peer_id
+    /// Flushes pending writes and half-closes the stream so the peer sees a
+    /// clean EOF after the last complete message.
+    pub async fn close(&mut self) {
+        let _ = self.stream.flush().await;
+        let _ = self.stream.shutdown().await;
     }
 
     pub async fn run(
-        mut self,
+        self,
         events_tx: mpsc::Sender<PeerEvent>,
-        mut clipboard_rx: mpsc::Receiver<(String, u64)>,
+        mut clipboard_rx: mpsc::Receiver<(String, u64, Selection)>,
     ) {
-        let peer_id = match self.peer_id {
-            Some(id) => id,
-            None => return,
+        let PeerConnection {
+            stream,
+            peer_id,
+            peer_name,
+            inbound_interval,
+            send_compression,
+            recv_compression,
+            mut outbound_data,
+            ping_interval,
+            frame_timeout,
+            ..
+        } = self;
+        let Some(peer_id) = peer_id else {
+            return;
         };
-        let peer_name = self.peer_name.clone().unwrap_or_default();
+        let peer_name = peer_name.unwrap_or_default();
+        // Frames are read in their own task: reading one isn't cancel safe,
+        // and the loop below stops waiting for it whenever something is due
+        // to be sent, which would lose a partly read frame.
+        let (reader, writer) = stream.into_split();
+        let (frames_tx, mut frames) = mpsc::channel(READ_AHEAD_FRAMES);
+        let reader = tokio::spawn(read_frames(
+            reader,
+            recv_compression,
+            frame_timeout,
+            frames_tx,
+        ));
+        // Writing has its own task too, so a large frame going out to a
+        // peer that is slow to read doesn't stop us from reading its frames.
+        // Otherwise two peers sending each other large data at once would
+        // both wait for the other to read.
+        let (to_peer, to_peer_rx) = mpsc::channel(WRITE_QUEUE);
+        let mut writer = tokio::spawn(write_frames(writer, send_compression, to_peer_rx));
+        let mut writer_done = false;
 
         let _ = events_tx
             .send(PeerEvent::Connected {
@@ -155,36 +515,839 @@ impl PeerConnection {
             })
             .await;
 
+        let (inbound_tx, throttle) = match inbound_interval {
+            Some(interval) => {
+                let (tx, rx) = mpsc::channel(16);
+                let task = tokio::spawn(throttle_inbound(rx, events_tx.clone(), peer_id, interval));
+                (Some(tx), Some(task))
+            }
+            None => (None, None),
+        };
+
+        let mut ping_interval =
+            ping_interval.map(|period| tokio::time::interval_at(Instant::now() + period, period));
+        let mut pings = PingTracker::default();
+
         loop {
             tokio::select! {
-                result = self.recv() => {
-                    match result {
-                        Ok(Message::Clipboard { content, timestamp }) => {
-                            debug!(peer = %peer_id, "received clipboard");
+                result = frames.recv() => {
+                    match result.unwrap_or(Err(ProtocolError::Closed)) {
+                        Ok(Message::Clipboard { content, timestamp, selection }) => {
+                            debug!(peer = %peer_id, ?selection, "received clipboard");
+                            match &inbound_tx {
+                                Some(tx) => {
+                                    let _ = tx.send((content, timestamp, selection)).await;
+                                }
+                                None => {
+                                    let _ = events_tx
+                                        .send(PeerEvent::Clipboard {
+                                            id: peer_id,
+                                            content,
+                                            timestamp,
+                                            selection,
+                                        })
+                                        .await;
+                                }
+                            }
+                        }
+                        Ok(Message::Ping { seq, sent_ms }) => {
+                            // Dropped while writes are backed up, like a pong
+                            // lost in transit.
+                            let _ = to_peer.try_send(Message::Pong { seq, sent_ms });
+                        }
+                        Ok(Message::Pong { seq, .. }) => {
+                            let before = pings.health();
+                            pings.pong(seq);
+                            if pings.health() != before {
+                                let _ = events_tx
+                                    .send(PeerEvent::Health { id: peer_id, health: pings.health() })
+                                    .await;
+                            }
+                        }
+                        Ok(Message::ClipboardHash { hash, timestamp, selection }) => {
                             let _ = events_tx
-                                .send(PeerEvent::Clipboard { content, timestamp })
+                                .send(PeerEvent::ClipboardHash { id: peer_id, hash, timestamp, selection })
                                 .await;
                         }
-                        Ok(Message::Ping) => {
-                            let _ = self.send(&Message::Pong).await;
+                        Ok(Message::ClipboardRequest { selection }) => {
+                            let _ = events_tx
+                                .send(PeerEvent::ClipboardRequest { id: peer_id, selection })
+                                .await;
                         }
-                        Ok(Message::Pong) => {}
+                        Ok(Message::Data { target, data, timestamp }) => match decode_data(&data) {
+                            Ok(data) => {
+                                debug!(peer = %peer_id, %target, "received clipboard data");
+                                let _ = events_tx
+                                    .send(PeerEvent::Data {
+                                        id: peer_id,
+                                        target,
+                                        data,
+                                        timestamp,
+                                    })
+                                    .await;
+                            }
+                            Err(e) => warn!(peer = %peer_id, %target, error = %e, "ignoring clipboard data"),
+                        },
                         Ok(_) => {}
+                        Err(ProtocolError::Closed) => {
+                            info!(peer = %peer_id, "peer closed connection");
+                            break;
+                        }
                         Err(e) => {
-                            info!(peer = %peer_id, error = %e, "peer disconnected");
+                            warn!(peer = %peer_id, error = %e, "peer connection failed");
                             break;
                         }
                     }
                 }
-                Some((content, timestamp)) = clipboard_rx.recv() => {
-                    let msg = Message::Clipboard { content, timestamp };
-                    if self.send(&msg).await.is_err() {
+                _ = async {
+                    match &mut ping_interval {
+                        Some(interval) => interval.tick().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    let before = pings.health();
+                    let ping = pings.ping();
+                    if pings.health() != before {
+                        debug!(peer = %peer_id, "peer missed a ping");
+                        let _ = events_tx
+                            .send(PeerEvent::Health { id: peer_id, health: pings.health() })
+                            .await;
+                    }
+                    // Skipped while writes are backed up, and counted as
+                    // missed when the next one is due.
+                    let _ = to_peer.try_send(ping);
+                }
+                outgoing = clipboard_rx.recv() => {
+                    let Some((content, timestamp, selection)) = outgoing else {
+                        debug!(peer = %peer_id, "closing connection");
+                        break;
+                    };
+                    let msg = Message::Clipboard { content, timestamp, selection };
+                    if to_peer.send(msg).await.is_err() {
                         break;
                     }
                 }
+                Some(msg) = async {
+                    match &mut outbound_data {
+                        Some(rx) => rx.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    if to_peer.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                written = &mut writer, if !writer_done => {
+                    writer_done = true;
+                    if let Ok(Err(e)) = written {
+                        warn!(peer = %peer_id, error = %e, "failed to send to peer");
+                    }
+                    break;
+                }
             }
         }
 
+        // Let queued messages go out, but don't wait on a peer that stopped
+        // reading.
+        drop(to_peer);
+        if !writer_done
+            && tokio::time::timeout(frame_timeout, &mut writer)
+                .await
+                .is_err()
+        {
+            writer.abort();
+        }
+        reader.abort();
+        drop(inbound_tx);
+        if let Some(task) = throttle {
+            let _ = task.await;
+        }
         let _ = events_tx.send(PeerEvent::Disconnected { id: peer_id }).await;
     }
 }
+
+/// Reads frames from the peer into `frames` until the connection fails or
+/// closes, which is reported as the last item.
+async fn read_frames(
+    mut reader: OwnedReadHalf,
+    compression: Option<Compression>,
+    frame_timeout: Duration,
+    frames: mpsc::Sender<Result<Message, ProtocolError>>,
+) {
+    loop {
+        let frame = read_frame(&mut reader, MAX_MESSAGE_SIZE, compression, frame_timeout).await;
+        let failed = frame.is_err();
+        if frames.send(frame).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Reads one frame of at most `max_len` bytes. Not cancel safe: the bytes
+/// of a partly read frame are lost.
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+    compression: Option<Compression>,
+    frame_timeout: Duration,
+) -> Result<Message, ProtocolError> {
+    let mut len_buf = [0u8; 4];
+    read_exact(reader, &mut len_buf).await?;
+    // Without negotiated compression, compare before stripping the flag,
+    // so a frame that can't be compressed but has it set (e.g. during the
+    // handshake) is rejected as oversized.
+    let header = u32::from_be_bytes(len_buf);
+    let len = frame_len(header);
+    let declared = match compression {
+        Some(_) => len,
+        None => header as usize,
+    };
+    if declared > max_len {
+        return Err(ProtocolError::MessageTooLarge(declared));
+    }
+
+    let mut buf = vec![0u8; 4 + len];
+    buf[..4].copy_from_slice(&len_buf);
+    let mut filled = 4;
+    while filled < buf.len() {
+        let read = tokio::time::timeout(frame_timeout, reader.read(&mut buf[filled..]))
+            .await
+            .map_err(|_| ProtocolError::StalledFrame(len))??;
+        if read == 0 {
+            return Err(ProtocolError::Closed);
+        }
+        filled += read;
+    }
+
+    Message::decode(&buf, compression)
+}
+
+/// Like `read_exact`, but reports the peer closing the connection, even
+/// mid-frame, as [`ProtocolError::Closed`] rather than an IO error.
+async fn read_exact<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> Result<(), ProtocolError> {
+    match reader.read_exact(buf).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(ProtocolError::Closed),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes messages from `outgoing` to the peer until the channel closes,
+/// then half-closes the stream so the peer sees a clean EOF.
+async fn write_frames(
+    mut writer: OwnedWriteHalf,
+    compression: Option<Compression>,
+    mut outgoing: mpsc::Receiver<Message>,
+) -> Result<(), ProtocolError> {
+    while let Some(msg) = outgoing.recv().await {
+        write_frame(&mut writer, &msg, compression).await?;
+    }
+    writer.flush().await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    msg: &Message,
+    compression: Option<Compression>,
+) -> Result<(), ProtocolError> {
+    writer.write_all(&msg.encode(compression)).await?;
+    Ok(())
+}
+
+/// Forwards inbound clipboard updates as events no more than once per
+/// `interval`. Updates arriving in between replace the one waiting for the
+/// same selection, and what is left is flushed when the connection ends.
+async fn throttle_inbound(
+    mut rx: mpsc::Receiver<(String, u64, Selection)>,
+    events_tx: mpsc::Sender<PeerEvent>,
+    peer_id: Uuid,
+    interval: Duration,
+) {
+    let mut next_allowed = Instant::now();
+    let mut pending = HashMap::new();
+    loop {
+        tokio::select! {
+            update = rx.recv() => {
+                let Some((content, timestamp, selection)) = update else { break };
+                if pending.insert(selection, (content, timestamp)).is_some() {
+                    debug!(peer = %peer_id, ?selection, "dropping superseded clipboard update");
+                }
+            }
+            _ = tokio::time::sleep_until(next_allowed), if !pending.is_empty() => {
+                forward_pending(&events_tx, peer_id, &mut pending).await;
+                next_allowed = Instant::now() + interval;
+            }
+        }
+    }
+    forward_pending(&events_tx, peer_id, &mut pending).await;
+}
+
+async fn forward_pending(
+    events_tx: &mpsc::Sender<PeerEvent>,
+    peer_id: Uuid,
+    pending: &mut HashMap<Selection, (String, u64)>,
+) {
+    for (selection, (content, timestamp)) in pending.drain() {
+        let _ = events_tx
+            .send(PeerEvent::Clipboard {
+                id: peer_id,
+                content,
+                timestamp,
+                selection,
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_keepalive_enabled_on_accepted_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let conn = PeerConnection::from_stream(stream).with_keepalive(Some(Keepalive {
+            idle: Duration::from_secs(45),
+            interval: Duration::from_secs(5),
+            retries: 3,
+        }));
+        let socket = socket2::SockRef::from(&conn.stream);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            assert_eq!(
+                socket.tcp_keepalive_time().unwrap(),
+                Duration::from_secs(45)
+            );
+            assert_eq!(
+                socket.tcp_keepalive_interval().unwrap(),
+                Duration::from_secs(5)
+            );
+            assert_eq!(socket.tcp_keepalive_retries().unwrap(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_handshake_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+            stream
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = PeerConnection::from_stream(stream);
+        let result = conn.handshake_inbound(Uuid::new_v4(), "test", "psk").await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::MessageTooLarge(len)) if len == u32::MAX as usize
+        ));
+        drop(client.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_slow_frame_survives_ping_ticks() {
+        let (mut outbound, inbound) = connected_pair().await;
+        let (events_tx, mut events_rx) = mpsc::channel(64);
+        let (_clipboard_tx, clipboard_rx) = mpsc::channel(1);
+        tokio::spawn(
+            inbound
+                .with_ping_interval(Some(Duration::from_millis(20)))
+                .run(events_tx, clipboard_rx),
+        );
+
+        let content = "x".repeat(64);
+        let frame = Message::Clipboard {
+            content: content.clone(),
+            timestamp: 1,
+            selection: Selection::Clipboard,
+        }
+        .encode(None);
+        // Several pings are due while the frame trickles in.
+        for chunk in frame.chunks(16) {
+            outbound.stream.write_all(chunk).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }
+
+        loop {
+            match events_rx.recv().await.unwrap() {
+                PeerEvent::Clipboard { content: got, .. } => {
+                    assert_eq!(got, content);
+                    break;
+                }
+                PeerEvent::Disconnected { .. } => panic!("connection dropped"),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_send_does_not_block_reading() {
+        let (mut outbound, inbound) = connected_pair().await;
+        let (events_tx, mut events_rx) = mpsc::channel(64);
+        let (_clipboard_tx, clipboard_rx) = mpsc::channel(1);
+        let (data_tx, data_rx) = mpsc::channel(1);
+        tokio::spawn(
+            inbound
+                .with_outbound_data(data_rx)
+                .run(events_tx, clipboard_rx),
+        );
+
+        // Far more than the socket buffers hold, and the peer never reads it.
+        data_tx
+            .send(Message::Data {
+                target: "image/png".into(),
+                data: "x".repeat(16 << 20),
+                timestamp: 1,
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let content = "sent while the data is stuck".to_string();
+        outbound
+            .send(&Message::Clipboard {
+                content: content.clone(),
+                timestamp: 2,
+                selection: Selection::Clipboard,
+            })
+            .await
+            .unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match events_rx.recv().await.unwrap() {
+                    PeerEvent::Clipboard { content, .. } => break content,
+                    PeerEvent::Disconnected { .. } => panic!("connection dropped"),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("reading blocked behind the send");
+        assert_eq!(received, content);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_after_handshake_rejected() {
+        let (mut outbound, mut inbound) = connected_pair().await;
+        let header = MAX_MESSAGE_SIZE as u32 + 1;
+        outbound
+            .stream
+            .write_all(&header.to_be_bytes())
+            .await
+            .unwrap();
+        assert!(matches!(
+            inbound.recv().await,
+            Err(ProtocolError::MessageTooLarge(len)) if len == MAX_MESSAGE_SIZE + 1
+        ));
+    }
+
+    async fn connected_pair() -> (PeerConnection, PeerConnection) {
+        connected_pair_with(&[], &[]).await
+    }
+
+    /// Connects two peers advertising `outbound` and `inbound` compression.
+    async fn connected_pair_with(
+        outbound: &'static [Compression],
+        inbound: &'static [Compression],
+    ) -> (PeerConnection, PeerConnection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let outbound = tokio::spawn(async move {
+            let mut conn = PeerConnection::connect(addr)
+                .await
+                .unwrap()
+                .with_compression(outbound);
+            conn.handshake_outbound(Uuid::new_v4(), "a", "psk")
+                .await
+                .unwrap();
+            conn
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut inbound = PeerConnection::from_stream(stream).with_compression(inbound);
+        inbound
+            .handshake_inbound(Uuid::new_v4(), "b", "psk")
+            .await
+            .unwrap();
+        (outbound.await.unwrap(), inbound)
+    }
+
+    fn large_clipboard() -> Message {
+        Message::Clipboard {
+            content: "the same line over and over\n".repeat(200),
+            timestamp: 1,
+            selection: Selection::Clipboard,
+        }
+    }
+
+    async fn read_header(conn: &mut PeerConnection) -> u32 {
+        let mut header = [0u8; 4];
+        conn.stream.read_exact(&mut header).await.unwrap();
+        u32::from_be_bytes(header)
+    }
+
+    #[tokio::test]
+    async fn test_groups_exchanged_in_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let outbound = tokio::spawn(async move {
+            let mut conn = PeerConnection::connect(addr)
+                .await
+                .unwrap()
+                .with_group(Some("demo"));
+            conn.handshake_outbound(Uuid::new_v4(), "a", "psk")
+                .await
+                .unwrap();
+            conn
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut inbound = PeerConnection::from_stream(stream);
+        inbound
+            .handshake_inbound(Uuid::new_v4(), "b", "psk")
+            .await
+            .unwrap();
+        let outbound = outbound.await.unwrap();
+
+        assert_eq!(inbound.peer_group(), Some("demo"));
+        assert_eq!(outbound.peer_group(), None);
+    }
+
+    #[tokio::test]
+    async fn test_peer_without_compression_gets_plain_frames() {
+        let (mut sender, mut receiver) = connected_pair_with(Compression::SUPPORTED, &[]).await;
+        assert_eq!(sender.send_compression, None);
+
+        let msg = large_clipboard();
+        sender.send(&msg).await.unwrap();
+        let header = read_header(&mut receiver).await;
+        assert_eq!(header as usize, msg.encode(None).len() - 4);
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_compression_shrinks_frames() {
+        let (mut sender, mut receiver) =
+            connected_pair_with(Compression::SUPPORTED, Compression::SUPPORTED).await;
+        assert_eq!(sender.send_compression, Some(Compression::Lz4));
+        assert_eq!(receiver.recv_compression, Some(Compression::Lz4));
+
+        let msg = large_clipboard();
+        sender.send(&msg).await.unwrap();
+        sender.send(&msg).await.unwrap();
+        let header = read_header(&mut receiver).await;
+        assert!(frame_len(header) < msg.encode(None).len() - 4);
+        let mut rest = vec![0u8; frame_len(header)];
+        receiver.stream.read_exact(&mut rest).await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), msg);
+    }
+
+    #[tokio::test]
+    async fn test_wrong_psk_is_auth_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let inbound = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = PeerConnection::from_stream(stream);
+            conn.handshake_inbound(Uuid::new_v4(), "b", "other").await
+        });
+        let mut conn = PeerConnection::connect(addr).await.unwrap();
+        let err = conn
+            .handshake_outbound(Uuid::new_v4(), "a", "psk")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ProtocolError::AuthFailed));
+        assert!(!err.is_retryable());
+        drop(conn);
+        assert!(inbound.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_inbound_rejects_dialer_with_wrong_psk() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let inbound = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = PeerConnection::from_stream(stream);
+            conn.handshake_inbound(Uuid::new_v4(), "b", "psk").await
+        });
+
+        // Follows the protocol, but can only answer with the wrong key.
+        let mut conn = PeerConnection::connect(addr).await.unwrap();
+        conn.send(&Message::Hello {
+            id: Uuid::new_v4(),
+            name: "a".into(),
+            group: None,
+            compression: Vec::new(),
+            mime_targets: Vec::new(),
+            hash_check: false,
+            primary: false,
+        })
+        .await
+        .unwrap();
+        conn.recv_handshake().await.unwrap();
+        let challenge = generate_challenge();
+        conn.send(&Message::Auth {
+            challenge,
+            response: [0u8; 32],
+        })
+        .await
+        .unwrap();
+        let Message::Auth {
+            challenge: their_challenge,
+            ..
+        } = conn.recv_handshake().await.unwrap()
+        else {
+            panic!("expected auth");
+        };
+        assert_ne!(their_challenge, [0u8; 32]);
+        conn.send(&Message::Auth {
+            challenge: [0u8; 32],
+            response: compute_dialer_response("other", &their_challenge, &challenge),
+        })
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            inbound.await.unwrap(),
+            Err(ProtocolError::AuthFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_times_out_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Connects and sends nothing, not even a length header.
+        let silent = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = PeerConnection::from_stream(stream);
+        conn.handshake_timeout = Duration::from_millis(100);
+        let result = conn.handshake_inbound(Uuid::new_v4(), "b", "psk").await;
+        assert!(matches!(result, Err(ProtocolError::HandshakeTimeout)));
+
+        // A listener that accepts but never answers doesn't hang the dialer.
+        let mut conn = PeerConnection::connect(addr).await.unwrap();
+        conn.handshake_timeout = Duration::from_millis(100);
+        let result = conn.handshake_outbound(Uuid::new_v4(), "a", "psk").await;
+        assert!(matches!(result, Err(ProtocolError::HandshakeTimeout)));
+        drop(silent);
+    }
+
+    #[tokio::test]
+    async fn test_dialer_without_mutual_auth_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let inbound = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = PeerConnection::from_stream(stream);
+            conn.handshake_timeout = Duration::from_millis(100);
+            conn.handshake_inbound(Uuid::new_v4(), "b", "psk").await
+        });
+
+        // Like a dialer from before mutual authentication: it checks the
+        // listener's answer and then waits for clipboard traffic.
+        let mut conn = PeerConnection::connect(addr).await.unwrap();
+        conn.send(&Message::Hello {
+            id: Uuid::new_v4(),
+            name: "a".into(),
+            group: None,
+            compression: Vec::new(),
+            mime_targets: Vec::new(),
+            hash_check: false,
+            primary: false,
+        })
+        .await
+        .unwrap();
+        conn.recv_handshake().await.unwrap();
+        conn.send(&Message::Auth {
+            challenge: generate_challenge(),
+            response: [0u8; 32],
+        })
+        .await
+        .unwrap();
+        conn.recv_handshake().await.unwrap();
+
+        assert!(matches!(
+            inbound.await.unwrap(),
+            Err(ProtocolError::MutualAuthIncomplete)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mid_frame_disconnect_is_clean_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let frame = Message::Ping {
+                seq: None,
+                sent_ms: None,
+            }
+            .encode(None);
+            stream.write_all(&frame[..frame.len() - 1]).await.unwrap();
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = PeerConnection::from_stream(stream);
+        client.await.unwrap();
+        assert!(matches!(conn.recv().await, Err(ProtocolError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_frame_shorter_than_declared_fails_instead_of_hanging() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ping = Message::Ping {
+            seq: None,
+            sent_ms: None,
+        };
+        let frame = ping.encode(None);
+
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(&frame).await.unwrap();
+            // Declare more bytes than are sent, then keep the connection open.
+            stream.write_all(&100u32.to_be_bytes()).await.unwrap();
+            stream.write_all(b"short").await.unwrap();
+            let _ = done_rx.await;
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = PeerConnection::from_stream(stream);
+        conn.frame_timeout = Duration::from_millis(100);
+        assert_eq!(conn.recv().await.unwrap(), ping);
+        assert!(matches!(
+            conn.recv().await,
+            Err(ProtocolError::StalledFrame(100))
+        ));
+        drop(done_tx);
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_frame_within_idle_timeout_arrives() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let msg = Message::Clipboard {
+            content: "x".repeat(64),
+            timestamp: 1,
+            selection: Selection::Clipboard,
+        };
+        let frame = msg.encode(None);
+
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            // Takes longer than the timeout in total, never between chunks.
+            for chunk in frame.chunks(16) {
+                stream.write_all(chunk).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(40)).await;
+            }
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = PeerConnection::from_stream(stream);
+        conn.frame_timeout = Duration::from_millis(100);
+        assert_eq!(conn.recv().await.unwrap(), msg);
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_clipboard_event_carries_sender_id() {
+        let (mut sender, receiver) = connected_pair().await;
+        let sender_id = receiver.peer_id.unwrap();
+        let (events_tx, mut events_rx) = mpsc::channel(8);
+        let (_clipboard_tx, clipboard_rx) = mpsc::channel(1);
+        tokio::spawn(receiver.run(events_tx, clipboard_rx));
+
+        sender
+            .send(&Message::Clipboard {
+                content: "hi".into(),
+                timestamp: 7,
+                selection: Selection::Clipboard,
+            })
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            events_rx.recv().await,
+            Some(PeerEvent::Connected { id, .. }) if id == sender_id
+        ));
+        match events_rx.recv().await {
+            Some(PeerEvent::Clipboard { id, content, .. }) => {
+                assert_eq!(id, sender_id);
+                assert_eq!(content, "hi");
+            }
+            other => panic!("unexpected event: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inbound_burst_throttled_keeping_last() {
+        let (tx, rx) = mpsc::channel(16);
+        let (events_tx, mut events_rx) = mpsc::channel(16);
+        let peer_id = Uuid::new_v4();
+        let task = tokio::spawn(throttle_inbound(
+            rx,
+            events_tx,
+            peer_id,
+            Duration::from_millis(200),
+        ));
+
+        for i in 1..=5 {
+            tx.send((format!("update {i}"), i, Selection::Clipboard))
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        drop(tx);
+        task.await.unwrap();
+
+        let mut received = Vec::new();
+        while let Ok(PeerEvent::Clipboard { id, content, .. }) = events_rx.try_recv() {
+            assert_eq!(id, peer_id);
+            received.push(content);
+        }
+        assert_eq!(received, ["update 1", "update 5"]);
+    }
+
+    #[tokio::test]
+    async fn test_large_send_delivered_before_close() {
+        let (sender, mut receiver) = connected_pair().await;
+        let (events_tx, _events_rx) = mpsc::channel(8);
+        let (clipboard_tx, clipboard_rx) = mpsc::channel(1);
+        let task = tokio::spawn(sender.run(events_tx, clipboard_rx));
+
+        let content = "x".repeat(4 * 1024 * 1024);
+        clipboard_tx
+            .send((content.clone(), 1, Selection::Clipboard))
+            .await
+            .unwrap();
+        drop(clipboard_tx);
+
+        match receiver.recv().await.unwrap() {
+            Message::Clipboard {
+                content: received, ..
+            } => assert_eq!(received, content),
+            other => panic!("unexpected message {other:?}"),
+        }
+        assert!(receiver.recv().await.is_err());
+        task.await.unwrap();
+    }
+}
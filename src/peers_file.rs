@@ -0,0 +1,86 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PeerListDiff {
+    pub added: Vec<SocketAddr>,
+    pub removed: Vec<SocketAddr>,
+}
+
+/// Reads one `host:port` per line. Blank lines and `#` comments are ignored.
+pub fn load(path: &Path) -> io::Result<Vec<SocketAddr>> {
+    let content = std::fs::read_to_string(path)?;
+    parse(&content)
+}
+
+fn parse(content: &str) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let addr = line.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no address for {line}"),
+            )
+        })?;
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    Ok(addrs)
+}
+
+pub fn diff(current: &[SocketAddr], new: &[SocketAddr]) -> PeerListDiff {
+    let current_set: HashSet<_> = current.iter().collect();
+    let new_set: HashSet<_> = new.iter().collect();
+    PeerListDiff {
+        added: new
+            .iter()
+            .filter(|addr| !current_set.contains(addr))
+            .copied()
+            .collect(),
+        removed: current
+            .iter()
+            .filter(|addr| !new_set.contains(addr))
+            .copied()
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let content = "# work\n10.0.0.2:42069\n\n10.0.0.3:42069 # laptop\n10.0.0.2:42069\n";
+        assert_eq!(
+            parse(content).unwrap(),
+            vec![addr("10.0.0.2:42069"), addr("10.0.0.3:42069")]
+        );
+        assert!(parse("not an address").is_err());
+    }
+
+    #[test]
+    fn test_reload_diff() {
+        let current = [addr("10.0.0.2:42069"), addr("10.0.0.3:42069")];
+        let reloaded = [addr("10.0.0.3:42069"), addr("10.0.0.4:42069")];
+        assert_eq!(
+            diff(&current, &reloaded),
+            PeerListDiff {
+                added: vec![addr("10.0.0.4:42069")],
+                removed: vec![addr("10.0.0.2:42069")],
+            }
+        );
+        assert_eq!(diff(&reloaded, &reloaded), PeerListDiff::default());
+    }
+}
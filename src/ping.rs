@@ -0,0 +1,160 @@
+use crate::protocol::Message;
+use crate::unix_millis;
+use std::time::{Duration, Instant};
+
+/// Round-trip latency and missed pings measured on a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerHealth {
+    pub latency: Option<Duration>,
+    pub missed_pings: u64,
+}
+
+/// Numbers the pings sent on a connection and matches them with the pongs
+/// echoing their sequence number. A ping counts as missed when the next one
+/// is due before its pong arrived.
+///
+/// The round trip is timed with the monotonic clock. The wall-clock send time
+/// in the ping is only for the peer, since a clock step (e.g. an NTP
+/// correction) between ping and pong would skew it.
+#[derive(Debug, Default)]
+pub struct PingTracker {
+    next_seq: u64,
+    /// Sequence number of the unanswered ping and when it was sent.
+    outstanding: Option<(u64, Instant)>,
+    health: PeerHealth,
+}
+
+impl PingTracker {
+    /// Returns the ping to send now.
+    pub fn ping(&mut self) -> Message {
+        self.ping_at(Instant::now(), unix_millis())
+    }
+
+    /// Returns the ping to send at `now`, which is `now_ms` milliseconds
+    /// since the epoch.
+    pub fn ping_at(&mut self, now: Instant, now_ms: u64) -> Message {
+        if self.outstanding.is_some() {
+            self.health.missed_pings += 1;
+        }
+        self.next_seq += 1;
+        self.outstanding = Some((self.next_seq, now));
+        Message::Ping {
+            seq: Some(self.next_seq),
+            sent_ms: Some(now_ms),
+        }
+    }
+
+    /// Records a pong received now.
+    pub fn pong(&mut self, seq: Option<u64>) {
+        self.pong_at(seq, Instant::now());
+    }
+
+    /// Records a pong received at `now`. Pongs from peers predating
+    /// sequence numbers carry none; they answer the outstanding ping without
+    /// a latency measurement. Pongs for older pings are ignored.
+    pub fn pong_at(&mut self, seq: Option<u64>, now: Instant) {
+        match (seq, self.outstanding) {
+            (Some(seq), Some((outstanding, sent))) if seq == outstanding => {
+                self.outstanding = None;
+                self.health.latency = Some(now.saturating_duration_since(sent));
+            }
+            (None, _) => self.outstanding = None,
+            _ => {}
+        }
+    }
+
+    pub fn health(&self) -> PeerHealth {
+        self.health
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo(ping: Message) -> Message {
+        match ping {
+            Message::Ping { seq, sent_ms } => Message::Pong { seq, sent_ms },
+            other => panic!("not a ping: {other:?}"),
+        }
+    }
+
+    fn seq(pong: Message) -> Option<u64> {
+        match pong {
+            Message::Pong { seq, .. } => seq,
+            other => panic!("not a pong: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_latency_from_ping_pong_exchange() {
+        let mut tracker = PingTracker::default();
+        let start = Instant::now();
+        let pong = echo(tracker.ping_at(start, 1_700_000_000_000));
+        assert!(matches!(
+            pong,
+            Message::Pong {
+                sent_ms: Some(1_700_000_000_000),
+                ..
+            }
+        ));
+        tracker.pong_at(seq(pong), start + Duration::from_millis(42));
+        assert_eq!(
+            tracker.health(),
+            PeerHealth {
+                latency: Some(Duration::from_millis(42)),
+                missed_pings: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_unanswered_ping_counted_missed() {
+        let mut tracker = PingTracker::default();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let first = echo(tracker.ping_at(at(1_000), 1_000));
+        let second = echo(tracker.ping_at(at(2_000), 2_000));
+        assert_eq!(tracker.health().missed_pings, 1);
+
+        // The late pong for the first ping doesn't count as a round trip.
+        tracker.pong_at(seq(first), at(2_500));
+        assert_eq!(tracker.health().latency, None);
+
+        tracker.pong_at(seq(second), at(2_010));
+        tracker.ping_at(at(3_000), 3_000);
+        assert_eq!(
+            tracker.health(),
+            PeerHealth {
+                latency: Some(Duration::from_millis(10)),
+                missed_pings: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_bare_pong_answers_ping_without_latency() {
+        let mut tracker = PingTracker::default();
+        let start = Instant::now();
+        tracker.ping_at(start, 1_000);
+        tracker.pong_at(None, start + Duration::from_millis(100));
+        tracker.ping_at(start + Duration::from_secs(1), 2_000);
+        assert_eq!(tracker.health(), PeerHealth::default());
+    }
+
+    #[test]
+    fn test_latency_ignores_wall_clock_in_pong() {
+        let mut tracker = PingTracker::default();
+        let start = Instant::now();
+        let Message::Ping { seq: sent, .. } = tracker.ping_at(start, 5_000) else {
+            unreachable!()
+        };
+        // The wall clock stepped back between ping and pong.
+        let pong = Message::Pong {
+            seq: sent,
+            sent_ms: Some(9_000),
+        };
+        tracker.pong_at(seq(pong), start + Duration::from_millis(7));
+        assert_eq!(tracker.health().latency, Some(Duration::from_millis(7)));
+    }
+}
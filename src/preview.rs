@@ -0,0 +1,44 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Shortens `content` to at most `max` user-perceived characters on a single
+/// line for showing on the command line, so a long or multi-line paste
+/// doesn't swamp the terminal. Line breaks become spaces and an ellipsis
+/// marks cut content. Logs keep to lengths and never show content.
+pub fn preview(content: &str, max: usize) -> String {
+    let mut graphemes = content.graphemes(true).map(|g| match g {
+        "\r\n" | "\n" | "\r" => " ",
+        g => g,
+    });
+    let mut preview: String = graphemes.by_ref().take(max).collect();
+    if graphemes.next().is_some() {
+        preview.push('…');
+    }
+    preview
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ascii_truncated_with_ellipsis() {
+        assert_eq!(preview("hello world", 5), "hello…");
+        assert_eq!(preview("hello", 5), "hello");
+        assert_eq!(preview("", 5), "");
+    }
+
+    #[test]
+    fn test_multibyte_not_split() {
+        assert_eq!(preview("héllo wörld", 7), "héllo w…");
+        assert_eq!(preview("日本語のテキスト", 3), "日本語…");
+        // A flag and a family are single graphemes made of several chars.
+        assert_eq!(preview("🇳🇴👨‍👩‍👧 ok", 2), "🇳🇴👨‍👩‍👧…");
+        assert_eq!(preview("e\u{301}e\u{301}", 1), "e\u{301}…");
+    }
+
+    #[test]
+    fn test_newlines_become_spaces() {
+        assert_eq!(preview("one\ntwo\r\nthree\rfour", 40), "one two three four");
+        assert_eq!(preview("a\r\nb", 2), "a …");
+    }
+}
@@ -1,56 +1,327 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 use uuid::Uuid;
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// Upper bound for frames read before a peer has authenticated.
+pub const MAX_HANDSHAKE_MESSAGE_SIZE: usize = 4 * 1024;
+
+/// Upper bound for frames from an authenticated peer, and for the payload a
+/// compressed frame expands to. Far above any clipboard worth syncing, but
+/// keeps a broken peer from making us allocate gigabytes.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Set in a frame's length prefix when its payload is compressed with the
+/// algorithm negotiated in `Hello`. Only sent to peers that advertised it.
+const COMPRESSED_FLAG: u32 = 1 << 31;
+
+/// Payloads shorter than this are never compressed.
+const COMPRESSION_THRESHOLD: usize = 512;
+
+/// LZ4 can't expand data by more than this; anything claiming more is bogus.
+const MAX_LZ4_RATIO: usize = 255;
+
+/// Fewer distinct characters than this suggests a repeated or keyboard-walk
+/// key such as `aaaaaaaaaaaaaaaa` or `1212121212121212`.
+const MIN_PSK_DISTINCT_CHARS: usize = 8;
+
 #[derive(Debug, Error)]
 pub enum ProtocolError {
     #[error("invalid message length")]
     InvalidLength,
+    #[error("empty frame")]
+    EmptyFrame,
+    #[error("message too large: {0} bytes")]
+    MessageTooLarge(usize),
+    #[error("invalid utf-8 in message: {0}")]
+    InvalidUtf8(#[from] std::str::Utf8Error),
     #[error("invalid message format: {0}")]
     InvalidFormat(#[from] toml::de::Error),
     #[error("authentication failed")]
     AuthFailed,
+    #[error("peer didn't complete mutual auth (old version?)")]
+    MutualAuthIncomplete,
+    #[error("peer stalled during the handshake")]
+    HandshakeTimeout,
+    #[error("compressed frame from a peer that didn't negotiate compression")]
+    UnexpectedCompression,
+    #[error("invalid compressed frame: {0}")]
+    Decompress(#[from] lz4_flex::block::DecompressError),
+    #[error("invalid clipboard data: {0}")]
+    InvalidData(#[from] base64::DecodeError),
+    #[error("connection closed by peer")]
+    Closed,
+    #[error("peer stalled sending a {0}-byte frame")]
+    StalledFrame(usize),
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Error)]
+pub enum PskFileError {
+    #[error("failed to read PSK file {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("PSK file {} is empty", .0.display())]
+    Empty(PathBuf),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum WeakPsk {
+    #[error("PSK has {len} characters, fewer than the minimum of {min}")]
+    TooShort { len: usize, min: usize },
+    #[error("PSK uses only {0} distinct characters")]
+    FewDistinct(usize),
+}
+
+/// Which system selection clipboard content belongs to. Only X11/Wayland
+/// have a separate PRIMARY selection; messages without a tag are CLIPBOARD.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Selection {
+    #[default]
+    Clipboard,
+    Primary,
+}
+
+impl Selection {
+    fn is_clipboard(&self) -> bool {
+        *self == Self::Clipboard
+    }
+}
+
+/// Frame compression a peer can decode. `Hello` lists the ones supported in
+/// order of preference; peers that list none only get uncompressed frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Lz4,
+}
+
+impl Compression {
+    /// Algorithms this build can decode, most preferred first.
+    pub const SUPPORTED: &'static [Self] = &[Self::Lz4];
+
+    /// Picks the receiver's most preferred algorithm the sender supports.
+    pub fn negotiate(receiver: &[Self], sender: &[Self]) -> Option<Self> {
+        receiver.iter().find(|c| sender.contains(c)).copied()
+    }
+
+    fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Lz4 => lz4_flex::compress_prepend_size(data),
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        match self {
+            Self::Lz4 => {
+                let size = data
+                    .get(..4)
+                    .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+                    .ok_or(ProtocolError::InvalidLength)?;
+                if size > MAX_MESSAGE_SIZE || size > data.len() * MAX_LZ4_RATIO {
+                    return Err(ProtocolError::MessageTooLarge(size));
+                }
+                Ok(lz4_flex::decompress_size_prepended(data)?)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Message {
-    Hello { id: Uuid, name: String },
+    Hello {
+        id: Uuid,
+        name: String,
+        /// Group the peer advertises over mDNS, if any.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        compression: Vec<Compression>,
+        /// MIME targets besides text the peer accepts as `Data`.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        mime_targets: Vec<String>,
+        /// Whether the peer understands `ClipboardHash` and
+        /// `ClipboardRequest`; older peers would drop the connection.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        hash_check: bool,
+        /// Whether the peer syncs PRIMARY. Older peers don't look at
+        /// `selection` and would put PRIMARY updates on their CLIPBOARD.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        primary: bool,
+    },
     Auth { challenge: [u8; 32], response: [u8; 32] },
-    Clipboard { content: String, timestamp: u64 },
-    Ping,
-    Pong,
+    Clipboard {
+        content: String,
+        timestamp: u64,
+        #[serde(default, skip_serializing_if = "Selection::is_clipboard")]
+        selection: Selection,
+    },
+    /// Keepalive. `seq` and `sent_ms` (our clock, milliseconds since the
+    /// epoch) are echoed in the `Pong` to measure the round trip; peers that
+    /// predate them send neither.
+    Ping {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sent_ms: Option<u64>,
+    },
+    Pong {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sent_ms: Option<u64>,
+    },
+    /// SHA-256 of the content last synced through `selection` and when it
+    /// was copied, sent periodically so a peer that missed it can ask for it.
+    ClipboardHash {
+        hash: String,
+        timestamp: u64,
+        #[serde(default, skip_serializing_if = "Selection::is_clipboard")]
+        selection: Selection,
+    },
+    /// Asks for the content last synced through `selection`, answered with a
+    /// `Clipboard` message.
+    ClipboardRequest {
+        #[serde(default, skip_serializing_if = "Selection::is_clipboard")]
+        selection: Selection,
+    },
+    /// Clipboard content for a MIME target such as `image/png`, only sent to
+    /// peers that listed the target in their `Hello`.
+    Data {
+        target: String,
+        /// Base64, since TOML has no byte strings.
+        data: String,
+        timestamp: u64,
+    },
 }
 
 impl Message {
-    pub fn encode(&self) -> Vec<u8> {
+    /// Encodes the message, compressing large payloads with `compression`
+    /// when that makes them smaller.
+    pub fn encode(&self, compression: Option<Compression>) -> Vec<u8> {
         let payload = toml::to_string(self).expect("message serialization should not fail");
-        let len = payload.len() as u32;
-        let mut buf = Vec::with_capacity(4 + payload.len());
-        buf.extend_from_slice(&len.to_be_bytes());
-        buf.extend_from_slice(payload.as_bytes());
-        buf
+        if let Some(compression) = compression.filter(|_| payload.len() >= COMPRESSION_THRESHOLD) {
+            let compressed = compression.compress(payload.as_bytes());
+            if compressed.len() < payload.len() {
+                return frame(&compressed, COMPRESSED_FLAG);
+            }
+        }
+        frame(payload.as_bytes(), 0)
     }
 
-    pub fn decode(data: &[u8]) -> Result<Self, ProtocolError> {
+    /// Decodes a frame whose payload may be compressed with `compression`.
+    pub fn decode(data: &[u8], compression: Option<Compression>) -> Result<Self, ProtocolError> {
         if data.len() < 4 {
             return Err(ProtocolError::InvalidLength);
         }
-        let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
-        if data.len() < 4 + len {
+        let header = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let len = frame_len(header);
+        if data.len() != 4 + len {
             return Err(ProtocolError::InvalidLength);
         }
-        let payload = std::str::from_utf8(&data[4..4 + len])
-            .map_err(|_| ProtocolError::InvalidLength)?;
+        // Every message serializes to at least its type tag, so a frame with
+        // no body is a broken peer rather than an empty clipboard.
+        if len == 0 {
+            return Err(ProtocolError::EmptyFrame);
+        }
+        let body = &data[4..4 + len];
+        let payload = if header & COMPRESSED_FLAG != 0 {
+            let compression = compression.ok_or(ProtocolError::UnexpectedCompression)?;
+            Cow::Owned(compression.decompress(body)?)
+        } else {
+            Cow::Borrowed(body)
+        };
+        let payload = std::str::from_utf8(&payload)?;
         Ok(toml::from_str(payload)?)
     }
 }
 
+/// Encodes raw clipboard data for [`Message::Data`].
+pub fn encode_data(data: &[u8]) -> String {
+    BASE64.encode(data)
+}
+
+pub fn decode_data(data: &str) -> Result<Vec<u8>, ProtocolError> {
+    Ok(BASE64.decode(data)?)
+}
+
+/// Payload length from a frame's length prefix, without the flag bits.
+pub fn frame_len(header: u32) -> usize {
+    (header & !COMPRESSED_FLAG) as usize
+}
+
+fn frame(payload: &[u8], flags: u32) -> Vec<u8> {
+    let header = payload.len() as u32 | flags;
+    let mut buf = Vec::with_capacity(4 + payload.len());
+    buf.extend_from_slice(&header.to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+impl ProtocolError {
+    /// Whether trying the same connection again could succeed. A peer that
+    /// fails authentication or speaks a different format will keep doing so.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::Io(_)
+                | Self::Closed
+                | Self::InvalidLength
+                | Self::StalledFrame(_)
+                | Self::HandshakeTimeout
+        )
+    }
+}
+
+/// Reads a PSK from `path`, ignoring the trailing newline most editors and
+/// secret managers leave behind.
+pub fn read_psk_file(path: &Path) -> Result<String, PskFileError> {
+    let content = std::fs::read_to_string(path).map_err(|source| PskFileError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let psk = content.trim_end_matches(['\r', '\n']);
+    if psk.is_empty() {
+        return Err(PskFileError::Empty(path.to_path_buf()));
+    }
+    Ok(psk.to_string())
+}
+
+/// Rejects keys that are easy to guess: shorter than `min_len` characters or
+/// made of only a handful of distinct ones.
+pub fn check_psk_strength(psk: &str, min_len: usize) -> Result<(), WeakPsk> {
+    let len = psk.chars().count();
+    if len < min_len {
+        return Err(WeakPsk::TooShort { len, min: min_len });
+    }
+    let distinct = psk.chars().collect::<std::collections::HashSet<_>>().len();
+    if distinct < MIN_PSK_DISTINCT_CHARS {
+        return Err(WeakPsk::FewDistinct(distinct));
+    }
+    Ok(())
+}
+
+/// Opaque group name derived from a shared secret, so the advertised group
+/// neither reveals anything readable nor can be matched without the secret.
+pub fn group_token(secret: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(b"cursedboard group\0")
+        .chain_update(secret.as_bytes())
+        .finalize();
+    digest[..8].iter().map(|b| format!("{b:02x}")).collect()
+}
+
 pub fn compute_auth_response(psk: &str, challenge: &[u8; 32]) -> [u8; 32] {
     let mut mac = HmacSha256::new_from_slice(psk.as_bytes())
         .expect("HMAC accepts any key length");
@@ -66,7 +337,38 @@ pub fn verify_auth_response(psk: &str, challenge: &[u8; 32], response: &[u8; 32]
     constant_time_eq(&expected, response)
 }
 
-fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+/// Response the dialing side returns to the listener's `challenge`. It also
+/// covers the dialer's own challenge and a distinct prefix, so it can't be
+/// obtained by handing the listener's challenge back to the listener as a
+/// dialer on a second connection.
+pub fn compute_dialer_response(
+    psk: &str,
+    challenge: &[u8; 32],
+    dialer_challenge: &[u8; 32],
+) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(psk.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(b"cursedboard dialer\0");
+    mac.update(challenge);
+    mac.update(dialer_challenge);
+    let mut response = [0u8; 32];
+    response.copy_from_slice(&mac.finalize().into_bytes());
+    response
+}
+
+pub fn verify_dialer_response(
+    psk: &str,
+    challenge: &[u8; 32],
+    dialer_challenge: &[u8; 32],
+    response: &[u8; 32],
+) -> bool {
+    let expected = compute_dialer_response(psk, challenge, dialer_challenge);
+    constant_time_eq(&expected, response)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
     let mut result = 0u8;
     for (x, y) in a.iter().zip(b.iter()) {
         result |= x ^ y;
@@ -80,6 +382,12 @@ pub fn generate_challenge() -> [u8; 32] {
     challenge
 }
 
+pub fn random_u32() -> u32 {
+    let mut buf = [0u8; 4];
+    getrandom(&mut buf);
+    u32::from_ne_bytes(buf)
+}
+
 fn getrandom(buf: &mut [u8]) {
     use std::fs::File;
     use std::io::Read;
@@ -116,11 +424,14 @@ mod tests {
         let msg = Message::Clipboard {
             content: "hello".into(),
             timestamp: 12345,
+            selection: Selection::Clipboard,
         };
-        let encoded = msg.encode();
-        let decoded = Message::decode(&encoded).unwrap();
+        let encoded = msg.encode(None);
+        let decoded = Message::decode(&encoded, None).unwrap();
         match decoded {
-            Message::Clipboard { content, timestamp } => {
+            Message::Clipboard {
+                content, timestamp, ..
+            } => {
                 assert_eq!(content, "hello");
                 assert_eq!(timestamp, 12345);
             }
@@ -128,6 +439,37 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_empty_content_roundtrips_and_empty_frame_rejected() {
+        let msg = Message::Clipboard {
+            content: String::new(),
+            timestamp: 1,
+            selection: Selection::Clipboard,
+        };
+        let encoded = msg.encode(None);
+        assert!(frame_len(u32::from_be_bytes(encoded[..4].try_into().unwrap())) > 0);
+        assert!(matches!(
+            Message::decode(&encoded, None),
+            Ok(Message::Clipboard { content, .. }) if content.is_empty()
+        ));
+
+        assert!(matches!(
+            Message::decode(&0u32.to_be_bytes(), None),
+            Err(ProtocolError::EmptyFrame)
+        ));
+        assert!(!ProtocolError::EmptyFrame.is_retryable());
+    }
+
+    #[test]
+    fn test_decode_invalid_utf8() {
+        let mut data = 2u32.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0xff, 0xfe]);
+        assert!(matches!(
+            Message::decode(&data, None),
+            Err(ProtocolError::InvalidUtf8(_))
+        ));
+    }
+
     #[test]
     fn test_auth_verify() {
         let psk = "secret";
@@ -135,5 +477,301 @@ mod tests {
         let response = compute_auth_response(psk, &challenge);
         assert!(verify_auth_response(psk, &challenge, &response));
         assert!(!verify_auth_response("wrong", &challenge, &response));
+
+        let ours = generate_challenge();
+        let response = compute_dialer_response(psk, &challenge, &ours);
+        assert!(verify_dialer_response(psk, &challenge, &ours, &response));
+        assert!(!verify_dialer_response(
+            "wrong", &challenge, &ours, &response
+        ));
+        // Answering the challenge as the listener doesn't answer it as the dialer.
+        let reflected = compute_auth_response(psk, &challenge);
+        assert!(!verify_dialer_response(psk, &challenge, &ours, &reflected));
+    }
+
+    #[test]
+    fn test_psk_file_authenticates() {
+        let dir = std::env::temp_dir().join(format!("cursedboard-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("psk");
+        std::fs::write(&path, "secret\n").unwrap();
+
+        let psk = read_psk_file(&path).unwrap();
+        assert_eq!(psk, "secret");
+        let challenge = generate_challenge();
+        let response = compute_auth_response("secret", &challenge);
+        assert!(verify_auth_response(&psk, &challenge, &response));
+
+        std::fs::write(&path, "\n").unwrap();
+        assert!(matches!(read_psk_file(&path), Err(PskFileError::Empty(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_psk_file() {
+        let path = std::env::temp_dir()
+            .join(format!("cursedboard-test-{}", Uuid::new_v4()))
+            .join("psk");
+        let err = read_psk_file(&path).unwrap_err();
+        assert!(matches!(err, PskFileError::Read { .. }));
+        assert!(err.to_string().contains(&path.display().to_string()));
+    }
+
+    #[test]
+    fn test_psk_strength() {
+        assert_eq!(
+            check_psk_strength("cursedboard", 16),
+            Err(WeakPsk::TooShort { len: 11, min: 16 })
+        );
+        assert_eq!(
+            check_psk_strength("abababababababababab", 16),
+            Err(WeakPsk::FewDistinct(2))
+        );
+        assert_eq!(check_psk_strength("k3Jd9-qLx2vR7wPz", 16), Ok(()));
+        assert_eq!(check_psk_strength("cursedboard", 0), Ok(()));
+    }
+
+    #[test]
+    fn test_group_token_from_secret() {
+        let token = group_token("correct horse");
+        assert_eq!(token, group_token("correct horse"));
+        assert_ne!(token, group_token("battery staple"));
+        assert_eq!(token.len(), 16);
+        assert!(!token.contains("horse"));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"a", b"a"));
+        assert!(constant_time_eq(&[7u8; 64], &[7u8; 64]));
+        assert!(!constant_time_eq(b"a", b"b"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+        assert!(!constant_time_eq(b"", b"a"));
+        assert!(!constant_time_eq(&[7u8; 32], &[7u8; 33]));
+    }
+
+    /// Golden frames for every message variant. A change to the wire format
+    /// must update these files deliberately, since deployed peers decode them.
+    fn vectors() -> Vec<(&'static [u8], Message)> {
+        vec![
+            (
+                include_bytes!("../testdata/protocol/hello.bin"),
+                Message::Hello {
+                    id: "6f1c2a7e-3b4d-4e5f-8a9b-0c1d2e3f4a5b".parse().unwrap(),
+                    name: "laptop".into(),
+                    group: None,
+                    compression: vec![],
+                    mime_targets: vec![],
+                    hash_check: false,
+                    primary: false,
+                },
+            ),
+            (
+                include_bytes!("../testdata/protocol/hello_compression.bin"),
+                Message::Hello {
+                    id: "6f1c2a7e-3b4d-4e5f-8a9b-0c1d2e3f4a5b".parse().unwrap(),
+                    name: "laptop".into(),
+                    group: None,
+                    compression: vec![Compression::Lz4],
+                    mime_targets: vec![],
+                    hash_check: false,
+                    primary: false,
+                },
+            ),
+            (
+                include_bytes!("../testdata/protocol/hello_hash_check.bin"),
+                Message::Hello {
+                    id: "6f1c2a7e-3b4d-4e5f-8a9b-0c1d2e3f4a5b".parse().unwrap(),
+                    name: "laptop".into(),
+                    group: None,
+                    compression: vec![],
+                    mime_targets: vec![],
+                    hash_check: true,
+                    primary: false,
+                },
+            ),
+            (
+                include_bytes!("../testdata/protocol/hello_primary.bin"),
+                Message::Hello {
+                    id: "6f1c2a7e-3b4d-4e5f-8a9b-0c1d2e3f4a5b".parse().unwrap(),
+                    name: "laptop".into(),
+                    group: None,
+                    compression: vec![],
+                    mime_targets: vec![],
+                    hash_check: false,
+                    primary: true,
+                },
+            ),
+            (
+                include_bytes!("../testdata/protocol/auth.bin"),
+                Message::Auth {
+                    challenge: [1; 32],
+                    response: [2; 32],
+                },
+            ),
+            (
+                include_bytes!("../testdata/protocol/clipboard.bin"),
+                Message::Clipboard {
+                    content: "hello\nworld".into(),
+                    timestamp: 1_700_000_000_000,
+                    selection: Selection::Clipboard,
+                },
+            ),
+            (
+                include_bytes!("../testdata/protocol/clipboard_primary.bin"),
+                Message::Clipboard {
+                    content: "selected".into(),
+                    timestamp: 1_700_000_000_000,
+                    selection: Selection::Primary,
+                },
+            ),
+            (
+                include_bytes!("../testdata/protocol/data.bin"),
+                Message::Data {
+                    target: "image/png".into(),
+                    data: encode_data(&[0x89, b'P', b'N', b'G']),
+                    timestamp: 1_700_000_000_000,
+                },
+            ),
+            (
+                include_bytes!("../testdata/protocol/clipboard_hash.bin"),
+                Message::ClipboardHash {
+                    hash: "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".into(),
+                    timestamp: 1_700_000_000_000,
+                    selection: Selection::Clipboard,
+                },
+            ),
+            (
+                include_bytes!("../testdata/protocol/clipboard_request.bin"),
+                Message::ClipboardRequest {
+                    selection: Selection::Primary,
+                },
+            ),
+            (
+                include_bytes!("../testdata/protocol/ping.bin"),
+                Message::Ping {
+                    seq: None,
+                    sent_ms: None,
+                },
+            ),
+            (
+                include_bytes!("../testdata/protocol/pong.bin"),
+                Message::Pong {
+                    seq: None,
+                    sent_ms: None,
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_encode_matches_vectors() {
+        for (bytes, msg) in vectors() {
+            assert_eq!(msg.encode(None), bytes, "encoding of {:?} changed", msg);
+        }
+    }
+
+    #[test]
+    fn test_decode_matches_vectors() {
+        for (bytes, msg) in vectors() {
+            assert_eq!(Message::decode(bytes, None).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_compressed_frame_roundtrip() {
+        let msg = Message::Clipboard {
+            content: "compress me ".repeat(100),
+            timestamp: 1,
+            selection: Selection::Clipboard,
+        };
+        let plain = msg.encode(None);
+        let compressed = msg.encode(Some(Compression::Lz4));
+        assert!(compressed.len() < plain.len());
+        assert_eq!(
+            Message::decode(&compressed, Some(Compression::Lz4)).unwrap(),
+            msg
+        );
+        assert!(matches!(
+            Message::decode(&compressed, None),
+            Err(ProtocolError::UnexpectedCompression)
+        ));
+        let ping = Message::Ping {
+            seq: None,
+            sent_ms: None,
+        };
+        assert_eq!(ping.encode(Some(Compression::Lz4)), ping.encode(None));
+    }
+
+    #[test]
+    fn test_timestamped_ping_roundtrips() {
+        let ping = Message::Ping {
+            seq: Some(3),
+            sent_ms: Some(1_700_000_000_000),
+        };
+        assert_eq!(Message::decode(&ping.encode(None), None).unwrap(), ping);
+    }
+
+    #[test]
+    fn test_compression_bomb_rejected() {
+        let mut body = (u32::MAX).to_le_bytes().to_vec();
+        body.extend_from_slice(&[0; 8]);
+        let frame = frame(&body, COMPRESSED_FLAG);
+        assert!(matches!(
+            Message::decode(&frame, Some(Compression::Lz4)),
+            Err(ProtocolError::MessageTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_decompressed_size_capped() {
+        // Plausible for the LZ4 ratio, but past the absolute limit.
+        let mut body = (MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes().to_vec();
+        body.resize(MAX_MESSAGE_SIZE / 64, 0);
+        assert!(matches!(
+            Compression::Lz4.decompress(&body),
+            Err(ProtocolError::MessageTooLarge(size)) if size == MAX_MESSAGE_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn test_negotiate_uses_receiver_preference() {
+        assert_eq!(
+            Compression::negotiate(&[Compression::Lz4], Compression::SUPPORTED),
+            Some(Compression::Lz4)
+        );
+        assert_eq!(Compression::negotiate(&[], Compression::SUPPORTED), None);
+        assert_eq!(Compression::negotiate(Compression::SUPPORTED, &[]), None);
+    }
+
+    #[test]
+    fn test_data_roundtrip() {
+        let bytes = [0u8, 0xff, 0x10, b'\n'];
+        assert_eq!(decode_data(&encode_data(&bytes)).unwrap(), bytes);
+        assert!(matches!(
+            decode_data("not base64!"),
+            Err(ProtocolError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_truncated_frame() {
+        let (bytes, _) = &vectors()[0];
+        assert!(matches!(
+            Message::decode(&bytes[..3], None),
+            Err(ProtocolError::InvalidLength)
+        ));
+        assert!(matches!(
+            Message::decode(&bytes[..bytes.len() - 1], None),
+            Err(ProtocolError::InvalidLength)
+        ));
+        let mut trailing = bytes.to_vec();
+        trailing.push(0);
+        assert!(matches!(
+            Message::decode(&trailing, None),
+            Err(ProtocolError::InvalidLength)
+        ));
     }
 }
@@ -0,0 +1,91 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// How long content counts as recently seen.
+pub const RECENT_WINDOW: Duration = Duration::from_secs(2);
+pub const RECENT_CAPACITY: usize = 16;
+
+/// Hashes of recently synced content, most recent last. In a mesh the same
+/// paste reaches a node from several directions; anything seen here is not
+/// broadcast again while it may still be circulating.
+#[derive(Debug)]
+pub struct RecentContent {
+    entries: VecDeque<(u64, Instant)>,
+    window: Duration,
+    capacity: usize,
+}
+
+impl Default for RecentContent {
+    fn default() -> Self {
+        Self::new(RECENT_WINDOW, RECENT_CAPACITY)
+    }
+}
+
+impl RecentContent {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            window,
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, item: impl Hash) {
+        self.record_at(hash(item), Instant::now());
+    }
+
+    pub fn contains(&self, item: impl Hash) -> bool {
+        self.contains_at(hash(item), Instant::now())
+    }
+
+    fn record_at(&mut self, hash: u64, now: Instant) {
+        self.entries.retain(|(h, _)| *h != hash);
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((hash, now));
+    }
+
+    fn contains_at(&self, hash: u64, now: Instant) -> bool {
+        self.entries
+            .iter()
+            .any(|(h, seen)| *h == hash && now.duration_since(*seen) < self.window)
+    }
+}
+
+fn hash(item: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    item.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_expire_after_window() {
+        let mut recent = RecentContent::new(Duration::from_secs(2), 4);
+        let start = Instant::now();
+        recent.record_at(hash("hello"), start);
+
+        assert!(recent.contains_at(hash("hello"), start + Duration::from_secs(1)));
+        assert!(!recent.contains_at(hash("other"), start));
+        assert!(!recent.contains_at(hash("hello"), start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_oldest_entry_evicted_at_capacity() {
+        let mut recent = RecentContent::new(Duration::from_secs(2), 2);
+        recent.record("a");
+        recent.record("b");
+        recent.record("a");
+        recent.record("c");
+
+        assert!(recent.contains("a"));
+        assert!(!recent.contains("b"));
+        assert!(recent.contains("c"));
+    }
+}
@@ -0,0 +1,280 @@
+use crate::peer::PeerEvent;
+use crate::protocol::{random_u32, ProtocolError};
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+const JITTER: f64 = 0.2;
+
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction by which each delay is randomly lengthened or shortened, so
+    /// peers that lost the same machine don't all retry at once.
+    pub jitter: f64,
+    /// Delays to use instead of the doubling ones, in order, repeating the
+    /// last. Followed exactly, without jitter.
+    pub schedule: Vec<Duration>,
+}
+
+impl ReconnectPolicy {
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            initial_delay: INITIAL_DELAY,
+            max_delay: MAX_DELAY,
+            jitter: JITTER,
+            schedule: Vec::new(),
+        }
+    }
+
+    pub fn with_schedule(mut self, schedule: Vec<Duration>) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Delay before the given (1-based) attempt: the schedule's entry for it
+    /// if there is a schedule, otherwise doubling up to `max_delay`.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        if let Some(last) = self.schedule.last() {
+            let index = attempt.saturating_sub(1) as usize;
+            return self.schedule.get(index).copied().unwrap_or(*last);
+        }
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+
+    /// Calls `connect` with backoff until it succeeds or fails with an error
+    /// that retrying can't fix. A `Reconnecting` event is emitted before each
+    /// attempt and a single `ReconnectFailed` event once it gives up.
+    pub async fn run<F, Fut>(&self, id: Uuid, mut connect: F, events_tx: &mpsc::Sender<PeerEvent>)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(), ProtocolError>>,
+    {
+        let mut attempts = 0;
+        while attempts < self.max_attempts {
+            attempts += 1;
+            let delay = if self.schedule.is_empty() {
+                jittered(self.delay(attempts), self.jitter, random_u32())
+            } else {
+                self.delay(attempts)
+            };
+            info!(
+                %id,
+                "reconnecting to peer, attempt {attempts}/{}, next in {:.1}s",
+                self.max_attempts,
+                delay.as_secs_f64()
+            );
+            let _ = events_tx
+                .send(PeerEvent::Reconnecting { id, attempts })
+                .await;
+            tokio::time::sleep(delay).await;
+            match connect().await {
+                Ok(()) => return,
+                Err(e) if !e.is_retryable() => {
+                    warn!(%id, error = %e, "not retrying peer");
+                    break;
+                }
+                Err(_) => {}
+            }
+        }
+
+        let _ = events_tx
+            .send(PeerEvent::ReconnectFailed { id, attempts })
+            .await;
+    }
+}
+
+/// Scales `delay` by a factor in `1 ± fraction`, picked by `sample`.
+fn jittered(delay: Duration, fraction: f64, sample: u32) -> Duration {
+    let unit = sample as f64 / u32::MAX as f64;
+    delay.mul_f64(1.0 + fraction * (2.0 * unit - 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: u32) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts,
+            initial_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            jitter: 0.0,
+            schedule: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_delay_backoff() {
+        let policy = ReconnectPolicy::new(10);
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(4), Duration::from_secs(8));
+        assert_eq!(policy.delay(10), MAX_DELAY);
+    }
+
+    #[test]
+    fn test_schedule_followed_then_last_repeats() {
+        let schedule = [500, 2000, 10_000].map(Duration::from_millis).to_vec();
+        let policy = ReconnectPolicy::new(10).with_schedule(schedule);
+        let delays: Vec<_> = (1..=5).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [500, 2000, 10_000, 10_000, 10_000].map(Duration::from_millis)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_schedule_not_jittered() {
+        let (tx, _rx) = mpsc::channel(8);
+        let mut policy = policy(3).with_schedule(vec![Duration::from_millis(50)]);
+        policy.jitter = 0.5;
+        let mut calls = Vec::new();
+        let start = tokio::time::Instant::now();
+        policy
+            .run(
+                Uuid::new_v4(),
+                || {
+                    calls.push(start.elapsed());
+                    async { Err(ProtocolError::Closed) }
+                },
+                &tx,
+            )
+            .await;
+
+        assert_eq!(calls.len(), 3);
+        for (attempt, elapsed) in calls.iter().enumerate() {
+            let expected = Duration::from_millis(50) * (attempt as u32 + 1);
+            assert!(*elapsed >= expected && *elapsed < expected + Duration::from_millis(40));
+        }
+    }
+
+    #[test]
+    fn test_jitter_range() {
+        let base = Duration::from_secs(10);
+        assert_eq!(jittered(base, 0.2, 0), Duration::from_secs(8));
+        assert_eq!(jittered(base, 0.2, u32::MAX), Duration::from_secs(12));
+        assert_eq!(jittered(base, 0.0, 12345), base);
+        for _ in 0..100 {
+            let delay = jittered(base, JITTER, random_u32());
+            assert!(delay >= Duration::from_secs(8) && delay <= Duration::from_secs(12));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_attempts_emit_event_once() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let id = Uuid::new_v4();
+        let mut calls = 0;
+        policy(3)
+            .run(
+                id,
+                || {
+                    calls += 1;
+                    async { Err(ProtocolError::Closed) }
+                },
+                &tx,
+            )
+            .await;
+        drop(tx);
+
+        assert_eq!(calls, 3);
+        let mut failures = 0;
+        let mut reconnecting = Vec::new();
+        while let Some(event) = rx.recv().await {
+            match event {
+                PeerEvent::Reconnecting {
+                    id: reconnected,
+                    attempts,
+                } => {
+                    assert_eq!(reconnected, id);
+                    assert_eq!(failures, 0);
+                    reconnecting.push(attempts);
+                }
+                PeerEvent::ReconnectFailed {
+                    id: failed,
+                    attempts,
+                } => {
+                    assert_eq!(failed, id);
+                    assert_eq!(attempts, 3);
+                    failures += 1;
+                }
+                other => panic!("unexpected event {other:?}"),
+            }
+        }
+        assert_eq!(failures, 1);
+        assert_eq!(reconnecting, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_success_stops_retrying() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut calls = 0;
+        policy(5)
+            .run(
+                Uuid::new_v4(),
+                || {
+                    calls += 1;
+                    let result = if calls == 2 {
+                        Ok(())
+                    } else {
+                        Err(ProtocolError::Io(
+                            std::io::ErrorKind::ConnectionRefused.into(),
+                        ))
+                    };
+                    async move { result }
+                },
+                &tx,
+            )
+            .await;
+        drop(tx);
+
+        assert_eq!(calls, 2);
+        assert!(matches!(
+            rx.recv().await,
+            Some(PeerEvent::Reconnecting { attempts: 1, .. })
+        ));
+        assert!(matches!(
+            rx.recv().await,
+            Some(PeerEvent::Reconnecting { attempts: 2, .. })
+        ));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_auth_failure_is_not_retried() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut calls = 0;
+        policy(5)
+            .run(
+                Uuid::new_v4(),
+                || {
+                    calls += 1;
+                    async { Err(ProtocolError::AuthFailed) }
+                },
+                &tx,
+            )
+            .await;
+        drop(tx);
+
+        assert_eq!(calls, 1);
+        assert!(matches!(
+            rx.recv().await,
+            Some(PeerEvent::Reconnecting { attempts: 1, .. })
+        ));
+        assert!(matches!(
+            rx.recv().await,
+            Some(PeerEvent::ReconnectFailed { attempts: 1, .. })
+        ));
+    }
+}
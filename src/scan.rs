@@ -0,0 +1,88 @@
+use crate::discovery::{Discovery, DiscoveryEvent, Peer};
+use crate::trust::TrustStore;
+use futures::StreamExt;
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Browses for other instances for `duration` and prints them along with
+/// whether they are already trusted, without connecting to any of them.
+pub async fn run(
+    instance_id: Uuid,
+    trusted: PathBuf,
+    subtype: Option<String>,
+    duration: Duration,
+) -> anyhow::Result<()> {
+    let trust = TrustStore::load_from(trusted)?;
+    let discovery = Discovery::new(instance_id, String::new(), None, subtype, 0)?;
+    let peers: Vec<Peer> = discovery
+        .browse()?
+        .take_until(tokio::time::sleep(duration))
+        .filter_map(|event| async move {
+            match event {
+                DiscoveryEvent::Resolved(peer) => Some(peer),
+                DiscoveryEvent::Removed(_) => None,
+            }
+        })
+        .collect()
+        .await;
+    discovery.shutdown()?;
+
+    if peers.is_empty() {
+        println!("no peers found");
+    }
+    for peer in &peers {
+        println!("{}", format_peer(peer, &trust));
+    }
+    Ok(())
+}
+
+fn format_peer(peer: &Peer, trust: &TrustStore) -> String {
+    let status = if trust.is_trusted(&peer.id) {
+        "trusted"
+    } else {
+        "untrusted"
+    };
+    let line = format!(
+        "{}  {}  {}  {}  {}",
+        peer.id,
+        peer.name,
+        peer.group.as_deref().unwrap_or("-"),
+        peer.addr,
+        status
+    );
+    match peer.last_active {
+        Some(ts) => format!("{line}  last active {ts}"),
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_untrusted_peer_reported() {
+        let trusted = Peer {
+            id: Uuid::new_v4(),
+            name: "desktop".into(),
+            group: None,
+            addr: "10.0.0.2:42069".parse().unwrap(),
+            last_active: None,
+        };
+        let untrusted = Peer {
+            id: Uuid::new_v4(),
+            name: "laptop".into(),
+            group: Some("home".into()),
+            addr: "10.0.0.3:42069".parse().unwrap(),
+            last_active: Some(1_700_000_040),
+        };
+        let mut trust = TrustStore::default();
+        trust.trust(trusted.id, trusted.name.clone());
+
+        assert!(format_peer(&trusted, &trust).ends_with("  trusted"));
+        let line = format_peer(&untrusted, &trust);
+        assert!(line.contains("laptop  home"));
+        assert!(line.ends_with("  untrusted  last active 1700000040"));
+    }
+}
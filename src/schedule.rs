@@ -0,0 +1,105 @@
+use chrono::{Local, NaiveTime};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("invalid active hours '{0}', expected HH:MM-HH:MM")]
+pub struct InvalidActiveHours(String);
+
+/// Local time-of-day window during which syncing is active. A window whose
+/// end is before its start wraps around midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActiveHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl ActiveHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    pub fn is_active_now(&self) -> bool {
+        self.contains(Local::now().time())
+    }
+}
+
+impl FromStr for ActiveHours {
+    type Err = InvalidActiveHours;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidActiveHours(s.to_string());
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let start = NaiveTime::parse_from_str(start.trim(), "%H:%M").map_err(|_| invalid())?;
+        let end = NaiveTime::parse_from_str(end.trim(), "%H:%M").map_err(|_| invalid())?;
+        Ok(Self { start, end })
+    }
+}
+
+impl fmt::Display for ActiveHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+impl Serialize for ActiveHours {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_daytime_window() {
+        let hours: ActiveHours = "09:00-17:30".parse().unwrap();
+        assert!(hours.contains(at(9, 0)));
+        assert!(hours.contains(at(12, 0)));
+        assert!(hours.contains(at(17, 29)));
+        assert!(!hours.contains(at(17, 30)));
+        assert!(!hours.contains(at(8, 59)));
+        assert!(!hours.contains(at(23, 0)));
+    }
+
+    #[test]
+    fn test_window_crossing_midnight() {
+        let hours: ActiveHours = "22:00-06:00".parse().unwrap();
+        assert!(hours.contains(at(22, 0)));
+        assert!(hours.contains(at(23, 59)));
+        assert!(hours.contains(at(0, 0)));
+        assert!(hours.contains(at(5, 59)));
+        assert!(!hours.contains(at(6, 0)));
+        assert!(!hours.contains(at(12, 0)));
+    }
+
+    #[test]
+    fn test_invalid_window() {
+        assert!("09:00".parse::<ActiveHours>().is_err());
+        assert!("9am-5pm".parse::<ActiveHours>().is_err());
+        assert!("25:00-06:00".parse::<ActiveHours>().is_err());
+    }
+
+    #[test]
+    fn test_display_round_trips() {
+        let hours: ActiveHours = "22:00-06:15".parse().unwrap();
+        assert_eq!(hours.to_string(), "22:00-06:15");
+        assert_eq!(hours.to_string().parse::<ActiveHours>().unwrap(), hours);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::clipboard::Backend;
+use crate::{DisconnectedMap, PeerMap, ReconnectingMap};
+use serde::Serialize;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize)]
+struct Status {
+    uptime_secs: u64,
+    clipboard_backend: Backend,
+    /// Updates from peers dropped because the local clipboard fell behind.
+    inbound_dropped: u64,
+    peer_count: usize,
+    peers: Vec<PeerStatus>,
+}
+
+#[derive(Debug, Serialize)]
+struct PeerStatus {
+    id: Uuid,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    outbound: bool,
+    #[serde(flatten)]
+    state: PeerState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+enum PeerState {
+    Connected {
+        /// Round trip of the last answered ping.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        latency_ms: Option<u64>,
+        missed_pings: u64,
+    },
+    Reconnecting { attempts: u32 },
+    Disconnected { last_seen_secs: u64 },
+}
+
+/// Daemon-wide figures reported next to the peers.
+#[derive(Clone, Copy)]
+struct Stats<'a> {
+    started: Instant,
+    backend: Backend,
+    inbound_dropped: &'a AtomicU64,
+}
+
+/// Serves a read-only JSON snapshot of connected and reconnecting peers over
+/// HTTP.
+pub async fn serve(
+    listener: TcpListener,
+    peers: PeerMap,
+    reconnecting: ReconnectingMap,
+    disconnected: DisconnectedMap,
+    started: Instant,
+    backend: Backend,
+    inbound_dropped: Arc<AtomicU64>,
+) {
+    if let Ok(addr) = listener.local_addr() {
+        info!(%addr, "serving status");
+    }
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            continue;
+        };
+        let peers = peers.clone();
+        let reconnecting = reconnecting.clone();
+        let disconnected = disconnected.clone();
+        let inbound_dropped = inbound_dropped.clone();
+        tokio::spawn(async move {
+            let stats = Stats {
+                started,
+                backend,
+                inbound_dropped: &inbound_dropped,
+            };
+            if let Err(e) = respond(stream, &peers, &reconnecting, &disconnected, stats).await {
+                debug!(%addr, error = %e, "status request failed");
+            }
+        });
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    peers: &PeerMap,
+    reconnecting: &ReconnectingMap,
+    disconnected: &DisconnectedMap,
+    stats: Stats<'_>,
+) -> io::Result<()> {
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let response = if buf[..n].starts_with(b"GET ") {
+        let body =
+            serde_json::to_string(&snapshot(peers, reconnecting, disconnected, stats).await)?;
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn snapshot(
+    peers: &PeerMap,
+    reconnecting: &ReconnectingMap,
+    disconnected: &DisconnectedMap,
+    stats: Stats<'_>,
+) -> Status {
+    let connected: Vec<PeerStatus> = peers
+        .lock()
+        .await
+        .iter()
+        .map(|(id, peer)| PeerStatus {
+            id: *id,
+            name: peer.display_name.clone(),
+            label: peer.label.clone(),
+            outbound: peer.addr.is_some(),
+            state: PeerState::Connected {
+                latency_ms: peer
+                    .health
+                    .latency
+                    .map(|latency| latency.as_millis() as u64),
+                missed_pings: peer.health.missed_pings,
+            },
+        })
+        .collect();
+    let peer_count = connected.len();
+    let mut peers = connected;
+    let reconnecting = reconnecting.lock().await;
+    peers.extend(reconnecting.iter().map(|(id, peer)| PeerStatus {
+        id: *id,
+        name: peer.name.clone(),
+        label: None,
+        outbound: true,
+        state: PeerState::Reconnecting {
+            attempts: peer.attempts,
+        },
+    }));
+    peers.extend(
+        disconnected
+            .lock()
+            .await
+            .recent()
+            .filter(|(id, _)| !reconnecting.contains_key(id))
+            .map(|(id, peer)| PeerStatus {
+                id: *id,
+                name: peer.name.clone(),
+                label: peer.label.clone(),
+                outbound: peer.outbound,
+                state: PeerState::Disconnected {
+                    last_seen_secs: peer.last_seen.elapsed().as_secs(),
+                },
+            }),
+    );
+    drop(reconnecting);
+    peers.sort_by(|a, b| a.name.cmp(&b.name));
+    Status {
+        uptime_secs: stats.started.elapsed().as_secs(),
+        clipboard_backend: stats.backend,
+        inbound_dropped: stats.inbound_dropped.load(Ordering::Relaxed),
+        peer_count,
+        peers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disconnected::{DisconnectedPeer, RecentlyDisconnected};
+    use crate::ping::PeerHealth;
+    use crate::tests::test_handle;
+    use crate::{PeerHandle, ReconnectingPeer};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, Mutex};
+
+    #[tokio::test]
+    async fn test_status_reports_peers() {
+        let peers: PeerMap = Arc::new(Mutex::new(HashMap::new()));
+        let id = Uuid::new_v4();
+        let (tx, _rx) = mpsc::channel(1);
+        let handle = PeerHandle {
+            label: Some("work".into()),
+            health: PeerHealth {
+                latency: Some(Duration::from_millis(12)),
+                missed_pings: 1,
+            },
+            ..test_handle("laptop", tx)
+        };
+        let task = handle.task.clone();
+        peers.lock().await.insert(id, handle);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let reconnecting: ReconnectingMap = Arc::new(Mutex::new(HashMap::new()));
+        reconnecting.lock().await.insert(
+            Uuid::new_v4(),
+            ReconnectingPeer {
+                name: "desktop".into(),
+                attempts: 3,
+            },
+        );
+        let mut recent = RecentlyDisconnected::new(Some(Duration::from_secs(600)));
+        // The monotonic clock may not go back two minutes right after boot.
+        let last_seen = Instant::now()
+            .checked_sub(Duration::from_secs(120))
+            .unwrap_or_else(Instant::now);
+        let seen_secs = last_seen.elapsed().as_secs();
+        recent.insert(
+            Uuid::new_v4(),
+            DisconnectedPeer {
+                name: "phone".into(),
+                label: None,
+                outbound: false,
+                last_seen,
+            },
+        );
+        tokio::spawn(serve(
+            listener,
+            peers,
+            reconnecting,
+            Arc::new(Mutex::new(recent)),
+            Instant::now(),
+            Backend::X11,
+            Arc::new(AtomicU64::new(2)),
+        ));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(json["peer_count"], 1);
+        assert_eq!(json["clipboard_backend"], "x11");
+        assert_eq!(json["inbound_dropped"], 2);
+        assert_eq!(json["peers"][0]["name"], "desktop");
+        assert_eq!(json["peers"][0]["state"], "reconnecting");
+        assert_eq!(json["peers"][0]["attempts"], 3);
+        assert_eq!(json["peers"][1]["id"], id.to_string());
+        assert_eq!(json["peers"][1]["name"], "laptop");
+        assert_eq!(json["peers"][1]["label"], "work");
+        assert!(json["peers"][0].get("label").is_none());
+        assert_eq!(json["peers"][1]["outbound"], false);
+        assert_eq!(json["peers"][1]["state"], "connected");
+        assert_eq!(json["peers"][1]["latency_ms"], 12);
+        assert_eq!(json["peers"][1]["missed_pings"], 1);
+        assert_eq!(json["peers"][2]["name"], "phone");
+        assert_eq!(json["peers"][2]["state"], "disconnected");
+        let reported = json["peers"][2]["last_seen_secs"].as_u64().unwrap();
+        assert!((seen_secs..=seen_secs + 5).contains(&reported));
+        task.abort();
+    }
+}
@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 use uuid::Uuid;
 
@@ -14,34 +15,88 @@ pub enum TrustError {
     Serialize(#[from] toml::ser::Error),
 }
 
+/// How far `last_seen` may lag behind before a connection updates it. It
+/// only orders peers for eviction, which doesn't need more precision than
+/// this, and saving on every reconnect would turn reconnect storms into
+/// disk writes.
+const LAST_SEEN_RESOLUTION: u64 = 60 * 60;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrustedPeer {
     pub name: String,
     pub first_seen: u64,
+    /// When the peer last connected; missing in stores written before it
+    /// was tracked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<u64>,
+    /// Set with `ctl label`, shown next to the peer in logs and status.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct TrustStore {
     peers: HashMap<Uuid, TrustedPeer>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    ephemeral: bool,
+    #[serde(skip)]
+    max_peers: Option<usize>,
+    #[serde(skip)]
+    strict_limit: bool,
+}
+
+/// What `TrustStore::trust` did with a new peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trusted {
+    Added,
+    /// Added after evicting the least recently seen peer to stay within
+    /// the limit.
+    Evicted(Uuid, String),
+    /// Not added, as the store is full and the limit is strict.
+    Refused,
+}
+
+impl TrustedPeer {
+    fn seen(&self) -> u64 {
+        self.last_seen.unwrap_or(self.first_seen)
+    }
 }
 
 impl TrustStore {
-    pub fn load() -> Result<Self, TrustError> {
-        let path = Self::path();
-        if !path.exists() {
-            return Ok(Self::default());
-        }
-        let content = std::fs::read_to_string(&path)?;
-        Ok(toml::from_str(&content)?)
+    pub fn load_from(path: PathBuf) -> Result<Self, TrustError> {
+        let mut store: Self = if path.exists() {
+            let content = std::fs::read_to_string(&path)?;
+            toml::from_str(&content)?
+        } else {
+            Self::default()
+        };
+        store.path = path;
+        Ok(store)
+    }
+
+    /// Keeps trust changes in memory only; `save` becomes a no-op.
+    pub fn ephemeral(mut self) -> Self {
+        self.ephemeral = true;
+        self
+    }
+
+    /// Keeps at most `max` peers. A new peer replaces the least recently
+    /// seen one, or with `strict` isn't trusted at all. A store already
+    /// holding more is only cut down by `trim`.
+    pub fn with_limit(mut self, max: usize, strict: bool) -> Self {
+        self.max_peers = Some(max);
+        self.strict_limit = strict;
+        self
     }
 
     pub fn save(&self) -> Result<(), TrustError> {
-        let path = Self::path();
-        if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
+        if self.ephemeral {
+            return Ok(());
         }
         let content = toml::to_string_pretty(self)?;
-        std::fs::write(&path, content)?;
+        write_atomic(&self.path, &content)?;
         Ok(())
     }
 
@@ -49,62 +104,461 @@ impl TrustStore {
         self.peers.contains_key(id)
     }
 
-    pub fn trust(&mut self, id: Uuid, name: String) {
-        if !self.peers.contains_key(&id) {
-            let peer = TrustedPeer {
+    pub fn trust(&mut self, id: Uuid, name: String) -> Trusted {
+        self.trust_at(id, name, unix_secs())
+    }
+
+    fn trust_at(&mut self, id: Uuid, name: String, now: u64) -> Trusted {
+        if self.peers.contains_key(&id) {
+            return Trusted::Added;
+        }
+        let mut outcome = Trusted::Added;
+        if self.max_peers.is_some_and(|max| self.peers.len() >= max) {
+            if self.strict_limit {
+                return Trusted::Refused;
+            }
+            if let Some((evicted, evicted_name)) = self.evict_least_recently_seen() {
+                outcome = Trusted::Evicted(evicted, evicted_name);
+            }
+        }
+        self.peers.insert(
+            id,
+            TrustedPeer {
                 name,
-                first_seen: std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            };
-            self.peers.insert(id, peer);
+                first_seen: now,
+                last_seen: Some(now),
+                label: None,
+            },
+        );
+        outcome
+    }
+
+    /// Forgets the least recently seen peers until the store is within its
+    /// limit, returning their ids and names.
+    pub fn trim(&mut self) -> Vec<(Uuid, String)> {
+        let Some(max) = self.max_peers else {
+            return Vec::new();
+        };
+        let mut evicted = Vec::new();
+        while self.peers.len() > max {
+            evicted.extend(self.evict_least_recently_seen());
+        }
+        evicted
+    }
+
+    fn evict_least_recently_seen(&mut self) -> Option<(Uuid, String)> {
+        let oldest = *self.peers.iter().min_by_key(|(_, peer)| peer.seen())?.0;
+        let peer = self.peers.remove(&oldest)?;
+        Some((oldest, peer.name))
+    }
+
+    /// Records that a trusted peer connected, so it is the last to be
+    /// evicted when the store is full. Only tracked with a limit, and to
+    /// within [`LAST_SEEN_RESOLUTION`]; returns whether anything changed
+    /// that needs saving.
+    pub fn seen(&mut self, id: &Uuid) -> bool {
+        self.seen_at(id, unix_secs())
+    }
+
+    fn seen_at(&mut self, id: &Uuid, now: u64) -> bool {
+        if self.max_peers.is_none() {
+            return false;
+        }
+        match self.peers.get_mut(id) {
+            Some(peer) if now >= peer.seen() + LAST_SEEN_RESOLUTION => {
+                peer.last_seen = Some(now);
+                true
+            }
+            _ => false,
         }
     }
 
-    pub fn get(&self, id: &Uuid) -> Option<&TrustedPeer> {
-        self.peers.get(id)
+    pub fn label(&self, id: &Uuid) -> Option<&str> {
+        self.peers.get(id)?.label.as_deref()
     }
 
-    fn path() -> PathBuf {
-        dirs::config_dir()
-            .unwrap_or_else(|| PathBuf::from("."))
-            .join("cursedboard")
-            .join("trusted.toml")
+    /// Sets or, with `None`, clears the label of a trusted peer. Returns
+    /// false if the peer isn't trusted.
+    pub fn set_label(&mut self, id: &Uuid, label: Option<String>) -> bool {
+        match self.peers.get_mut(id) {
+            Some(peer) => {
+                peer.label = label;
+                true
+            }
+            None => false,
+        }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Instance {
     pub id: Uuid,
+    #[serde(skip)]
+    path: PathBuf,
 }
 
 impl Instance {
-    pub fn load_or_create() -> Result<Self, TrustError> {
-        let path = Self::path();
+    pub fn load_or_create_from(path: PathBuf) -> Result<Self, TrustError> {
         if path.exists() {
-            let content = std::fs::read_to_string(&path)?;
-            return Ok(toml::from_str(&content)?);
+            return Self::load_or_generate_from(path);
         }
-        let instance = Self { id: Uuid::new_v4() };
+        let instance = Self::load_or_generate_from(path)?;
         instance.save()?;
         Ok(instance)
     }
 
-    fn save(&self) -> Result<(), TrustError> {
-        let path = Self::path();
-        if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
+    /// Loads the saved instance id, or makes up one without saving it, for
+    /// config directories that can't be written. An unsaved id changes on
+    /// every start, so peers see a new device each time.
+    pub fn load_or_generate_from(path: PathBuf) -> Result<Self, TrustError> {
+        if path.exists() {
+            let content = std::fs::read_to_string(&path)?;
+            let mut instance: Self = toml::from_str(&content)?;
+            instance.path = path;
+            return Ok(instance);
+        }
+        Ok(Self::generate(path))
+    }
+
+    /// Makes up an id without looking at or saving to `path`.
+    pub fn generate(path: PathBuf) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            path,
         }
+    }
+
+    /// Replaces the instance id with a fresh one and saves it, for machines
+    /// that were cloned together with their config directory. Peers keep
+    /// their entry for the old id and trust the new one on first connection.
+    pub fn regenerate(mut self) -> Result<Self, TrustError> {
+        self.id = Uuid::new_v4();
+        self.save()?;
+        Ok(self)
+    }
+
+    fn save(&self) -> Result<(), TrustError> {
         let content = toml::to_string_pretty(self)?;
-        std::fs::write(&path, content)?;
+        write_atomic(&self.path, &content)?;
         Ok(())
     }
+}
+
+/// Where an instance keeps its state: the instance id, trusted peers and
+/// the last synced hashes. Everything lives in one directory, so daemons
+/// given instance files in different directories don't share anything.
+#[derive(Debug, Clone)]
+pub struct StatePaths {
+    pub dir: PathBuf,
+    pub instance: PathBuf,
+    /// Whether an instance file was given instead of the default one.
+    custom: bool,
+}
+
+impl StatePaths {
+    /// Paths under the config directory, or next to `instance_file`.
+    pub fn new(instance_file: Option<&Path>) -> Self {
+        match instance_file {
+            Some(file) => Self {
+                dir: file
+                    .parent()
+                    .filter(|dir| !dir.as_os_str().is_empty())
+                    .unwrap_or(Path::new("."))
+                    .to_path_buf(),
+                instance: file.to_path_buf(),
+                custom: true,
+            },
+            None => {
+                let dir = config_dir();
+                Self {
+                    instance: dir.join("instance.toml"),
+                    dir,
+                    custom: false,
+                }
+            }
+        }
+    }
+
+    pub fn trusted(&self) -> PathBuf {
+        self.dir.join("trusted.toml")
+    }
+
+    pub fn last_synced(&self) -> PathBuf {
+        self.dir.join("last_synced.toml")
+    }
+
+    /// Control socket in the state directory for a custom instance file, so
+    /// daemons running side by side each get their own.
+    pub fn control_socket(&self) -> Option<PathBuf> {
+        self.custom.then(|| self.dir.join("control.sock"))
+    }
+}
+
+fn unix_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Directory holding the instance id, trusted peers and other state.
+fn config_dir() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("cursedboard")
+}
+
+/// Whether files can be created in `dir`, creating it if needed. Immutable
+/// deployments may ship a config directory that is read-only.
+pub fn is_writable(dir: &Path) -> bool {
+    if std::fs::create_dir_all(dir).is_err() {
+        return false;
+    }
+    let probe = dir.join(format!(".write-test-{}", Uuid::new_v4()));
+    match std::fs::File::create(&probe) {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Writes `content` to a temporary file next to `path` and renames it into
+/// place, so a crash mid-write leaves either the old file or the new one.
+pub fn write_atomic(path: &Path, content: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = tmp_path(path);
+    let result = std::fs::File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(content.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("cursedboard-test-{}", Uuid::new_v4()))
+            .join(name)
+    }
+
+    #[test]
+    fn test_trust_store_persists() {
+        let path = temp_path("trusted.toml");
+        let id = Uuid::new_v4();
+        let mut store = TrustStore::load_from(path.clone()).unwrap();
+        store.trust(id, "laptop".into());
+        store.save().unwrap();
+
+        let reloaded = TrustStore::load_from(path.clone()).unwrap();
+        assert!(reloaded.is_trusted(&id));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_ephemeral_trust_store_writes_nothing() {
+        let path = temp_path("trusted.toml");
+        let id = Uuid::new_v4();
+        let mut store = TrustStore::load_from(path.clone()).unwrap().ephemeral();
+        store.trust(id, "laptop".into());
+        store.save().unwrap();
+
+        assert!(store.is_trusted(&id));
+        assert!(!path.exists());
+        assert!(!path.parent().unwrap().exists());
+    }
+
+    #[test]
+    fn test_trust_beyond_limit_evicts_least_recently_seen() {
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let hour = LAST_SEEN_RESOLUTION;
+        let mut store = TrustStore::default().with_limit(2, false);
+        assert_eq!(store.trust_at(first, "laptop".into(), hour), Trusted::Added);
+        assert_eq!(
+            store.trust_at(second, "desktop".into(), 2 * hour),
+            Trusted::Added
+        );
+        // Reconnecting within the resolution changes nothing to save.
+        assert!(!store.seen_at(&first, hour + 60));
+        assert!(store.seen_at(&first, 3 * hour));
+
+        assert_eq!(
+            store.trust_at(third, "phone".into(), 4 * hour),
+            Trusted::Evicted(second, "desktop".into())
+        );
+        assert!(store.is_trusted(&first));
+        assert!(!store.is_trusted(&second));
+        assert!(store.is_trusted(&third));
+    }
+
+    #[test]
+    fn test_seen_untracked_without_limit() {
+        let id = Uuid::new_v4();
+        let mut store = TrustStore::default();
+        store.trust_at(id, "laptop".into(), 100);
+        assert!(!store.seen_at(&id, 100 + 2 * LAST_SEEN_RESOLUTION));
+        assert_eq!(store.peers[&id].last_seen, Some(100));
+    }
+
+    #[test]
+    fn test_store_over_limit_trimmed() {
+        let mut store = TrustStore::default();
+        let ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        for (i, id) in ids.iter().enumerate() {
+            store.trust_at(*id, format!("peer{i}"), 100 * (i as u64 + 1));
+        }
+        let mut store = store.with_limit(2, true);
+        assert_eq!(
+            store.trim(),
+            [(ids[0], "peer0".to_string()), (ids[1], "peer1".to_string())]
+        );
+        assert!(store.is_trusted(&ids[2]) && store.is_trusted(&ids[3]));
+        assert!(store.trim().is_empty());
+    }
+
+    #[test]
+    fn test_strict_limit_refuses_new_peer() {
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut store = TrustStore::default().with_limit(1, true);
+        store.trust_at(first, "laptop".into(), 100);
+
+        assert_eq!(
+            store.trust_at(second, "desktop".into(), 200),
+            Trusted::Refused
+        );
+        assert!(store.is_trusted(&first));
+        assert!(!store.is_trusted(&second));
+        // Peers already trusted are still fine.
+        assert_eq!(store.trust_at(first, "laptop".into(), 300), Trusted::Added);
+    }
+
+    #[test]
+    fn test_atomic_write_never_leaves_partial_file() {
+        let path = temp_path("trusted.toml");
+        write_atomic(&path, "old = 1\n").unwrap();
+
+        // A write cut short before the rename only ever touched the temp file.
+        std::fs::write(tmp_path(&path), "new = ").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old = 1\n");
+
+        write_atomic(&path, "new = 2\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new = 2\n");
+        assert!(!tmp_path(&path).exists());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_failed_save_keeps_previous_store() {
+        let path = temp_path("trusted.toml");
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut store = TrustStore::load_from(path.clone()).unwrap();
+        store.trust(first, "laptop".into());
+        store.save().unwrap();
+
+        // Interrupt the next write by making its temp file impossible to create.
+        std::fs::create_dir(tmp_path(&path)).unwrap();
+        store.trust(second, "desktop".into());
+        assert!(store.save().is_err());
+
+        let reloaded = TrustStore::load_from(path.clone()).unwrap();
+        assert!(reloaded.is_trusted(&first));
+        assert!(!reloaded.is_trusted(&second));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_unwritable_config_dir_falls_back_to_memory() {
+        // A directory that can't be created stands in for a read-only one,
+        // which root could still write to.
+        let file = temp_path("config");
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(&file, "").unwrap();
+        let dir = file.join("cursedboard");
+        assert!(!is_writable(&dir));
+        assert!(Instance::load_or_create_from(dir.join("instance.toml")).is_err());
+
+        let instance = Instance::load_or_generate_from(dir.join("instance.toml")).unwrap();
+        let mut store = TrustStore::load_from(dir.join("trusted.toml"))
+            .unwrap()
+            .ephemeral();
+        store.trust(instance.id, "laptop".into());
+        store.save().unwrap();
+        assert!(store.is_trusted(&instance.id));
+
+        assert!(is_writable(file.parent().unwrap()));
+        std::fs::remove_dir_all(file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_instances_with_separate_files_are_independent() {
+        let paths = [
+            StatePaths::new(Some(&temp_path("instance.toml"))),
+            StatePaths::new(Some(&temp_path("instance.toml"))),
+        ];
+        let ids: Vec<Uuid> = paths
+            .iter()
+            .map(|paths| {
+                Instance::load_or_create_from(paths.instance.clone())
+                    .unwrap()
+                    .id
+            })
+            .collect();
+        assert_ne!(ids[0], ids[1]);
+
+        let peer = Uuid::new_v4();
+        let mut store = TrustStore::load_from(paths[0].trusted()).unwrap();
+        store.trust(peer, "laptop".into());
+        store.save().unwrap();
+
+        for (paths, id) in paths.iter().zip(&ids) {
+            let instance = Instance::load_or_create_from(paths.instance.clone()).unwrap();
+            assert_eq!(instance.id, *id);
+            assert!(paths.trusted().starts_with(&paths.dir));
+            assert!(paths.control_socket().unwrap().starts_with(&paths.dir));
+        }
+        assert!(TrustStore::load_from(paths[0].trusted())
+            .unwrap()
+            .is_trusted(&peer));
+        assert!(!TrustStore::load_from(paths[1].trusted())
+            .unwrap()
+            .is_trusted(&peer));
+        for paths in &paths {
+            std::fs::remove_dir_all(&paths.dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_regenerate_instance_id_persists() {
+        let path = temp_path("instance.toml");
+        let original = Instance::load_or_create_from(path.clone()).unwrap();
+        assert_eq!(
+            Instance::load_or_create_from(path.clone()).unwrap().id,
+            original.id
+        );
+
+        let old_id = original.id;
+        let regenerated = original.regenerate().unwrap();
+        assert_ne!(regenerated.id, old_id);
 
-    fn path() -> PathBuf {
-        dirs::config_dir()
-            .unwrap_or_else(|| PathBuf::from("."))
-            .join("cursedboard")
-            .join("instance.toml")
+        let reloaded = Instance::load_or_create_from(path.clone()).unwrap();
+        assert_eq!(reloaded.id, regenerated.id);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
     }
 }